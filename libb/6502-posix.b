@@ -62,70 +62,60 @@ realloc(ptr, size) {
     return (malloc(size));
 }
 
-/* TODO: Try to implement this function with assembly
-   Problem with this implementation is that it is not
-   mapped to the operator
-   We cannot call this function `div` as it conflicts
+/* We cannot call this function `div` as it conflicts
    with the `divmod` test
 */
 _div(a, b) {
-    auto d, sign;
-    sign = 0;
-    if (a < 0) {
-        sign = !sign;
-        a = -a;
-    }
-    if (b < 0) {
-        sign = !sign;
-        b = -a;
-    }
-
-    d = 0; while(a >= b) {
-        a = a - b;
-        d++;
-    }
-    if (sign) d = -d;
-    return (d);
-}
-_udiv(a, b) {
-    auto d;
-    d = 0; while(a >= b | a < 0) {
-        a = a - b;
-        d++;
-    }
-    return (d);
+    return (a / b);
 }
+/* The unsigned versions call the unsigned entry point `__udivmod` of the division routine
+   that the codegen emits for `/` and `%`. It divides Y:A by $05:$04 and leaves the quotient
+   in $07:$06 and the remainder in $09:$08.
+*/
+_udiv __asm__(
+    "TSX",
+    "PHA",
+    "LDA $0103,X",
+    "STA $04",
+    "LDA $0104,X",
+    "STA $05",
+    "PLA",
+    "JSR __udivmod",
+    "LDA $06",
+    "LDY $07",
+    "RTS"
+);
 
-/* TODO: Try to implement this function with assembly
-   Problem with this implementation is that it is not
-   mapped to the operator */
-_rem (a, b) {
-    auto d;
-    while(a >= b) {
-        a = a - b;
-    }
-    return (a);
-}
-_urem(a, b) {
-    auto d;
-    while(a >= b | a < 0) {
-        a = a - b;
-    }
-    return (a);
+_rem(a, b) {
+    return (a % b);
 }
+_urem __asm__(
+    "TSX",
+    "PHA",
+    "LDA $0103,X",
+    "STA $04",
+    "LDA $0104,X",
+    "STA $05",
+    "PLA",
+    "JSR __udivmod",
+    "LDA $08",
+    "LDY $09",
+    "RTS"
+);
 
 printn(n, b, sign) {
-    auto a, c, d, __div, __rem;
-
-    /* use correct div/rem based on sign */
-    __div = sign ? &_div : &_udiv;
-    __rem = sign ? &_rem : &_urem;
+    auto a, c, __div, __rem;
 
     if (sign & n < 0) {
         putchar('-');
         n = -n;
+        sign = 0; /* -n does not fit for the smallest number, so treat it as unsigned */
     }
 
+    /* use correct div/rem based on sign */
+    __div = sign ? &_div : &_udiv;
+    __rem = sign ? &_rem : &_urem;
+
     if(a=__div(n, b)) /* assignment, not test for equality */
         printn(a, b, 0); /* recursive */
     c = __rem(n,b) + '0';
@@ -133,6 +123,7 @@ printn(n, b, sign) {
     putchar(c);
 }
 
+__variadic__(printf, 1);
 printf(str, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12, x13, x14, x15) {
     auto i, j, arg, c;
     i = 0;
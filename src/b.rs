@@ -38,6 +38,11 @@ pub mod params;
 pub mod ir;
 pub mod time;
 pub mod shlex;
+pub mod jim;
+pub mod glob;
+pub mod unit;
+pub mod cache;
+pub mod dwarf;
 
 use core::ffi::*;
 use core::mem::zeroed;
@@ -47,23 +52,75 @@ use core::cmp;
 use nob::*;
 use flag::*;
 use crust::libc::*;
-use crust::assoc_lookup_cstr;
+use crust::assoc_lookup_cstr_mut;
 use arena::Arena;
 use targets::*;
-use lexer::{Lexer, Loc, Token};
+use lexer::{Lexer, Loc, Token, MAX_ERROR_COUNT};
 use ir::*;
+use glob::*;
 use time::Instant;
 use shlex::*;
 use params::*;
+use unit::{UNIT_FILE_EXT, write_unit, read_unit, link_unit};
+
+/// Directories where libb is looked for in the order of priority. The directories provided by the user
+/// with -L and B_LIBB_PATH come first, then the default ones.
+pub unsafe fn libb_search_paths(c: *mut Compiler, user_paths: *const [*const c_char]) -> Array<*const c_char> {
+    let mut paths: Array<*const c_char> = zeroed();
+    da_append_many(&mut paths, user_paths);
+
+    let env_paths = getenv(c!("B_LIBB_PATH"));
+    if !env_paths.is_null() {
+        let separator = if cfg!(target_os = "windows") { ';' } else { ':' } as c_char;
+        let mut start = env_paths;
+        loop {
+            let mut end = start;
+            while *end != 0 && *end != separator {
+                end = end.add(1);
+            }
+            if end > start {
+                da_append(&mut paths, arena::sprintf(&mut (*c).arena, c!("%.*s"), end.offset_from(start) as c_int, start) as *const c_char);
+            }
+            if *end == 0 { break; }
+            start = end.add(1);
+        }
+    }
+
+    let executable_directory = arena::strdup(&mut (*c).arena, dirname(flag_program_name()));
+    da_append(&mut paths, arena::sprintf(&mut (*c).arena, c!("%s/libb/"), executable_directory) as *const c_char);
+    // Installation prefix: <prefix>/bin/b and <prefix>/share/b/libb/
+    da_append(&mut paths, arena::sprintf(&mut (*c).arena, c!("%s/../share/b/libb/"), executable_directory) as *const c_char);
+    da_append(&mut paths, c!("./libb/"));
+    if !cfg!(target_os = "windows") {
+        da_append(&mut paths, c!("/usr/local/share/b/libb/"));
+        da_append(&mut paths, c!("/usr/share/b/libb/"));
+    }
+    paths
+}
 
-pub unsafe fn add_libb_files(path: *const c_char, target: *const c_char, inputs: &mut Array<*const c_char>, c: *mut Compiler) -> Option<bool> {
-    if !file_exists(path)? {
-        // why is rust like this.
-        return Some(false);
+/// Picks `all.b` and `<target>.b` each from the first directory of `paths` it exists in.
+pub unsafe fn add_libb_files(paths: *const [*const c_char], target: *const c_char, inputs: &mut Array<*const c_char>, c: *mut Compiler) -> Option<()> {
+    let names = [c!("all.b"), arena::sprintf(&mut (*c).arena, c!("%s.b"), target) as *const c_char];
+    let mut found_any = false;
+    for name in names {
+        for i in 0..paths.len() {
+            let path = arena::sprintf(&mut (*c).arena, c!("%s/%s"), (*paths)[i], name);
+            if file_exists(path)? {
+                da_append(inputs, path);
+                da_append(&mut (*c).libb_files, path as *const c_char);
+                found_any = true;
+                break;
+            }
+        }
     }
-    include_path_if_exists(inputs, arena::sprintf(&mut (*c).arena, c!("%s/all.b"), path));
-    include_path_if_exists(inputs, arena::sprintf(&mut (*c).arena, c!("%s/%s.b"), path, target));
-    Some(true)
+    if !found_any {
+        log(Log_Level::WARNING, c!("could not find libb in any of the following directories:"));
+        for i in 0..paths.len() {
+            log(Log_Level::WARNING, c!("    %s"), (*paths)[i]);
+        }
+        log(Log_Level::WARNING, c!("provide its location with -L <dir> or B_LIBB_PATH, or disable it with -nostdlib"));
+    }
+    Some(())
 }
 
 pub unsafe fn expect_tokens(l: *mut Lexer, tokens: *const [Token]) -> Option<()> {
@@ -86,7 +143,7 @@ pub unsafe fn expect_tokens(l: *mut Lexer, tokens: *const [Token]) -> Option<()>
     }
     da_append(&mut sb, 0);
 
-    diagf!((*l).loc, c!("ERROR: expected %s, but got %s\n"), sb.items, lexer::display_token((*l).token));
+    diagf!((*l).loc, code = c!("unexpected-token"), c!("ERROR: expected %s, but got %s\n"), sb.items, lexer::display_token((*l).token));
 
     free(sb.items);
     None
@@ -120,7 +177,7 @@ pub unsafe fn get_and_expect_tokens(l: *mut Lexer, clexes: *const [Token]) -> Op
 pub unsafe fn expect_token_id(l: *mut Lexer, id: *const c_char) -> Option<()> {
     expect_token(l, Token::ID)?;
     if strcmp((*l).string, id) != 0 {
-        diagf!((*l).loc, c!("ERROR: expected `%s`, but got `%s`\n"), id, (*l).string);
+        diagf!((*l).loc, code = c!("unexpected-token"), c!("ERROR: expected `%s`, but got `%s`\n"), id, (*l).string);
         return None;
     }
     Some(())
@@ -143,6 +200,7 @@ pub struct Var {
     pub name: *const c_char,
     pub loc: Loc,
     pub storage: Storage,
+    pub used: bool,
 }
 
 pub unsafe fn scope_push(vars: *mut Array<Array<Var>>) {
@@ -182,20 +240,134 @@ pub unsafe fn find_var_deep(vars: *const Array<Array<Var>>, name: *const c_char)
     ptr::null()
 }
 
+/// Optimal string alignment distance between two names, i.e. the Levenshtein distance where swapping two adjacent
+/// characters is a single edit, since that is the most common typo
+pub unsafe fn edit_distance(a: *const c_char, b: *const c_char) -> usize {
+    let a_len = strlen(a);
+    let b_len = strlen(b);
+    let width = b_len + 1;
+    let mut d: Array<usize> = zeroed();
+    for i in 0..=a_len {
+        for j in 0..=b_len {
+            da_append(&mut d, if i == 0 { j } else if j == 0 { i } else { 0 });
+        }
+    }
+    for i in 1..=a_len {
+        for j in 1..=b_len {
+            let cost = if *a.add(i - 1) == *b.add(j - 1) { 0 } else { 1 };
+            let mut distance = (*d.items.add((i - 1)*width + j) + 1)
+                .min(*d.items.add(i*width + j - 1) + 1)
+                .min(*d.items.add((i - 1)*width + j - 1) + cost);
+            if i > 1 && j > 1 && *a.add(i - 1) == *b.add(j - 2) && *a.add(i - 2) == *b.add(j - 1) {
+                distance = distance.min(*d.items.add((i - 2)*width + j - 2) + 1);
+            }
+            *d.items.add(i*width + j) = distance;
+        }
+    }
+    let distance = *d.items.add(a_len*width + b_len);
+    free(d.items as *mut c_void);
+    distance
+}
+
+#[derive(Clone, Copy)]
+pub struct Suggestion {
+    pub name: *const c_char,
+    pub loc: Loc,
+    pub distance: usize,
+}
+
+/// Updates `best` if `vars` contains a name closer to `name` than the current suggestion.
+/// Names that are too different to be a plausible typo are never suggested.
+pub unsafe fn suggest_var_near(vars: *const Array<Var>, name: *const c_char, best: *mut Option<Suggestion>) {
+    let max_distance = strlen(name)/3;
+    for i in 0..(*vars).count {
+        let var = *(*vars).items.add(i);
+        let distance = edit_distance(name, var.name);
+        if distance == 0 || distance > max_distance { continue; }
+        if let Some(suggestion) = *best {
+            if suggestion.distance <= distance { continue; }
+        }
+        *best = Some(Suggestion {name: var.name, loc: var.loc, distance});
+    }
+}
+
+/// Suggests the closest name among the local scopes, i.e. everything but the global scope.
+/// Must be called at the point of usage, because the local scopes are gone by the time
+/// the undefined names are reported.
+pub unsafe fn suggest_local_var(vars: *const Array<Array<Var>>, name: *const c_char) -> Option<Suggestion> {
+    let mut best = None;
+    for i in 1..(*vars).count {
+        suggest_var_near((*vars).items.add(i), name, &mut best);
+    }
+    best
+}
+
+pub unsafe fn report_undefined_name(c: *mut Compiler, name: *const c_char, loc: Loc, local_suggestion: Option<Suggestion>, in_function: bool) -> Option<()> {
+    diagf!(loc, code = c!("undefined-name"), c!("ERROR: could not find name `%s`\n"), name);
+    let extrns = &(*c).program.extrns;
+    if (0..extrns.count).any(|i| strcmp(*extrns.items.add(i), name) == 0) {
+        if in_function {
+            diagf!(loc, c!("NOTE: `%s` is only declared with `extrn` in other functions. Add `extrn %s;` to the current function to use it here\n"), name, name);
+        } else {
+            diagf!(loc, c!("NOTE: `%s` is only declared with `extrn` inside of functions. Add `extrn %s;` at the top level to use it here\n"), name, name);
+        }
+    } else {
+        let mut best = local_suggestion;
+        suggest_var_near((*c).vars.items, name, &mut best);
+        if let Some(suggestion) = best {
+            diagf!(suggestion.loc, c!("NOTE: did you mean `%s` declared here?\n"), suggestion.name);
+        }
+    }
+    bump_error_count(c)
+}
+
 pub unsafe fn declare_var(c: *mut Compiler, name: *const c_char, loc: Loc, storage: Storage) -> Option<()> {
     let scope = da_last_mut(&mut (*c).vars).expect("There should be always at least the global scope");
     let existing_var = find_var_near(scope, name);
     if !existing_var.is_null() {
-        diagf!(loc, c!("ERROR: redefinition of variable `%s`\n"), name);
+        diagf!(loc, code = c!("redefinition"), c!("ERROR: redefinition of variable `%s`\n"), name);
         diagf!((*existing_var).loc, c!("NOTE: the first declaration is located here\n"));
         return bump_error_count(c);
     }
 
+    if (*c).vars.count > 1 {
+        let outer_var = find_var_deep(&(*c).vars, name);
+        // `extrn x;` of a global `x` refers to the very same thing, so it's not really shadowing
+        let same_external = !outer_var.is_null() && matches!((storage, (*outer_var).storage), (Storage::External{..}, Storage::External{..}));
+        if !outer_var.is_null() && !same_external && warning_enabled(c, Warning::Shadow, loc) {
+            diagf!(loc, code = Warning::Shadow.name(), c!("%s: declaration of `%s` shadows a previous declaration [-W%s]\n"), warning_prefix(c), name, Warning::Shadow.name());
+            diagf!((*outer_var).loc, c!("NOTE: the shadowed declaration is located here\n"));
+            bump_warning_count(c, Warning::Shadow)?;
+        }
+    }
+
     if let Storage::Auto {index} = storage {
         da_append(&mut (*c).func_scope_events, ScopeEvent::Declare {name, index});
     }
 
-    da_append(scope, Var {name, loc, storage});
+    da_append(scope, Var {name, loc, storage, used: false});
+    Some(())
+}
+
+/// Reports the variables of the innermost scope that were declared but never referenced. Must be called right before
+/// popping the scope of a block.
+pub unsafe fn check_unused_vars(c: *mut Compiler) -> Option<()> {
+    let scope = da_last_mut(&mut (*c).vars).expect("There should be always at least the global scope");
+    for i in 0..(*scope).count {
+        let var = *(*scope).items.add(i);
+        if var.used { continue; }
+        let warning = match var.storage {
+            Storage::Auto{..}     => Warning::UnusedVariable,
+            Storage::External{..} => Warning::UnusedExtrn,
+        };
+        if warning_enabled(c, warning, var.loc) {
+            match warning {
+                Warning::UnusedVariable => diagf!(var.loc, code = warning.name(), c!("%s: unused variable `%s` [-W%s]\n"), warning_prefix(c), var.name, warning.name()),
+                _                       => diagf!(var.loc, code = warning.name(), c!("%s: `extrn %s` is never used [-W%s]\n"), warning_prefix(c), var.name, warning.name()),
+            }
+            bump_warning_count(c, warning)?;
+        }
+    }
     Some(())
 }
 
@@ -226,7 +398,7 @@ pub unsafe fn find_goto_label(labels: *const Array<GotoLabel>, name: *const c_ch
 pub unsafe fn define_goto_label(c: *mut Compiler, name: *const c_char, loc: Loc, label: usize) -> Option<()> {
     let existing_label = find_goto_label(&(*c).func_goto_labels, name);
     if !existing_label.is_null() {
-        diagf!(loc, c!("ERROR: duplicate label `%s`\n"), name);
+        diagf!(loc, code = c!("duplicate-label"), c!("ERROR: duplicate label `%s`\n"), name);
         diagf!((*existing_label).loc, c!("NOTE: the first definition is located here\n"));
         return bump_error_count(c);
     }
@@ -344,6 +516,7 @@ pub unsafe fn compile_string(string: *const c_char, c: *mut Compiler) -> usize {
 
 pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Option<(Arg, bool)> {
     lexer::get_token(l)?;
+    let primary_loc = (*l).loc;
     let arg = match (*l).token {
         Token::OParen => {
             let result = compile_expression(l, c)?;
@@ -377,7 +550,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             let (arg, is_lvalue) = compile_primary_expression(l, c)?;
 
             if !is_lvalue {
-                diagf!(loc, c!("ERROR: cannot take the address of an rvalue\n"));
+                diagf!(loc, code = c!("not-an-lvalue"), c!("ERROR: cannot take the address of an rvalue\n"));
                 return bump_error_count(c).map(|()| (Arg::Bogus, false));
             }
 
@@ -394,7 +567,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             let (arg, is_lvalue) = compile_primary_expression(l, c)?;
 
             if !is_lvalue {
-                diagf!(loc, c!("ERROR: cannot increment an rvalue\n"));
+                diagf!(loc, code = c!("not-an-lvalue"), c!("ERROR: cannot increment an rvalue\n"));
                 return bump_error_count(c).map(|()| (Arg::Bogus, false));
             }
 
@@ -406,7 +579,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             let (arg, is_lvalue) = compile_primary_expression(l, c)?;
 
             if !is_lvalue {
-                diagf!(loc, c!("ERROR: cannot decrement an rvalue\n"));
+                diagf!(loc, code = c!("not-an-lvalue"), c!("ERROR: cannot decrement an rvalue\n"));
                 return bump_error_count(c).map(|()| (Arg::Bogus, false));
             }
 
@@ -419,9 +592,15 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
 
             let var_def = find_var_deep(&mut (*c).vars, name);
             if var_def.is_null() {
-                da_append(&mut (*c).used_funcs, UsedFunc {name, loc: (*l).loc});
+                let suggestion = suggest_local_var(&(*c).vars, name);
+                da_append(&mut (*c).used_funcs, UsedFunc {name, loc: (*l).loc, suggestion});
                 Some((Arg::External(name), true))
             } else {
+                (*(var_def as *mut Var)).used = true;
+                let globals = *(*c).vars.items;
+                if var_def >= globals.items && var_def < globals.items.add((*c).outer_vars_count) && !(0..(*c).outer_names.count).any(|i| strcmp((*(*c).outer_names.items.add(i)).0, name) == 0) {
+                    da_append(&mut (*c).outer_names, (name, (*l).loc));
+                }
                 match (*var_def).storage {
                     Storage::Auto{index} => Some((Arg::AutoVar(index), true)),
                     Storage::External{name} => Some((Arg::External(name), true)),
@@ -433,7 +612,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             Some((Arg::DataOffset(offset), false))
         }
         _ => {
-            diagf!((*l).loc, c!("Expected start of a primary expression but got %s\n"), lexer::display_token((*l).token));
+            diagf!((*l).loc, code = c!("unexpected-token"), c!("ERROR: Expected start of a primary expression but got %s\n"), lexer::display_token((*l).token));
             None
         }
     };
@@ -445,7 +624,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
         lexer::get_token(l)?;
 
         (arg, is_lvalue) = match (*l).token {
-            Token::OParen => Some((compile_function_call(l, c, arg)?, false)),
+            Token::OParen => Some((compile_function_call(l, c, arg, primary_loc)?, false)),
             Token::OBracket => {
                 let (offset, _) = compile_expression(l, c)?;
                 get_and_expect_token_but_continue(l, c, Token::CBracket)?;
@@ -458,7 +637,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             Token::PlusPlus => {
                 let loc = (*l).loc;
                 if !is_lvalue {
-                    diagf!(loc, c!("ERROR: cannot increment an rvalue\n"));
+                    diagf!(loc, code = c!("not-an-lvalue"), c!("ERROR: cannot increment an rvalue\n"));
                     return bump_error_count(c).map(|()| (Arg::Bogus, false));
                 }
 
@@ -471,7 +650,7 @@ pub unsafe fn compile_primary_expression(l: *mut Lexer, c: *mut Compiler) -> Opt
             Token::MinusMinus => {
                 let loc = (*l).loc;
                 if !is_lvalue {
-                    diagf!(loc, c!("ERROR: cannot decrement an rvalue\n"));
+                    diagf!(loc, code = c!("not-an-lvalue"), c!("ERROR: cannot decrement an rvalue\n"));
                     return bump_error_count(c).map(|()| (Arg::Bogus, false));
                 }
 
@@ -556,7 +735,7 @@ pub unsafe fn compile_assign_expression(l: *mut Lexer, c: *mut Compiler) -> Opti
         let (rhs, _) = compile_assign_expression(l, c)?;
 
         if !lvalue {
-            diagf!(binop_loc, c!("ERROR: cannot assign to rvalue\n"));
+            diagf!(binop_loc, code = c!("not-an-lvalue"), c!("ERROR: cannot assign to rvalue\n"));
             return bump_error_count(c).map(|()| (Arg::Bogus, false));
         }
 
@@ -620,19 +799,50 @@ pub unsafe fn compile_block(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
     (*c).func_blocks_count += 1;
     da_append(&mut (*c).func_scope_events, ScopeEvent::BlockBegin {index});
 
+    let mut after_return = false;
     loop {
         let saved_point = (*l).parse_point;
         lexer::get_token(l)?;
         if (*l).token == Token::CCurly { break }
+        let statement_token = (*l).token;
+        let statement_loc = (*l).loc;
+        let is_label = statement_token == Token::Case || statement_token == Token::ID && {
+            lexer::get_token(l)?;
+            (*l).token == Token::Colon
+        };
         (*l).parse_point = saved_point;
 
-        compile_statement(l, c)?
+        // Declarations and empty statements don't generate any code, so they are not really "unreachable"
+        let is_declaration = matches!(statement_token, Token::Auto | Token::Extrn | Token::SemiColon);
+        if after_return && !is_label && !is_declaration {
+            if warning_enabled(c, Warning::UnreachableCode, statement_loc) {
+                diagf!(statement_loc, code = Warning::UnreachableCode.name(), c!("%s: statement after `return` or a call of a `noreturn` function is never executed [-W%s]\n"), warning_prefix(c), Warning::UnreachableCode.name());
+                bump_warning_count(c, Warning::UnreachableCode)?;
+            }
+            // Report only the first unreachable statement in a row
+            after_return = false;
+        }
+
+        compile_statement(l, c)?;
+
+        if !is_declaration {
+            after_return = match da_last(&(*c).func_body) {
+                Some(op) => match (*op).opcode {
+                    Op::Return {..} => true,
+                    // The attribute must be declared before the call for the check to know about it
+                    Op::Funcall {fun: Arg::External(name), ..} => find_attribute(da_slice((*c).program.attributes), name, AttributeKind::NoReturn).is_some(),
+                    _ => false,
+                },
+                None => false,
+            };
+        }
     }
 
     da_append(&mut (*c).func_scope_events, ScopeEvent::BlockEnd {index});
     Some(())
 }
- unsafe fn compile_function_call(l: *mut Lexer, c: *mut Compiler, fun: Arg) -> Option<Arg> {
+/// `fun_loc` is the location of the expression of the called function, the diagnostics about the call point at it
+pub unsafe fn compile_function_call(l: *mut Lexer, c: *mut Compiler, fun: Arg, fun_loc: Loc) -> Option<Arg> {
     let mut args: Array<Arg> = zeroed();
     let saved_point = (*l).parse_point;
     lexer::get_token(l)?;
@@ -651,7 +861,7 @@ pub unsafe fn compile_block(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
     }
 
     let result = allocate_auto_var(&mut (*c).auto_vars_ator);
-    push_opcode(Op::Funcall {result, fun, args}, (*l).loc, c);
+    push_opcode(Op::Funcall {result, fun, args}, fun_loc, c);
     Some(Arg::AutoVar(result))
 }
 
@@ -693,6 +903,72 @@ pub unsafe fn compile_asm_stmts(l: *mut Lexer, c: *mut Compiler, stmts: *mut Arr
     Some(())
 }
 
+/// Parses `("pattern")` of `__if_target__` and matches the pattern against the current target.
+/// Leaves the lexer right before the `{` of the selected branch.
+pub unsafe fn compile_target_condition(l: *mut Lexer, c: *mut Compiler) -> Option<bool> {
+    get_and_expect_token(l, Token::OParen)?;
+    get_and_expect_token(l, Token::String)?;
+    let pattern_loc = (*l).loc;
+    let result = glob_utf8((*l).string, (*c).target_name);
+    get_and_expect_token(l, Token::CParen)?;
+
+    let saved_point = (*l).parse_point;
+    get_and_expect_token(l, Token::OCurly)?;
+    (*l).parse_point = saved_point;
+
+    match result {
+        Glob_Result::MATCHED   => Some(true),
+        Glob_Result::UNMATCHED => Some(false),
+        Glob_Result::OOM_ERROR => {
+            diagf!(pattern_loc, c!("ERROR: out of memory while matching the target pattern\n"));
+            None
+        }
+        Glob_Result::ENCODING_ERROR | Glob_Result::SYNTAX_ERROR => {
+            diagf!(pattern_loc, code = c!("invalid-target-pattern"), c!("ERROR: invalid target pattern\n"));
+            bump_error_count(c).map(|()| false)
+        }
+    }
+}
+
+/// Skips a branch of `__if_target__` that was not selected. The branch is only lexed, not compiled.
+/// Expects the lexer to be right after the opening `{`.
+pub unsafe fn skip_target_block(l: *mut Lexer) -> Option<()> {
+    let start_loc = (*l).loc;
+    let mut depth = 1;
+    while depth > 0 {
+        lexer::get_token(l)?;
+        match (*l).token {
+            Token::OCurly => depth += 1,
+            Token::CCurly => depth -= 1,
+            Token::EOF => {
+                diagf!(start_loc, code = c!("unfinished-block"), c!("ERROR: unfinished block\n"));
+                return None;
+            }
+            _ => {}
+        }
+    }
+    Some(())
+}
+
+/// Skips the `else` branches that follow the selected branch of `__if_target__`
+pub unsafe fn skip_else_target_branches(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
+    let saved_point = (*l).parse_point;
+    lexer::get_token(l)?;
+    if (*l).token != Token::Else {
+        (*l).parse_point = saved_point;
+        return Some(());
+    }
+    get_and_expect_tokens(l, &[Token::OCurly, Token::IfTarget])?;
+    if (*l).token == Token::IfTarget {
+        compile_target_condition(l, c)?;
+        get_and_expect_token(l, Token::OCurly)?;
+        skip_target_block(l)?;
+        skip_else_target_branches(l, c)
+    } else {
+        skip_target_block(l)
+    }
+}
+
 pub unsafe fn compile_statement(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
     let saved_point = (*l).parse_point;
     lexer::get_token(l)?;
@@ -706,6 +982,7 @@ pub unsafe fn compile_statement(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
             let saved_auto_vars_count = (*c).auto_vars_ator.count;
             compile_block(l, c)?;
             (*c).auto_vars_ator.count = saved_auto_vars_count;
+            check_unused_vars(c)?;
             scope_pop(&mut (*c).vars);
             Some(())
         }
@@ -744,6 +1021,26 @@ pub unsafe fn compile_statement(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
             }
             compile_statement(l, c)
         }
+        Token::IfTarget => {
+            if compile_target_condition(l, c)? {
+                compile_statement(l, c)?;
+                skip_else_target_branches(l, c)
+            } else {
+                get_and_expect_token(l, Token::OCurly)?;
+                skip_target_block(l)?;
+                let saved_point = (*l).parse_point;
+                lexer::get_token(l)?;
+                if (*l).token == Token::Else {
+                    let saved_point = (*l).parse_point;
+                    get_and_expect_tokens(l, &[Token::OCurly, Token::IfTarget])?;
+                    (*l).parse_point = saved_point;
+                    compile_statement(l, c)
+                } else {
+                    (*l).parse_point = saved_point;
+                    Some(())
+                }
+            }
+        }
         Token::If => {
             get_and_expect_token_but_continue(l, c, Token::OParen)?;
                 let saved_auto_vars_count = (*c).auto_vars_ator.count;
@@ -853,7 +1150,7 @@ pub unsafe fn compile_statement(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
 
                 Some(())
             } else {
-                diagf!(case_loc, c!("ERROR: case label outside of switch\n"));
+                diagf!(case_loc, code = c!("case-outside-switch"), c!("ERROR: case label outside of switch\n"));
                 bump_error_count(c)
             }
         }
@@ -904,6 +1201,14 @@ pub unsafe fn usage() {
     fprintf(stderr(), c!("Usage: %s [OPTIONS] <inputs...> [--] [run arguments]\n"), flag_program_name());
     fprintf(stderr(), c!("OPTIONS:\n"));
     flag_print_options(stderr());
+    fprintf(stderr(), c!("    -W<name>, -Wno-<name>\n"));
+    fprintf(stderr(), c!("        Enable or disable a warning. `all` stands for all of them. All warnings are enabled by default:\n"));
+    for i in 0..WARNING_ORDER.len() {
+        let warning = (*WARNING_ORDER)[i];
+        fprintf(stderr(), c!("          %-16s - %s\n"), warning.name(), warning.description());
+    }
+    fprintf(stderr(), c!("    -Werror\n"));
+    fprintf(stderr(), c!("        Treat warnings as errors\n"));
 }
 
 #[derive(Clone, Copy)]
@@ -938,16 +1243,141 @@ pub struct Compiler {
     /// Dynamic Arrays and this Arena.
     pub arena: Arena,
     pub error_count: usize,
+    pub warnings: Warnings,
     pub historical: bool,
+    /// Name of the current target. Used by `__if_target__`.
+    pub target_name: *const c_char,
+    /// Directories where `__include__` looks for the files that are not found next to the including file.
+    pub include_paths: Array<*const c_char>,
+    /// Real paths of all the files that were compiled so far. Each file is compiled only once
+    /// no matter how many times it is included or passed to the compiler.
+    pub compiled_files: Array<*const c_char>,
+    /// Real paths of the files that `__include__` did not compile since they were already compiled.
+    pub skipped_includes: Array<*const c_char>,
+    /// Paths of the libb files among the inputs. Warnings are not reported for them since the user can't fix them anyway.
+    pub libb_files: Array<*const c_char>,
+    /// Directory of the cache of the compiled files (see cache.rs). Null if the cache is disabled.
+    pub cache_dir: *const c_char,
+    /// Hash of all the settings of the compilation that affect the compiled files
+    pub cache_salt: u64,
+    /// Amount of the global names declared before the file that is going to be cached. The file compiles differently
+    /// without them, so the ones it uses are collected into `outer_names` and checked before reusing the cache entry.
+    pub outer_vars_count: usize,
+    pub outer_names: Array<(*const c_char, Loc)>,
 }
 
 #[derive(Clone, Copy)]
 pub struct UsedFunc {
     name: *const c_char,
     loc: Loc,
+    /// The closest local name visible at the point of usage
+    suggestion: Option<Suggestion>,
+}
+
+enum_with_order! {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Warning in WARNING_ORDER {
+        UnusedVariable,
+        UnusedLabel,
+        UnusedExtrn,
+        UnreachableCode,
+        Shadow,
+        CallArity,
+    }
+}
+
+impl Warning {
+    pub unsafe fn name(self) -> *const c_char {
+        match self {
+            Self::UnusedVariable  => c!("unused-variable"),
+            Self::UnusedLabel     => c!("unused-label"),
+            Self::UnusedExtrn     => c!("unused-extrn"),
+            Self::UnreachableCode => c!("unreachable-code"),
+            Self::Shadow          => c!("shadow"),
+            Self::CallArity       => c!("call-arity"),
+        }
+    }
+
+    pub unsafe fn description(self) -> *const c_char {
+        match self {
+            Self::UnusedVariable  => c!("auto variable is declared but never used"),
+            Self::UnusedLabel     => c!("goto label is defined but never used"),
+            Self::UnusedExtrn     => c!("name is declared with extrn inside of a function but never used"),
+            Self::UnreachableCode => c!("statement right after return is never executed"),
+            Self::Shadow          => c!("declaration shadows a variable of an outer scope"),
+            Self::CallArity       => c!("function is called with a different amount of arguments than it is defined with"),
+        }
+    }
+
+    pub unsafe fn from_name(name: *const c_char) -> Option<Self> {
+        for i in 0..WARNING_ORDER.len() {
+            let warning = (*WARNING_ORDER)[i];
+            if strcmp(warning.name(), name) == 0 {
+                return Some(warning)
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Warnings {
+    pub disabled: [bool; WARNING_ORDER.len()],
+    pub counts: [usize; WARNING_ORDER.len()],
+    /// Treat all the enabled warnings as errors
+    pub error: bool,
+}
+
+/// Applies a single `-W<name>`, `-Wno-<name>` or `-Werror` flag. `flag` is expected to be without the `-W` prefix.
+pub unsafe fn apply_warning_flag(warnings: *mut Warnings, flag: *const c_char) -> Option<()> {
+    if strcmp(flag, c!("error")) == 0 {
+        (*warnings).error = true;
+        return Some(());
+    }
+    if strcmp(flag, c!("no-error")) == 0 {
+        (*warnings).error = false;
+        return Some(());
+    }
+
+    let (name, disabled) = if strncmp(flag, c!("no-"), 3) == 0 {
+        (flag.add(3), true)
+    } else {
+        (flag, false)
+    };
+
+    if strcmp(name, c!("all")) == 0 {
+        for i in 0..WARNING_ORDER.len() {
+            (*warnings).disabled[i] = disabled;
+        }
+        return Some(());
+    }
+
+    let Some(warning) = Warning::from_name(name) else {
+        log(Log_Level::ERROR, c!("Unknown warning `-W%s`"), flag);
+        return None;
+    };
+    (*warnings).disabled[warning as usize] = disabled;
+    Some(())
+}
+
+/// Whether the warning should be reported at `loc`
+pub unsafe fn warning_enabled(c: *const Compiler, warning: Warning, loc: Loc) -> bool {
+    !(*c).warnings.disabled[warning as usize] && !is_defined_in(loc, da_slice((*c).libb_files))
+}
+
+pub unsafe fn warning_prefix(c: *const Compiler) -> *const c_char {
+    if (*c).warnings.error { c!("ERROR") } else { c!("WARNING") }
+}
+
+/// Same as bump_error_count() but for warnings. With -Werror a warning counts as an error as well.
+pub unsafe fn bump_warning_count(c: *mut Compiler, warning: Warning) -> Option<()> {
+    (*c).warnings.counts[warning as usize] += 1;
+    if (*c).warnings.error {
+        return bump_error_count(c);
+    }
+    Some(())
 }
 
-pub const MAX_ERROR_COUNT: usize = 100;
 /// The point of this function is to indicate that a compilation error happened, but continue the compilation anyway
 /// even if the state of the Compiler became bogus. This is needed to report as many compilation errors as possible.
 /// After calling this function always continue the compilation like nothing happened.
@@ -960,34 +1390,476 @@ pub unsafe fn bump_error_count(c: *mut Compiler) -> Option<()> {
     Some(())
 }
 
+/// Compares the amount of arguments of every call of a function defined in the program against the amount of its parameters.
+/// Must be called after all the inputs are compiled since a function may be called before it is defined.
+pub unsafe fn check_call_arity(c: *mut Compiler) -> Option<()> {
+    let funcs = da_slice((*c).program.funcs);
+    for i in 0..funcs.len() {
+        let body = da_slice((*funcs)[i].body);
+        for j in 0..body.len() {
+            let op = (*body)[j];
+            let Op::Funcall {fun: Arg::External(name), args, ..} = op.opcode else { continue; };
+            if !warning_enabled(c, Warning::CallArity, op.loc) { continue; }
+
+            let mut callee: *const Func = ptr::null();
+            for k in 0..funcs.len() {
+                if strcmp((*funcs)[k].name, name) == 0 {
+                    callee = &(*funcs)[k];
+                    break;
+                }
+            }
+            // Just like in C, `main` is allowed to ignore the arguments it is called with
+            if callee.is_null() || strcmp(name, c!("main")) == 0 { continue; }
+
+            if let Some((fixed_args, variadic_loc)) = find_variadic(da_slice((*c).program.attributes), name) {
+                if args.count < fixed_args {
+                    diagf!(op.loc, code = Warning::CallArity.name(), c!("%s: variadic function `%s` expects at least %zu arguments, but %zu were provided [-W%s]\n"), warning_prefix(c), name, fixed_args, args.count, Warning::CallArity.name());
+                    diagf!(variadic_loc, c!("NOTE: the variadic declaration is located here\n"));
+                    bump_warning_count(c, Warning::CallArity)?;
+                }
+                continue;
+            }
+
+            if args.count != (*callee).params_count {
+                diagf!(op.loc, code = Warning::CallArity.name(), c!("%s: function `%s` expects %zu arguments, but %zu were provided [-W%s]\n"), warning_prefix(c), name, (*callee).params_count, args.count, Warning::CallArity.name());
+                diagf!((*callee).name_loc, c!("NOTE: the function is defined here\n"));
+                bump_warning_count(c, Warning::CallArity)?;
+            }
+        }
+    }
+    Some(())
+}
+
+/// The gas targets make `main` an alias of the function marked as the entry point, since the C runtime always calls `main`.
+/// So `main` can't be defined along with another entry point on any target.
+pub unsafe fn check_entry_point(c: *mut Compiler) -> Option<()> {
+    let attributes = da_slice((*c).program.attributes);
+    let Some(entry) = find_entry(attributes) else { return Some(()); };
+    if strcmp(entry, c!("main")) == 0 { return Some(()); }
+
+    let mut main_loc: Option<Loc> = None;
+    for i in 0..(*c).program.funcs.count {
+        let func = *(*c).program.funcs.items.add(i);
+        if strcmp(func.name, c!("main")) == 0 { main_loc = Some(func.name_loc); }
+    }
+    for i in 0..(*c).program.asm_funcs.count {
+        let asm_func = *(*c).program.asm_funcs.items.add(i);
+        if strcmp(asm_func.name, c!("main")) == 0 { main_loc = Some(asm_func.name_loc); }
+    }
+    for i in 0..(*c).program.globals.count {
+        let global = *(*c).program.globals.items.add(i);
+        if strcmp(global.name, c!("main")) == 0 { main_loc = Some(global.name_loc); }
+    }
+
+    if let Some(main_loc) = main_loc {
+        diagf!(main_loc, code = c!("entry-main"), c!("ERROR: `main` cannot be defined since `%s` is the entry point\n"), entry);
+        diagf!(find_attribute(attributes, entry, AttributeKind::Entry)?.loc, c!("NOTE: the entry point is declared here\n"));
+        bump_error_count(c)?;
+    }
+    Some(())
+}
+
+/// Functions marked with the `naked` attribute may consist only of `__asm__` statements.
+/// They are turned into the asm functions, so the codegens don't generate any prologue
+/// or epilogue for them.
+pub unsafe fn lower_naked_funcs(c: *mut Compiler) -> Option<()> {
+    let funcs = &mut (*c).program.funcs;
+    let mut i = 0;
+    while i < funcs.count {
+        let func = *funcs.items.add(i);
+        let Some(naked) = find_attribute(da_slice((*c).program.attributes), func.name, AttributeKind::Naked) else {
+            i += 1;
+            continue;
+        };
+
+        if func.auto_vars_count > 0 {
+            diagf!(func.name_loc, code = c!("naked-function"), c!("ERROR: naked function `%s` cannot have parameters or auto variables\n"), func.name);
+            diagf!(naked.loc, c!("NOTE: the function is marked naked here\n"));
+            bump_error_count(c)?;
+        }
+
+        let mut asm_func = AsmFunc {
+            name: func.name,
+            name_loc: func.name_loc,
+            body: zeroed(),
+        };
+        for j in 0..func.body.count {
+            let op = *func.body.items.add(j);
+            if let Op::Asm {stmts} = op.opcode {
+                da_append_many(&mut asm_func.body, da_slice(stmts));
+            } else {
+                diagf!(op.loc, code = c!("naked-function"), c!("ERROR: naked function `%s` may contain only `__asm__` statements\n"), func.name);
+                diagf!(naked.loc, c!("NOTE: the function is marked naked here\n"));
+                bump_error_count(c)?;
+                break;
+            }
+        }
+        da_append(&mut (*c).program.asm_funcs, asm_func);
+
+        ptr::copy(funcs.items.add(i + 1), funcs.items.add(i), funcs.count - i - 1);
+        funcs.count -= 1;
+    }
+    Some(())
+}
+
+pub unsafe fn add_attribute(c: *mut Compiler, name: *const c_char, attr: Attribute, values_count: usize) -> Option<()> {
+    let expected_values_count = match attr.kind {
+        AttributeKind::Variadic | AttributeKind::Section => 1,
+        AttributeKind::NoReturn | AttributeKind::Naked | AttributeKind::Weak | AttributeKind::Entry => 0,
+    };
+    if values_count != expected_values_count {
+        diagf!(attr.loc, code = c!("attribute-arguments"), c!("ERROR: attribute `%s` expects %zu arguments, but %zu were provided\n"), attr.kind.name(), expected_values_count, values_count);
+        return bump_error_count(c);
+    }
+
+    match (attr.kind, attr.value) {
+        (AttributeKind::Variadic, AttributeValue::Int(0)) => {
+            diagf!(attr.loc, code = c!("variadic-without-arguments"), c!("ERROR: variadic function `%s` cannot have 0 arguments\n"), name);
+            return bump_error_count(c);
+        }
+        (AttributeKind::Variadic, AttributeValue::String(_)) => {
+            diagf!(attr.loc, code = c!("attribute-arguments"), c!("ERROR: attribute `%s` expects the amount of fixed arguments, but a string was provided\n"), attr.kind.name());
+            return bump_error_count(c);
+        }
+        (AttributeKind::Section, AttributeValue::Int(_)) => {
+            diagf!(attr.loc, code = c!("attribute-arguments"), c!("ERROR: attribute `%s` expects the name of the section as a string\n"), attr.kind.name());
+            return bump_error_count(c);
+        }
+        _ => {}
+    }
+
+    let attributes = &mut (*c).program.attributes;
+    if let Some(existing) = find_attribute(da_slice(*attributes), name, attr.kind) {
+        // TODO: report all the duplicate attributes maybe?
+        diagf!(attr.loc, code = c!("duplicate-attribute"), c!("ERROR: duplicate attribute `%s` of `%s`\n"), attr.kind.name(), name);
+        diagf!(existing.loc, c!("NOTE: the first declaration is located here\n"));
+        return bump_error_count(c);
+    }
+    if attr.kind == AttributeKind::Entry {
+        if let Some(entry) = find_entry(da_slice(*attributes)) {
+            diagf!(attr.loc, code = c!("duplicate-attribute"), c!("ERROR: `%s` cannot be the entry point, the entry point is already `%s`\n"), name, entry);
+            diagf!(find_attribute(da_slice(*attributes), entry, AttributeKind::Entry)?.loc, c!("NOTE: the first declaration is located here\n"));
+            return bump_error_count(c);
+        }
+    }
+
+    if let Some(attrs) = assoc_lookup_cstr_mut(da_slice(*attributes), name) {
+        da_append(attrs, attr);
+    } else {
+        let mut attrs: Array<Attribute> = zeroed();
+        da_append(&mut attrs, attr);
+        da_append(attributes, (name, attrs));
+    }
+    Some(())
+}
+
+/// Looks for the file of `__include__` next to the including file and then in the include paths.
+pub unsafe fn resolve_include_path(c: *mut Compiler, including_path: *const c_char, path: *const c_char) -> Option<*const c_char> {
+    if *path == '/' as c_char {
+        return if file_exists(path)? { Some(path) } else { None };
+    }
+
+    let including_directory = dirname(temp_strdup(including_path));
+    let candidate = arena::sprintf(&mut (*c).arena, c!("%s/%s"), including_directory, path);
+    if file_exists(candidate)? {
+        return Some(candidate);
+    }
+
+    for i in 0..(*c).include_paths.count {
+        let candidate = arena::sprintf(&mut (*c).arena, c!("%s/%s"), *(*c).include_paths.items.add(i), path);
+        if file_exists(candidate)? {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Compiles the file unless it was already compiled before.
+pub unsafe fn compile_file(c: *mut Compiler, input_path: *const c_char, included_from: *const Loc) -> Option<()> {
+    let real_path = realpath(input_path);
+    let key = if real_path.is_null() { arena::strdup(&mut (*c).arena, input_path) } else { arena::strdup(&mut (*c).arena, real_path) };
+    free(real_path as *mut c_void);
+    for i in 0..(*c).compiled_files.count {
+        if strcmp(*(*c).compiled_files.items.add(i), key) == 0 {
+            if !included_from.is_null() {
+                da_append(&mut (*c).skipped_includes, key);
+            }
+            return Some(());
+        }
+    }
+    da_append(&mut (*c).compiled_files, key);
+
+    let mut input: String_Builder = zeroed();
+    if read_entire_file(input_path, &mut input).is_none() {
+        free(input.items);
+        return None;
+    }
+
+    // Only the files passed to the compiler directly are cached. The included ones are cached as a part of them.
+    let mut cache = None;
+    if included_from.is_null() && !(*c).cache_dir.is_null() {
+        let cache_key = cache::hash_bytes(cache::hash_cstr((*c).cache_salt, input_path), da_slice(input) as *const [u8]);
+        if load_cached_file(c, cache_key).is_some() {
+            log(Log_Level::INFO, c!("%s: reusing the cached compilation"), input_path);
+            free(input.items);
+            return Some(());
+        }
+        cache = Some((cache_key, snapshot_compiler(c)));
+        // The extrns are deduplicated, so the extrns of the file are collected separately to cache all of them
+        (*c).program.extrns = zeroed();
+        (*c).outer_vars_count = (*(*c).vars.items).count;
+        (*c).outer_names.count = 0;
+    }
+
+    let mut l: Lexer = lexer::new(input_path, input.items, input.items.add(input.count), (*c).historical);
+    l.included_from = included_from;
+
+    let result = compile_program(&mut l, c);
+    (*c).error_count += l.error_count;
+    free(input.items);
+
+    if let Some((cache_key, snapshot)) = cache {
+        (*c).outer_vars_count = 0;
+        let file_extrns = (*c).program.extrns;
+        (*c).program.extrns = snapshot.program.extrns;
+        for i in 0..file_extrns.count {
+            name_declare_if_not_exists(&mut (*c).program.extrns, *file_extrns.items.add(i));
+        }
+        if result.is_some() && (*c).error_count == snapshot.error_count && (*c).warnings.counts == snapshot.warnings_counts {
+            store_cached_file(c, cache_key, &snapshot, file_extrns);
+        }
+        free(file_extrns.items);
+    }
+    result
+}
+
+/// The state of the Compiler before compiling a file. Whatever was added to the Compiler after that is what the file produced.
+#[derive(Clone, Copy)]
+pub struct Compiler_Snapshot {
+    pub program: Program,
+    /// Amount of attributes of each name of `program.attributes`
+    pub attributes_counts: Array<usize>,
+    pub global_vars_count: usize,
+    pub used_funcs_count: usize,
+    pub compiled_files_count: usize,
+    pub skipped_includes_count: usize,
+    pub error_count: usize,
+    pub warnings_counts: [usize; WARNING_ORDER.len()],
+}
+
+pub unsafe fn snapshot_compiler(c: *mut Compiler) -> Compiler_Snapshot {
+    let mut snapshot = Compiler_Snapshot {
+        // Only the counts of the arrays are going to be used
+        program: (*c).program,
+        attributes_counts: zeroed(),
+        global_vars_count: (*(*c).vars.items).count,
+        used_funcs_count: (*c).used_funcs.count,
+        compiled_files_count: (*c).compiled_files.count,
+        skipped_includes_count: (*c).skipped_includes.count,
+        error_count: (*c).error_count,
+        warnings_counts: (*c).warnings.counts,
+    };
+    for i in 0..(*c).program.attributes.count {
+        da_append(&mut snapshot.attributes_counts, (*(*c).program.attributes.items.add(i)).1.count);
+    }
+    snapshot
+}
+
+unsafe fn array_tail<T>(xs: Array<T>, start: usize) -> Array<T> {
+    Array {items: xs.items.add(start), count: xs.count - start, capacity: xs.count - start}
+}
+
+pub unsafe fn store_cached_file(c: *mut Compiler, cache_key: u64, snapshot: *const Compiler_Snapshot, file_extrns: Array<*const c_char>) {
+    let mut entry: cache::Cache_Entry = zeroed();
+    for i in (*snapshot).compiled_files_count..(*c).compiled_files.count {
+        let path = *(*c).compiled_files.items.add(i);
+        let Some(hash) = cache::hash_file(path) else { return; };
+        da_append(&mut entry.includes, (path, hash));
+    }
+    da_append_many(&mut entry.skipped_includes, da_slice(array_tail((*c).skipped_includes, (*snapshot).skipped_includes_count)));
+    let global_vars = array_tail(*(*c).vars.items, (*snapshot).global_vars_count);
+    for i in 0..global_vars.count {
+        let var = *global_vars.items.add(i);
+        da_append(&mut entry.decls, (var.name, var.loc));
+    }
+    for i in (*snapshot).used_funcs_count..(*c).used_funcs.count {
+        let used_func = *(*c).used_funcs.items.add(i);
+        da_append(&mut entry.used_funcs, (used_func.name, used_func.loc));
+    }
+    entry.outer_names = (*c).outer_names;
+
+    let program = &(*c).program;
+    let old_program = &(*snapshot).program;
+    entry.program.funcs     = array_tail(program.funcs, old_program.funcs.count);
+    entry.program.data      = array_tail(program.data, old_program.data.count);
+    entry.program.extrns    = file_extrns;
+    entry.program.globals   = array_tail(program.globals, old_program.globals.count);
+    entry.program.asm_funcs = array_tail(program.asm_funcs, old_program.asm_funcs.count);
+    for i in 0..program.attributes.count {
+        let (name, attrs) = *program.attributes.items.add(i);
+        let old_count = if i < (*snapshot).attributes_counts.count { *(*snapshot).attributes_counts.items.add(i) } else { 0 };
+        if attrs.count > old_count {
+            da_append(&mut entry.program.attributes, (name, array_tail(attrs, old_count)));
+        }
+    }
+
+    if cache::write_entry((*c).cache_dir, cache_key, (*c).target_name, &entry, old_program.data.count).is_none() {
+        log(Log_Level::WARNING, c!("could not write the cache entry %s"), cache::entry_path((*c).cache_dir, cache_key));
+    }
+    free(entry.includes.items);
+    free(entry.skipped_includes.items);
+    free(entry.decls.items);
+    free(entry.used_funcs.items);
+    free(entry.program.attributes.items);
+}
+
+pub unsafe fn load_cached_file(c: *mut Compiler, cache_key: u64) -> Option<()> {
+    let mut entry: cache::Cache_Entry = zeroed();
+    cache::read_entry(&mut (*c).arena, (*c).cache_dir, cache_key, (*c).target_name, &mut entry)?;
+
+    // The entry must have been produced with exactly the same files already compiled, otherwise it may
+    // contain the files that should be skipped now or miss the files that should be compiled now
+    let is_compiled = |path: *const c_char| (0..(*c).compiled_files.count).any(|i| strcmp(*(*c).compiled_files.items.add(i), path) == 0);
+    if (0..entry.includes.count).any(|i| is_compiled((*entry.includes.items.add(i)).0)) { return None; }
+    if !(0..entry.skipped_includes.count).all(|i| is_compiled(*entry.skipped_includes.items.add(i))) { return None; }
+    // Compiling the file again reports the names that are missing now
+    if (0..entry.outer_names.count).any(|i| find_var_deep(&(*c).vars, (*entry.outer_names.items.add(i)).0).is_null()) { return None; }
+
+    for i in 0..entry.includes.count {
+        da_append(&mut (*c).compiled_files, (*entry.includes.items.add(i)).0);
+    }
+    da_append_many(&mut (*c).skipped_includes, da_slice(entry.skipped_includes));
+    for i in 0..entry.decls.count {
+        let (name, loc) = *entry.decls.items.add(i);
+        declare_var(c, name, loc, Storage::External {name})?;
+    }
+    for i in 0..entry.used_funcs.count {
+        let (name, loc) = *entry.used_funcs.items.add(i);
+        da_append(&mut (*c).used_funcs, UsedFunc {name, loc, suggestion: None});
+    }
+    // Added one by one to report the duplicates between the cached and the freshly compiled files
+    for i in 0..entry.program.attributes.count {
+        let (name, attrs) = *entry.program.attributes.items.add(i);
+        for j in 0..attrs.count {
+            let attr = *attrs.items.add(j);
+            let values_count = if matches!(attr.value, AttributeValue::None) { 0 } else { 1 };
+            add_attribute(c, name, attr, values_count)?;
+        }
+    }
+    entry.program.attributes = zeroed();
+    link_unit(&mut (*c).program, &mut entry.program);
+    Some(())
+}
+
 pub unsafe fn compile_program(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
+    // Locations of the `{` of the selected top level `__if_target__` branches we are currently in
+    let mut target_blocks: Array<Loc> = zeroed();
     'def: loop {
         lexer::get_token(l)?;
         match (*l).token {
-            Token::EOF => break 'def,
+            Token::EOF => {
+                for i in 0..target_blocks.count {
+                    diagf!(*target_blocks.items.add(i), code = c!("unfinished-block"), c!("ERROR: unfinished block\n"));
+                }
+                let unfinished = target_blocks.count > 0;
+                free(target_blocks.items as *mut c_void);
+                if unfinished { return None; }
+                break 'def
+            }
+            Token::Include => {
+                let include_loc = (*l).loc;
+                get_and_expect_token_but_continue(l, c, Token::OParen)?;
+                get_and_expect_token(l, Token::String)?;
+                let path = arena::strdup(&mut (*c).arena, (*l).string);
+                let path_loc = (*l).loc;
+                get_and_expect_token_but_continue(l, c, Token::CParen)?;
+                get_and_expect_token_but_continue(l, c, Token::SemiColon)?;
+                if let Some(resolved_path) = resolve_include_path(c, (*l).input_path, path) {
+                    let included_from = arena::alloc_type::<Loc>(&mut (*c).arena);
+                    *included_from = include_loc;
+                    compile_file(c, resolved_path, included_from)?;
+                } else {
+                    diagf!(path_loc, code = c!("include-not-found"), c!("ERROR: could not find `%s` to include\n"), path);
+                    if (*c).include_paths.count > 0 {
+                        let mut sb: String_Builder = zeroed();
+                        for i in 0..(*c).include_paths.count {
+                            if i > 0 { sb_appendf(&mut sb, c!(", ")); }
+                            sb_appendf(&mut sb, c!("%s"), *(*c).include_paths.items.add(i));
+                        }
+                        da_append(&mut sb, 0);
+                        diagf!(path_loc, c!("NOTE: also searched in the include paths: %s\n"), sb.items);
+                        free(sb.items);
+                    }
+                    bump_error_count(c)?;
+                }
+            }
+            Token::IfTarget => {
+                'branches: loop {
+                    let selected = compile_target_condition(l, c)?;
+                    get_and_expect_token(l, Token::OCurly)?;
+                    if selected {
+                        da_append(&mut target_blocks, (*l).loc);
+                        break 'branches;
+                    }
+                    skip_target_block(l)?;
+
+                    let saved_point = (*l).parse_point;
+                    lexer::get_token(l)?;
+                    if (*l).token != Token::Else {
+                        (*l).parse_point = saved_point;
+                        break 'branches;
+                    }
+                    get_and_expect_tokens(l, &[Token::OCurly, Token::IfTarget])?;
+                    if (*l).token == Token::OCurly {
+                        da_append(&mut target_blocks, (*l).loc);
+                        break 'branches;
+                    }
+                }
+            }
+            Token::CCurly if target_blocks.count > 0 => {
+                target_blocks.count -= 1;
+                skip_else_target_branches(l, c)?;
+            }
             Token::Variadic => {
                 get_and_expect_token_but_continue(l, c, Token::OParen)?;
                 get_and_expect_token_but_continue(l, c, Token::ID)?;
                 let func = arena::strdup(&mut (*c).arena, (*l).string);
                 let func_loc = (*l).loc;
-                if let Some(existing_variadic) = assoc_lookup_cstr(da_slice((*c).program.variadics), func) {
-                    // TODO: report all the duplicate variadics maybe?
-                    diagf!(func_loc, c!("ERROR: duplicate variadic declaration `%s`\n"), func);
-                    diagf!((*existing_variadic).loc, c!("NOTE: the first declaration is located here\n"));
-                    bump_error_count(c)?;
-                }
                 get_and_expect_token_but_continue(l, c, Token::Comma)?;
                 get_and_expect_token_but_continue(l, c, Token::IntLit)?;
-                if (*l).int_number == 0 {
-                    diagf!((*l).loc, c!("ERROR: variadic function `%s` cannot have 0 arguments\n"), func);
-                    bump_error_count(c)?;
-                }
-                da_append(&mut (*c).program.variadics, (func, Variadic {
-                    loc: func_loc,
-                    fixed_args: (*l).int_number as usize,
-                }));
+                let value = AttributeValue::Int((*l).int_number);
                 get_and_expect_token_but_continue(l, c, Token::CParen)?;
                 get_and_expect_token_but_continue(l, c, Token::SemiColon)?;
+                add_attribute(c, func, Attribute {kind: AttributeKind::Variadic, value, loc: func_loc}, 1)?;
+            }
+            Token::Attr => {
+                get_and_expect_token_but_continue(l, c, Token::OParen)?;
+                get_and_expect_token_but_continue(l, c, Token::ID)?;
+                let name = arena::strdup(&mut (*c).arena, (*l).string);
+                get_and_expect_token_but_continue(l, c, Token::Comma)?;
+                get_and_expect_token_but_continue(l, c, Token::ID)?;
+                let key = arena::strdup(&mut (*c).arena, (*l).string);
+                let key_loc = (*l).loc;
+                let mut value = AttributeValue::None;
+                let mut values_count = 0;
+                get_and_expect_tokens(l, &[Token::Comma, Token::CParen])?;
+                while (*l).token == Token::Comma {
+                    get_and_expect_tokens(l, &[Token::IntLit, Token::CharLit, Token::String])?;
+                    value = match (*l).token {
+                        Token::IntLit | Token::CharLit => AttributeValue::Int((*l).int_number),
+                        Token::String => AttributeValue::String(arena::strdup(&mut (*c).arena, (*l).string)),
+                        _ => unreachable!(),
+                    };
+                    values_count += 1;
+                    get_and_expect_tokens(l, &[Token::Comma, Token::CParen])?;
+                }
+                get_and_expect_token_but_continue(l, c, Token::SemiColon)?;
+                if let Some(kind) = AttributeKind::from_name(key) {
+                    add_attribute(c, name, Attribute {kind, value, loc: key_loc}, values_count)?;
+                } else {
+                    diagf!(key_loc, code = c!("unknown-attribute"), c!("ERROR: unknown attribute `%s`\n"), key);
+                    bump_error_count(c)?;
+                }
             }
             Token::Extrn => {
                 while (*l).token != Token::SemiColon {
@@ -1037,13 +1909,25 @@ pub unsafe fn compile_program(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
                             let used_label = *(*c).func_gotos.items.add(i);
                             let existing_label = find_goto_label(&(*c).func_goto_labels, used_label.name);
                             if existing_label.is_null() {
-                                diagf!(used_label.loc, c!("ERROR: label `%s` used but not defined\n"), used_label.name);
+                                diagf!(used_label.loc, code = c!("undefined-label"), c!("ERROR: label `%s` used but not defined\n"), used_label.name);
                                 bump_error_count(c)?;
                                 continue;
                             }
                             (*(*c).func_body.items.add(used_label.addr)).opcode = Op::JmpLabel {label: (*existing_label).label};
                         }
 
+                        'labels: for i in 0..(*c).func_goto_labels.count {
+                            let label = *(*c).func_goto_labels.items.add(i);
+                            if !warning_enabled(c, Warning::UnusedLabel, label.loc) { continue; }
+                            for j in 0..(*c).func_gotos.count {
+                                if strcmp((*(*c).func_gotos.items.add(j)).name, label.name) == 0 {
+                                    continue 'labels;
+                                }
+                            }
+                            diagf!(label.loc, code = Warning::UnusedLabel.name(), c!("%s: label `%s` is defined but never used [-W%s]\n"), warning_prefix(c), label.name, Warning::UnusedLabel.name());
+                            bump_warning_count(c, Warning::UnusedLabel)?;
+                        }
+
                         da_append(&mut (*c).program.funcs, Func {
                             name,
                             name_loc,
@@ -1103,8 +1987,7 @@ pub unsafe fn compile_program(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
                                     let scope = da_last_mut(&mut (*c).vars).expect("There should be always at least the global scope");
                                     let var = find_var_near(scope, name);
                                     if var.is_null() {
-                                        diagf!((*l).loc, c!("ERROR: could not find name `%s`\n"), name);
-                                        bump_error_count(c)?;
+                                        report_undefined_name(c, name, (*l).loc, None, false)?;
                                     }
                                     ImmediateValue::Name(name)
                                 }
@@ -1133,13 +2016,6 @@ pub unsafe fn compile_program(l: *mut Lexer, c: *mut Compiler) -> Option<()> {
     Some(())
 }
 
-pub unsafe fn include_path_if_exists(input_paths: &mut Array<*const c_char>, path: *const c_char) -> Option<()> {
-    if file_exists(path)? {
-        da_append(input_paths, path);
-    }
-    Some(())
-}
-
 pub unsafe fn get_file_name(path: *const c_char) -> *const c_char {
     let p = if cfg!(target_os = "windows") {
         let p1 = strrchr(path, '/' as i32);
@@ -1170,7 +2046,140 @@ pub unsafe fn temp_strip_file_ext(path: *const c_char) -> *const c_char {
     }
 }
 
-pub unsafe fn get_garbage_base(path: *const c_char, target: Target) -> Option<*mut c_char> {
+/// Compiles the B files into `(*c).program` making the names defined by the already compiled `units`
+/// visible to them. The units themselves are not added to the program, see unit::link_unit() for that.
+///
+/// The undefined names used in the `stripped_paths` files are not reported, since their definitions are
+/// going to be removed from the program with strip_definitions() anyway.
+pub unsafe fn compile_inputs(c: *mut Compiler, input_paths: *const [*const c_char], units: *const [Program], stripped_paths: *const [*const c_char]) -> Option<()> {
+    let mut sb: String_Builder = zeroed();
+    for i in 0..input_paths.len() {
+        if i > 0 { sb_appendf(&mut sb, c!(", ")); }
+        sb_appendf(&mut sb, c!("%s"), (*input_paths)[i]);
+    }
+    da_append(&mut sb, 0);
+    log(Log_Level::INFO, c!("compiling %zu files: %s"), input_paths.len(), sb.items);
+    free(sb.items);
+
+    let compilation_start = Instant::now();
+
+    scope_push(&mut (*c).vars);          // begin global scope
+
+    for i in 0..units.len() {
+        let unit = (*units)[i];
+        for j in 0..unit.funcs.count {
+            let func = *unit.funcs.items.add(j);
+            declare_var(c, func.name, func.name_loc, Storage::External {name: func.name})?;
+        }
+        for j in 0..unit.globals.count {
+            let global = *unit.globals.items.add(j);
+            declare_var(c, global.name, global.name_loc, Storage::External {name: global.name})?;
+        }
+        for j in 0..unit.asm_funcs.count {
+            let asm_func = *unit.asm_funcs.items.add(j);
+            declare_var(c, asm_func.name, asm_func.name_loc, Storage::External {name: asm_func.name})?;
+        }
+    }
+
+    for i in 0..input_paths.len() {
+        compile_file(c, (*input_paths)[i], ptr::null())?;
+    }
+
+    // `main` is an alias of the entry point if there is one (see check_entry_point())
+    let main_is_entry = find_entry(da_slice((*c).program.attributes)).is_some();
+    for i in 0..(*c).used_funcs.count {
+        let used_global = *(*c).used_funcs.items.add(i);
+
+        if main_is_entry && strcmp(used_global.name, c!("main")) == 0 { continue; }
+        if find_var_deep(&mut (*c).vars, used_global.name).is_null() && !is_defined_in(used_global.loc, stripped_paths) {
+            report_undefined_name(c, used_global.name, used_global.loc, used_global.suggestion, true)?;
+        }
+    }
+
+    scope_pop(&mut (*c).vars);          // end global scope
+
+    check_call_arity(c)?;
+    check_entry_point(c)?;
+    lower_naked_funcs(c)?;
+
+    let mut warnings_count = 0;
+    for i in 0..WARNING_ORDER.len() {
+        warnings_count += (*c).warnings.counts[i];
+    }
+    if warnings_count > 0 {
+        let mut sb: String_Builder = zeroed();
+        for i in 0..WARNING_ORDER.len() {
+            if (*c).warnings.counts[i] == 0 { continue; }
+            if sb.count > 0 { sb_appendf(&mut sb, c!(", ")); }
+            sb_appendf(&mut sb, c!("%s: %zu"), (*WARNING_ORDER)[i].name(), (*c).warnings.counts[i]);
+        }
+        da_append(&mut sb, 0);
+        log(Log_Level::INFO, c!("%zu %s generated (%s)"), warnings_count, if warnings_count == 1 { c!("warning") } else { c!("warnings") }, sb.items);
+        free(sb.items);
+    }
+
+    if (*c).error_count > 0 {
+        return None;
+    }
+
+    log(Log_Level::INFO, c!("compilation took %.3fs"), compilation_start.elapsed().as_secs_f64());
+    Some(())
+}
+
+unsafe fn is_defined_in(loc: Loc, paths: *const [*const c_char]) -> bool {
+    let mut loc = &loc as *const Loc;
+    while !(*loc).included_from.is_null() {
+        loc = (*loc).included_from;
+    }
+    (0..paths.len()).any(|i| strcmp((*loc).input_path, (*paths)[i]) == 0)
+}
+
+/// Turns everything defined in the `paths` files (and the files they include) into extrns.
+///
+/// The units produced by `-c` are compiled along with libb so they can see its declarations
+/// (attributes like `variadic` affect the generated code), but libb itself is linked only once
+/// during the final link step.
+pub unsafe fn strip_definitions(program: *mut Program, paths: *const [*const c_char]) {
+    let mut n = 0;
+    for i in 0..(*program).funcs.count {
+        let func = *(*program).funcs.items.add(i);
+        if is_defined_in(func.name_loc, paths) {
+            name_declare_if_not_exists(&mut (*program).extrns, func.name);
+        } else {
+            *(*program).funcs.items.add(n) = func;
+            n += 1;
+        }
+    }
+    (*program).funcs.count = n;
+
+    let mut n = 0;
+    for i in 0..(*program).globals.count {
+        let global = *(*program).globals.items.add(i);
+        if is_defined_in(global.name_loc, paths) {
+            name_declare_if_not_exists(&mut (*program).extrns, global.name);
+        } else {
+            *(*program).globals.items.add(n) = global;
+            n += 1;
+        }
+    }
+    (*program).globals.count = n;
+
+    let mut n = 0;
+    for i in 0..(*program).asm_funcs.count {
+        let asm_func = *(*program).asm_funcs.items.add(i);
+        if is_defined_in(asm_func.name_loc, paths) {
+            name_declare_if_not_exists(&mut (*program).extrns, asm_func.name);
+        } else {
+            *(*program).asm_funcs.items.add(n) = asm_func;
+            n += 1;
+        }
+    }
+    (*program).asm_funcs.count = n;
+}
+
+/// Creates the garbage folder next to `path` if it does not exist yet. See the comment near
+/// the usage of get_garbage_base() in compiler_main() to learn more about the garbage.
+pub unsafe fn get_garbage_dir(path: *const c_char) -> Option<*const c_char> {
     const GARBAGE_PATH_NAME: *const c_char = c!(".build");
 
     let filename = get_file_name(path);
@@ -1190,7 +2199,90 @@ pub unsafe fn get_garbage_base(path: *const c_char, target: Target) -> Option<*m
         write_entire_file(gitignore_path, c!("*") as *const c_void, 1)?;
     }
 
-    Some(temp_sprintf(c!("%s/%s.%s"), garbage_dir, filename, target.api.name()))
+    Some(garbage_dir)
+}
+
+/// If `save_temps_dir` is provided the garbage goes there instead of the garbage folder. Unlike in the garbage
+/// folder the base is just the name of the `path` there, so the build systems can easily find the garbage.
+pub unsafe fn get_garbage_base(path: *const c_char, target: Target, save_temps_dir: *const c_char) -> Option<*mut c_char> {
+    if !save_temps_dir.is_null() {
+        if !mkdir_if_not_exists(save_temps_dir) { return None }
+        return Some(temp_sprintf(c!("%s/%s"), save_temps_dir, get_file_name(path)));
+    }
+    let garbage_dir = get_garbage_dir(path)?;
+    Some(temp_sprintf(c!("%s/%s.%s"), garbage_dir, get_file_name(path), target.api.name()))
+}
+
+unsafe fn sb_append_make_escaped(sb: *mut String_Builder, path: *const c_char) {
+    let mut p = path;
+    while *p != 0 {
+        match *p as u8 {
+            b' ' | b'#' => sb_appendf(sb, c!("\\%c"), *p as c_int),
+            b'$'        => sb_appendf(sb, c!("$$")),
+            _           => sb_appendf(sb, c!("%c"), *p as c_int),
+        };
+        p = p.add(1);
+    }
+}
+
+/// Writes a Makefile rule stating that `target_path` depends on the `inputs` and the rest of the
+/// `compiled_files` (the ones that were included).
+pub unsafe fn write_dep_file(dep_path: *const c_char, target_path: *const c_char, inputs: *const [*const c_char], compiled_files: *const [*const c_char]) -> Option<()> {
+    let mut sb: String_Builder = zeroed();
+    sb_append_make_escaped(&mut sb, target_path);
+    sb_appendf(&mut sb, c!(":"));
+    let mut input_real_paths: Array<*mut c_char> = zeroed();
+    for i in 0..inputs.len() {
+        sb_appendf(&mut sb, c!(" \\\n  "));
+        sb_append_make_escaped(&mut sb, (*inputs)[i]);
+        da_append(&mut input_real_paths, realpath((*inputs)[i]));
+    }
+    for i in 0..compiled_files.len() {
+        let path = (*compiled_files)[i];
+        if (0..input_real_paths.count).any(|j| {
+            let real_path = *input_real_paths.items.add(j);
+            !real_path.is_null() && strcmp(real_path, path) == 0
+        }) {
+            continue;
+        }
+        sb_appendf(&mut sb, c!(" \\\n  "));
+        sb_append_make_escaped(&mut sb, path);
+    }
+    sb_appendf(&mut sb, c!("\n"));
+    for i in 0..input_real_paths.count {
+        free(*input_real_paths.items.add(i) as *mut c_void);
+    }
+    free(input_real_paths.items);
+
+    let result = write_entire_file(dep_path, sb.items as *const c_void, sb.count);
+    free(sb.items);
+    result?;
+    log(Log_Level::INFO, c!("generated %s"), dep_path);
+    Some(())
+}
+
+/// Creates the directory of the cache of the compiled files in the garbage folder next to `path`
+pub unsafe fn get_cache_dir(path: *const c_char) -> Option<*const c_char> {
+    let cache_dir = temp_sprintf(c!("%s/cache"), get_garbage_dir(path)?);
+    if !mkdir_if_not_exists(cache_dir) { return None }
+    Some(cache_dir)
+}
+
+/// Hashes everything besides the files themselves that may affect the compilation of the files. The `-C` parameters
+/// are not a part of it, since they only affect the code generation which is not cached.
+pub unsafe fn get_cache_salt(c: *const Compiler) -> u64 {
+    let mut hash = cache::hash_cstr(cache::HASH_INIT, cache::compiler_version().unwrap_or(c!("")));
+    hash = cache::hash_cstr(hash, (*c).target_name);
+    hash = cache::hash_u64(hash, (*c).historical as u64);
+    hash = cache::hash_u64(hash, (*c).warnings.error as u64);
+    for i in 0..WARNING_ORDER.len() {
+        hash = cache::hash_u64(hash, (*c).warnings.disabled[i] as u64);
+    }
+    hash = cache::hash_u64(hash, (*c).include_paths.count as u64);
+    for i in 0..(*c).include_paths.count {
+        hash = cache::hash_cstr(hash, *(*c).include_paths.items.add(i));
+    }
+    hash
 }
 
 pub unsafe fn print_available_targets(targets: *const [Target]) {
@@ -1200,7 +2292,14 @@ pub unsafe fn print_available_targets(targets: *const [Target]) {
     }
 }
 
-pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
+pub unsafe fn main(argc: i32, argv: *mut*mut c_char) -> Option<()> {
+    let result = compiler_main(argc, argv);
+    // The machine-readable diagnostics may hold onto the last reported diagnostic waiting for its NOTEs
+    lexer::diag_flush();
+    result
+}
+
+pub unsafe fn compiler_main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
     let targets = codegen::load_targets()?;
 
     let default_target;
@@ -1230,15 +2329,43 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
     let nobuild  = flag_bool(c!("nobuild"), false, temp_sprintf(c!("Skip the build step. Useful in conjunction with the -%s flag when you already have a built program and just want to run it on the specified target without rebuilding it."), flag_name(run)));
     let help        = flag_bool(c!("help"), false, c!("Print this help message"));
     let codegen_args = flag_list(PARAM_FLAG_NAME, temp_sprintf(c!("Pass an argument to the codegen of the current target selected by the -%s flag. Pass argument `-%s help` to learn more about what current codegen provides. All sorts of linker flag parameters are probably there."), flag_name(target_name), PARAM_FLAG_NAME));
-    let linker = {
+    let libb_dirs = {
         let name = c!("L");
-        flag_list(name, temp_sprintf(c!("DEPRECATED! Append a flag to the linker of the target platform. But not every target even has a linker! For backward compatibility we transform `-%s foo -%s bar -%s ...` into `-%s link-args='foo bar ...'` but do not expect every codegen to support that. Use `-%s help` to learn more about what your current codegen supports. Expect -%s to be removed entirely in the future."), name, name, name, PARAM_FLAG_NAME, PARAM_FLAG_NAME, name))
+        flag_list(name, temp_sprintf(c!("Add a directory to search libb in before the default ones. The directories from the B_LIBB_PATH environment variable are searched right after. DEPRECATED! For backward compatibility the values that start with `-` are still appended to the linker of the target platform by transforming `-%s -foo -%s -bar -%s ...` into `-%s link-args='-foo -bar ...'`, but do not expect every codegen to support that. Use `-%s help` to learn more about what your current codegen supports."), name, name, name, PARAM_FLAG_NAME, PARAM_FLAG_NAME))
     };
+    let compile_only = flag_bool(c!("c"), false, c!("Compile each input into a separate unit without linking it. The unit is a native object file on the targets that support them and a serialized IR unit (.bo) on the rest. Pass the units back to the compiler as inputs to link them into a program."));
+    let assembly_only = flag_bool(c!("S"), false, c!("Stop after generating the assembly of the program and write it to the output (if applicable for the target)"));
+    let save_temps  = flag_str(c!("save-temps"), ptr::null(), c!("Directory to write the intermediate files (assembly, objects, etc) to instead of the garbage folder. Use it as -save-temps=<dir>"));
+    let md          = flag_bool(c!("MD"), false, c!("Write a Makefile dependency file listing all the files the output was built from. The file is named after the output with the .d extension unless it is provided with -MF"));
+    let mf          = flag_str(c!("MF"), ptr::null(), c!("Path of the dependency file. Implies -MD"));
+    let print_libb  = flag_bool(c!("print-libb"), false, c!("Print the libb files that would be compiled for the current target and exit"));
+    let include_paths = flag_list(c!("I"), c!("Add a directory to the list of directories where `__include__` looks for the files"));
+    let nocache     = flag_bool(c!("nocache"), false, c!("Do not use the cache of the compiled files from the garbage folder"));
     let nostdlib    = flag_bool(c!("nostdlib"), false, c!("Do not link with standard libraries like libb and/or libc on some platforms"));
     let ir          = flag_bool(c!("ir"), false, c!("Instead of compiling, dump the IR of the program to stdout"));
     let historical  = flag_bool(c!("hist"), false, c!("Makes the compiler strictly follow the description of the B language from the \"Users' Reference to B\" by Ken Thompson as much as possible"));
     let quiet       = flag_bool(c!("q"), false, c!("Makes the compiler yap less about what it's doing"));
     let debug       = flag_bool(c!("g"), false, c!("Add debug information to the compiled program (if applicable for the target)"));
+    let diagnostics_format = flag_str(c!("diagnostics-format"), c!("text"), c!("Format of the compiler diagnostics. Available formats: text, json. `json` prints one JSON object per line per diagnostic with its severity, code, message, location and notes."));
+    let diagnostics_output = flag_str(c!("diagnostics-output"), ptr::null(), c!("File to write the diagnostics to when they are not in the `text` format. The default is stdout."));
+
+    // flag.h does not support flags with the value glued to their name like `-Wno-shadow`, so we pick them out by hand
+    let mut warning_flags: Array<*const c_char> = zeroed();
+    let mut args: Array<*mut c_char> = zeroed();
+    for i in 0..argc as usize {
+        let arg = *argv.add(i);
+        if strcmp(arg, c!("--")) == 0 {
+            da_append_many(&mut args, slice::from_raw_parts(argv.add(i), argc as usize - i));
+            break;
+        }
+        if i > 0 && strncmp(arg, c!("-W"), 2) == 0 {
+            da_append(&mut warning_flags, arg.add(2) as *const c_char);
+        } else {
+            da_append(&mut args, arg);
+        }
+    }
+    argc = args.count as i32;
+    argv = args.items;
 
     let mut input_paths: Array<*const c_char> = zeroed();
     let mut run_args: Array<*const c_char> = zeroed();
@@ -1264,11 +2391,30 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
         minimal_log_level = Log_Level::WARNING;
     }
 
+    let use_cache = !*nocache && cache::compiler_version().is_some();
+    if !*nocache && !use_cache {
+        log(Log_Level::INFO, c!("the compiler was built without B_VERSION, the cache of the compiled files is disabled"));
+    }
+
     if *help {
         usage();
         return None;
     }
 
+    let Some(format) = lexer::Diag_Format::from_name(*diagnostics_format) else {
+        usage();
+        log(Log_Level::ERROR, c!("Unknown diagnostics format `%s`"), *diagnostics_format);
+        return None;
+    };
+    lexer::diag_format = format;
+    if !(*diagnostics_output).is_null() {
+        lexer::diag_json_output = fopen(*diagnostics_output, c!("w"));
+        if lexer::diag_json_output.is_null() {
+            log(Log_Level::ERROR, c!("Could not open file %s for writing diagnostics"), *diagnostics_output);
+            return None;
+        }
+    }
+
     if (*target_name).is_null() {
         usage();
         log(Log_Level::ERROR, c!("No value is provided for -%s flag."), flag_name(target_name));
@@ -1289,20 +2435,43 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
 
     let mut c: Compiler = zeroed();
     c.historical = *historical;
-    let executable_directory = arena::strdup(&mut c.arena, dirname(flag_program_name()));
+    c.target_name = *target_name;
+    da_append_many(&mut c.include_paths, da_slice(*include_paths));
+    for i in 0..warning_flags.count {
+        apply_warning_flag(&mut c.warnings, *warning_flags.items.add(i))?;
+    }
 
-    if (*linker).count > 0 {
+    let mut user_libb_paths: Array<*const c_char> = zeroed();
+    let mut linker: Array<*const c_char> = zeroed();
+    for i in 0..(*libb_dirs).count {
+        let arg = *(*libb_dirs).items.add(i);
+        if *arg == '-' as c_char {
+            da_append(&mut linker, arg);
+        } else {
+            da_append(&mut user_libb_paths, arg);
+        }
+    }
+    if linker.count > 0 {
         let mut s: Shlex = zeroed();
-        for i in 0..(*linker).count {
-            shlex_append_quoted(&mut s, *(*linker).items.add(i));
+        for i in 0..linker.count {
+            shlex_append_quoted(&mut s, *linker.items.add(i));
         }
         let codegen_arg = temp_sprintf(c!("link-args=%s"), shlex_join(&mut s));
         da_append(codegen_args, codegen_arg);
         shlex_free(&mut s);
-        log(Log_Level::WARNING, c!("Flag -%s is DEPRECATED! Interpreting it as `-%s %s` instead."), flag_name(linker), PARAM_FLAG_NAME, codegen_arg);
+        log(Log_Level::WARNING, c!("Passing linker flags with -%s is DEPRECATED! Interpreting it as `-%s %s` instead."), flag_name(libb_dirs), PARAM_FLAG_NAME, codegen_arg);
+    }
+    free(linker.items);
+    let libb_paths = libb_search_paths(&mut c, da_slice(user_libb_paths));
+
+    if *print_libb {
+        let mut libb_files: Array<*const c_char> = zeroed();
+        add_libb_files(da_slice(libb_paths), *target_name, &mut libb_files, &mut c)?;
+        for i in 0..libb_files.count {
+            printf(c!("%s\n"), *libb_files.items.add(i));
+        }
+        return Some(());
     }
-
-    let gen = target.new(&mut c.arena, da_slice(*codegen_args))?;
 
     if input_paths.count == 0 {
         usage();
@@ -1310,61 +2479,150 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
         return None;
     }
 
-    if !*nobuild {
-        if !*nostdlib {
-            // TODO: should be probably a list libb paths which we sequentually probe to find which one exists.
-            //   And of course we should also enable the user to append additional paths via the command line.
-            //   Paths to potentially check by default:
-            //   - Current working directory
-            //   - Some system paths like /usr/include/libb on Linux? (Not 100% sure about this one)
-            //   - Some sort of instalation prefix? (Requires making build system more complicated)
-            //
-            //     - rexim (2025-06-12 20:56:08)
-            add_libb_files(arena::sprintf(&mut c.arena, c!("%s/libb/"), executable_directory), *target_name, &mut input_paths, &mut c);
+    // The inputs that were already compiled into units or native objects are only linked
+    let first_input_path = *input_paths.items;
+    let mut unit_paths: Array<*const c_char> = zeroed();
+    let mut objects: Array<*const c_char> = zeroed();
+    let mut b_paths: Array<*const c_char> = zeroed();
+    for i in 0..input_paths.count {
+        let input_path = *input_paths.items.add(i);
+        match get_file_ext(input_path) {
+            Some(ext) if strcmp(ext, UNIT_FILE_EXT) == 0 => da_append(&mut unit_paths, input_path),
+            Some(ext) if strcmp(ext, c!(".o")) == 0 || strcmp(ext, c!(".obj")) == 0 || strcmp(ext, c!(".a")) == 0 => da_append(&mut objects, input_path),
+            _ => da_append(&mut b_paths, input_path),
         }
+    }
+    input_paths = b_paths;
 
-        let mut sb: String_Builder = zeroed();
+    if *compile_only {
+        if *run || *nobuild || *ir || *assembly_only {
+            log(Log_Level::ERROR, c!("-%s can't be combined with -%s, -%s, -%s or -%s"), flag_name(compile_only), flag_name(run), flag_name(nobuild), flag_name(ir), flag_name(assembly_only));
+            return None;
+        }
+        if unit_paths.count > 0 || objects.count > 0 {
+            log(Log_Level::ERROR, c!("-%s expects only B files as inputs, but got %s that is already compiled"), flag_name(compile_only), if unit_paths.count > 0 { *unit_paths.items } else { *objects.items });
+            return None;
+        }
+        if !(*output_path).is_null() && input_paths.count > 1 {
+            log(Log_Level::ERROR, c!("-%s can't be used with -%s when compiling several files"), flag_name(output_path), flag_name(compile_only));
+            return None;
+        }
+        if !(*mf).is_null() && input_paths.count > 1 {
+            log(Log_Level::ERROR, c!("-%s can't be used with -%s when compiling several files"), flag_name(mf), flag_name(compile_only));
+            return None;
+        }
+
+        let cache_salt = get_cache_salt(&c);
+        let unit_ext = if target.object_file_ext().is_null() { UNIT_FILE_EXT } else { target.object_file_ext() };
         for i in 0..input_paths.count {
             let input_path = *input_paths.items.add(i);
-            if i > 0 { sb_appendf(&mut sb, c!(", ")); }
-            sb_appendf(&mut sb, c!("%s"), input_path);
-        }
-        da_append(&mut sb, 0);
-        log(Log_Level::INFO, c!("compiling %zu files: %s"), input_paths.count, sb.items);
 
-        let compilation_start = Instant::now();
+            let mut uc: Compiler = zeroed();
+            uc.historical = c.historical;
+            uc.target_name = c.target_name;
+            uc.warnings = c.warnings;
+            da_append_many(&mut uc.include_paths, da_slice(c.include_paths));
 
-        let mut input: String_Builder = zeroed();
+            let unit_path = if (*output_path).is_null() {
+                temp_sprintf(c!("%s%s"), temp_strip_file_ext(input_path), unit_ext)
+            } else {
+                *output_path
+            };
+            if use_cache {
+                uc.cache_dir = get_cache_dir(unit_path)?;
+                uc.cache_salt = cache_salt;
+            }
 
-        scope_push(&mut c.vars);          // begin global scope
+            let mut unit_inputs: Array<*const c_char> = zeroed();
+            da_append(&mut unit_inputs, input_path);
+            if !*nostdlib {
+                add_libb_files(da_slice(libb_paths), *target_name, &mut unit_inputs, &mut uc)?;
+            }
+            let libb_files = slice::from_raw_parts(unit_inputs.items.add(1), unit_inputs.count - 1);
+            compile_inputs(&mut uc, da_slice(unit_inputs), &[], libb_files)?;
+            strip_definitions(&mut uc.program, libb_files);
+            if target.object_file_ext().is_null() {
+                let mut output: String_Builder = zeroed();
+                write_unit(&mut output, &uc.program, *target_name, 0);
+                write_entire_file(unit_path, output.items as *const c_void, output.count)?;
+                log(Log_Level::INFO, c!("generated %s"), unit_path);
+                free(output.items);
+            } else {
+                let gen = target.new(&mut uc.arena, da_slice(*codegen_args))?;
+                let garbage_base = get_garbage_base(unit_path, target, *save_temps)?;
+                target.build(gen, &uc.program, unit_path, garbage_base, *nostdlib, *debug, BuildStage::Object, &[])?;
+            }
 
-        for i in 0..input_paths.count {
-            let input_path = *input_paths.items.add(i);
+            if *md || !(*mf).is_null() {
+                let dep_path = if (*mf).is_null() { temp_sprintf(c!("%s.d"), temp_strip_file_ext(unit_path)) } else { *mf };
+                write_dep_file(dep_path, unit_path, da_slice(unit_inputs), da_slice(uc.compiled_files))?;
+            }
+        }
+        return Some(());
+    }
+
+    let stage = if *assembly_only {
+        if target.assembly_file_ext().is_null() {
+            log(Log_Level::ERROR, c!("target `%s` does not support -%s"), *target_name, flag_name(assembly_only));
+            return None;
+        }
+        if *run {
+            log(Log_Level::ERROR, c!("-%s can't be combined with -%s"), flag_name(assembly_only), flag_name(run));
+            return None;
+        }
+        if objects.count > 0 {
+            log(Log_Level::ERROR, c!("-%s can't be used with the native object files like %s"), flag_name(assembly_only), *objects.items);
+            return None;
+        }
+        BuildStage::Assembly
+    } else {
+        BuildStage::Program
+    };
+    let output_file_ext = if stage == BuildStage::Assembly { target.assembly_file_ext() } else { target.file_ext() };
 
-            input.count = 0;
-            read_entire_file(input_path, &mut input)?;
+    if objects.count > 0 && target.object_file_ext().is_null() {
+        log(Log_Level::ERROR, c!("target `%s` can't link native object files like %s"), *target_name, *objects.items);
+        return None;
+    }
 
-            let mut l: Lexer = lexer::new(input_path, input.items, input.items.add(input.count), *historical);
+    let gen = target.new(&mut c.arena, da_slice(*codegen_args))?;
 
-            compile_program(&mut l, &mut c)?;
+    let program_path = if (*output_path).is_null() {
+        temp_sprintf(c!("%s%s"), temp_strip_file_ext(first_input_path), output_file_ext)
+    } else {
+        if get_file_ext(*output_path).is_some() {
+            *output_path
+        } else {
+            temp_sprintf(c!("%s%s"), *output_path, output_file_ext)
         }
+    };
 
-        for i in 0..c.used_funcs.count {
-            let used_global = *c.used_funcs.items.add(i);
-
-            if find_var_deep(&mut c.vars, used_global.name).is_null() {
-                diagf!(used_global.loc, c!("ERROR: could not find name `%s`\n"), used_global.name);
-                bump_error_count(&mut c)?;
-            }
+    if !*nobuild {
+        if use_cache {
+            c.cache_dir = get_cache_dir(program_path)?;
+            c.cache_salt = get_cache_salt(&c);
         }
 
-        scope_pop(&mut c.vars);          // end global scope
+        let mut units: Array<Program> = zeroed();
+        for i in 0..unit_paths.count {
+            let unit_path = *unit_paths.items.add(i);
+            let mut data: String_Builder = zeroed();
+            read_entire_file(unit_path, &mut data)?;
+            let mut unit: Program = zeroed();
+            read_unit(&mut c.arena, unit_path, ptr::slice_from_raw_parts(data.items as *const u8, data.count), *target_name, &mut unit)?;
+            free(data.items);
+            da_append(&mut units, unit);
+        }
 
-        if c.error_count > 0 {
-            return None;
+        if !*nostdlib {
+            add_libb_files(da_slice(libb_paths), *target_name, &mut input_paths, &mut c)?;
         }
 
-        log(Log_Level::INFO, c!("compilation took %.3fs"), compilation_start.elapsed().as_secs_f64());
+        compile_inputs(&mut c, da_slice(input_paths), da_slice(units), &[])?;
+
+        for i in 0..units.count {
+            link_unit(&mut c.program, units.items.add(i));
+        }
     }
 
     if *ir {
@@ -1378,16 +2636,6 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
         return Some(())
     }
 
-    let program_path = if (*output_path).is_null() {
-        temp_sprintf(c!("%s%s"), temp_strip_file_ext(*input_paths.items), target.file_ext())
-    } else {
-        if get_file_ext(*output_path).is_some() {
-            *output_path
-        } else {
-            temp_sprintf(c!("%s%s"), *output_path, target.file_ext())
-        }
-    };
-
     // Compiler may produce lots of intermediate files (assembly,
     // object, etc) also known collectively as "garbage". We are
     // trying to keep the garbage away from the user in a separate
@@ -1400,10 +2648,20 @@ pub unsafe fn main(mut argc: i32, mut argv: *mut*mut c_char) -> Option<()> {
     //
     // Let's say you want to output an object file somewhere. The path
     // to that object should be computed as `temp_sprintf("%s.o", garbase_base)`.
-    let garbage_base = get_garbage_base(program_path, target)?;
+    let garbage_base = get_garbage_base(program_path, target, *save_temps)?;
 
     if !*nobuild {
-        target.build(gen, &c.program, program_path, garbage_base, *nostdlib, *debug)?;
+        target.build(gen, &c.program, program_path, garbage_base, *nostdlib, *debug, stage, da_slice(objects))?;
+
+        if *md || !(*mf).is_null() {
+            let dep_path = if (*mf).is_null() { temp_sprintf(c!("%s.d"), temp_strip_file_ext(program_path)) } else { *mf };
+            let mut deps: Array<*const c_char> = zeroed();
+            da_append_many(&mut deps, da_slice(input_paths));
+            da_append_many(&mut deps, da_slice(unit_paths));
+            da_append_many(&mut deps, da_slice(objects));
+            write_dep_file(dep_path, program_path, da_slice(deps), da_slice(c.compiled_files))?;
+            free(deps.items);
+        }
     }
 
     if *run {
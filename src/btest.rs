@@ -20,6 +20,7 @@ pub mod lexer;
 pub mod codegen;
 pub mod shlex;
 pub mod params;
+pub mod dwarf;
 
 use core::ffi::*;
 use core::cmp;
@@ -168,9 +169,9 @@ pub unsafe fn execute_test(
         c!("-nobuild"),
         c!("-run"),
     }
-    // Hack for Uxn
-    if strcmp(target.api.name(), c!("uxn")) == 0 {
-        cmd_append!(cmd, c!("-C"), c!("runner=uxncli"));
+    // The default runners of Uxn and 6502 are the external emulators, run the tests in the builtin ones instead
+    if strcmp(target.api.name(), c!("uxn")) == 0 || strcmp(target.api.name(), c!("6502-posix")) == 0 {
+        cmd_append!(cmd, c!("-C"), c!("runner=builtin"));
     }
     let mut fdout = fd_open_for_write(stdout_path);
     let mut redirect: Cmd_Redirect = zeroed();
@@ -0,0 +1,160 @@
+//! On-disk cache of the compiled B files.
+//!
+//! The IR of a B file depends only on its content, the content of the files it includes and the settings of
+//! the compiler. So after compiling a file we store its IR in the garbage folder and reuse it the next time the
+//! same file is compiled with the same settings. This mostly pays off for libb which is compiled along with
+//! every single program.
+//!
+//! The generated code is not cached. The codegens generate the whole program at once, resolving the labels and
+//! the relocations between the functions of all files, so the code generation runs on every compilation.
+//!
+//! The files that produced any diagnostics are never cached, so the diagnostics are reported on every compilation.
+
+use core::ffi::*;
+use core::mem::zeroed;
+use crate::nob::*;
+use crate::ir::*;
+use crate::lexer::Loc;
+use crate::crust::libc::*;
+use crate::arena::Arena;
+use crate::unit::*;
+
+/// Changes whenever the compiler itself changes. See the B_VERSION in the Makefile. The compiler built without it
+/// can't tell its own entries from the entries of the other builds, so it does not use the cache at all.
+pub const COMPILER_VERSION: Option<&str> = option_env!("B_VERSION");
+const CACHE_MAGIC: u64 = 0x45484341_43_42; // "BCACHE" in little-endian
+
+pub const HASH_INIT: u64 = 0xcbf29ce484222325;
+
+pub unsafe fn compiler_version() -> Option<*const c_char> {
+    COMPILER_VERSION.map(|version| temp_sprintf(c!("%.*s"), version.len() as c_int, version.as_ptr()) as *const c_char)
+}
+
+/// 64-bit FNV-1a
+pub unsafe fn hash_bytes(mut hash: u64, bytes: *const [u8]) -> u64 {
+    for i in 0..bytes.len() {
+        hash ^= (*bytes)[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub unsafe fn hash_u64(hash: u64, value: u64) -> u64 {
+    hash_bytes(hash, &value.to_le_bytes())
+}
+
+pub unsafe fn hash_cstr(hash: u64, s: *const c_char) -> u64 {
+    // Hash the terminator too so "ab", "c" and "a", "bc" are different
+    hash_bytes(hash, core::ptr::slice_from_raw_parts(s as *const u8, strlen(s) + 1))
+}
+
+pub unsafe fn hash_file(path: *const c_char) -> Option<u64> {
+    let mut content: String_Builder = zeroed();
+    if read_entire_file(path, &mut content).is_none() {
+        free(content.items);
+        return None;
+    }
+    let hash = hash_bytes(HASH_INIT, da_slice(content) as *const [u8]);
+    free(content.items);
+    Some(hash)
+}
+
+#[derive(Clone, Copy)]
+pub struct Cache_Entry {
+    /// Files that were included by the cached file along with the hashes of their content
+    pub includes: Array<(*const c_char, u64)>,
+    /// Files that the cached file included, but they were already compiled by then
+    pub skipped_includes: Array<*const c_char>,
+    /// Top level declarations of the cached file
+    pub decls: Array<(*const c_char, Loc)>,
+    /// Names that were used as functions without being declared
+    pub used_funcs: Array<(*const c_char, Loc)>,
+    /// Names declared by the previously compiled files that the cached file used
+    pub outer_names: Array<(*const c_char, Loc)>,
+    pub program: Program,
+}
+
+pub unsafe fn entry_path(cache_dir: *const c_char, key: u64) -> *const c_char {
+    temp_sprintf(c!("%s/%016llx.bc"), cache_dir, key)
+}
+
+/// Offsets into the data section of the entry's program are relative to `data_base`. See unit::write_unit().
+pub unsafe fn write_entry(cache_dir: *const c_char, key: u64, target_name: *const c_char, entry: *const Cache_Entry, data_base: usize) -> Option<()> {
+    let mut output: String_Builder = zeroed();
+    write_u64(&mut output, CACHE_MAGIC);
+    write_string(&mut output, compiler_version()?);
+    write_u64(&mut output, key);
+
+    write_u64(&mut output, (*entry).includes.count as u64);
+    for i in 0..(*entry).includes.count {
+        let (path, hash) = *(*entry).includes.items.add(i);
+        write_string(&mut output, path);
+        write_u64(&mut output, hash);
+    }
+    write_u64(&mut output, (*entry).skipped_includes.count as u64);
+    for i in 0..(*entry).skipped_includes.count {
+        write_string(&mut output, *(*entry).skipped_includes.items.add(i));
+    }
+    for names in [(*entry).decls, (*entry).used_funcs, (*entry).outer_names] {
+        write_u64(&mut output, names.count as u64);
+        for i in 0..names.count {
+            let (name, loc) = *names.items.add(i);
+            write_string(&mut output, name);
+            write_loc(&mut output, loc);
+        }
+    }
+    write_unit(&mut output, &(*entry).program, target_name, data_base);
+
+    let result = write_entire_file(entry_path(cache_dir, key), output.items as *const c_void, output.count);
+    free(output.items);
+    result
+}
+
+/// Returns None if there is no valid entry for the `key`. The included files of the entry are checked to be unchanged.
+pub unsafe fn read_entry(a: *mut Arena, cache_dir: *const c_char, key: u64, target_name: *const c_char, entry: *mut Cache_Entry) -> Option<()> {
+    let path = entry_path(cache_dir, key);
+    if !file_exists(path)? { return None; }
+
+    let mut data: String_Builder = zeroed();
+    read_entire_file(path, &mut data)?;
+    let mut reader = Unit_Reader {
+        path,
+        data: data.items as *const u8,
+        count: data.count,
+        pos: 0,
+        arena: a,
+        // A broken entry is just a cache miss
+        quiet: true,
+    };
+    let result = read_entry_from(&mut reader, key, target_name, entry);
+    free(data.items);
+    result
+}
+
+unsafe fn read_entry_from(r: *mut Unit_Reader, key: u64, target_name: *const c_char, entry: *mut Cache_Entry) -> Option<()> {
+    if read_u64(r)? != CACHE_MAGIC { return None; }
+    // Entries of the other versions of the compiler are just stale, not broken
+    if strcmp(read_string(r)?, compiler_version()?) != 0 { return None; }
+    if read_u64(r)? != key { return None; }
+
+    let includes_count = read_usize(r)?;
+    for _ in 0..includes_count {
+        let path = read_string(r)?;
+        let hash = read_u64(r)?;
+        if hash_file(path) != Some(hash) { return None; }
+        da_append(&mut (*entry).includes, (path, hash));
+    }
+    let skipped_includes_count = read_usize(r)?;
+    for _ in 0..skipped_includes_count {
+        da_append(&mut (*entry).skipped_includes, read_string(r)?);
+    }
+    for names in [&mut (*entry).decls, &mut (*entry).used_funcs, &mut (*entry).outer_names] {
+        let count = read_usize(r)?;
+        for _ in 0..count {
+            let name = read_string(r)?;
+            let loc = read_loc(r)?;
+            da_append(names, (name, loc));
+        }
+    }
+    read_unit_from(r, target_name, &mut (*entry).program)
+}
@@ -1,15 +1,16 @@
 use core::ffi::*;
 use core::mem::zeroed;
+use core::ptr;
 use crate::nob::*;
 use crate::crust::libc::*;
-use crate::crust::assoc_lookup_cstr;
 use crate::ir::*;
 use crate::lexer::*;
 use crate::missingf;
-use crate::targets::{Os, TargetAPI};
+use crate::targets::{Os, TargetAPI, BuildStage};
 use crate::shlex::*;
 use crate::arena;
 use crate::params::*;
+use crate::dwarf;
 
 pub unsafe fn align_bytes(bytes: usize, alignment: usize) -> usize {
     let rem = bytes%alignment;
@@ -127,16 +128,26 @@ pub unsafe fn load_arg_to_reg(arg: Arg, reg: *const c_char, output: *mut String_
     };
 }
 
-pub unsafe fn generate_function(name: *const c_char, _name_loc: Loc, params_count: usize, auto_vars_count: usize, os: Os, variadics: *const [(*const c_char, Variadic)], body: *const [OpWithLocation], output: *mut String_Builder) {
-    let stack_size = align_bytes(auto_vars_count*8, 16);
+pub unsafe fn generate_function_label(name: *const c_char, attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder, os: Os) {
+    if let Some(Attribute {value: AttributeValue::String(section), ..}) = find_attribute(attributes, name, AttributeKind::Section) {
+        sb_appendf(output, c!(".pushsection %s\n"), section);
+    }
+    let weak = find_attribute(attributes, name, AttributeKind::Weak).is_some();
     match os {
         Os::Linux => {
-            sb_appendf(output, c!(".global %s\n"), name);
+            if weak {
+                sb_appendf(output, c!(".weak %s\n"), name);
+            } else {
+                sb_appendf(output, c!(".global %s\n"), name);
+            }
             sb_appendf(output, c!(".p2align 4\n"));
             sb_appendf(output, c!("%s:\n"), name);
         }
         Os::Darwin => {
             sb_appendf(output, c!(".global _%s\n"), name);
+            if weak {
+                sb_appendf(output, c!(".weak_definition _%s\n"), name);
+            }
             sb_appendf(output, c!(".p2align 4\n"));
             sb_appendf(output, c!("_%s:\n"), name);
         }
@@ -144,9 +155,76 @@ pub unsafe fn generate_function(name: *const c_char, _name_loc: Loc, params_coun
             todo!("AArch64 is not supported on windows\n")
         }
     }
+}
+
+pub unsafe fn generate_function_end(name: *const c_char, attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder) {
+    if find_attribute(attributes, name, AttributeKind::Section).is_some() {
+        sb_appendf(output, c!(".popsection\n"));
+    }
+}
+
+/// The C runtime always calls `main`, so if some other function is marked as the entry point
+/// `main` becomes an alias to it.
+pub unsafe fn generate_entry_alias(attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder, os: Os) {
+    let Some(entry) = find_entry(attributes) else { return; };
+    if strcmp(entry, c!("main")) == 0 { return; }
+    match os {
+        Os::Linux => {
+            sb_appendf(output, c!(".global main\n"));
+            sb_appendf(output, c!(".set main, %s\n"), entry);
+        }
+        Os::Darwin => {
+            sb_appendf(output, c!(".global _main\n"));
+            sb_appendf(output, c!(".set _main, _%s\n"), entry);
+        }
+        Os::Windows => {
+            todo!("AArch64 is not supported on windows\n")
+        }
+    }
+}
+
+/// Emits the labels of the lexical blocks that begin or end at the current point of the function. The labels are
+/// referenced by the DW_TAG_lexical_block entries. See generate_funcs_debuginfo().
+pub unsafe fn generate_scope_event_labels(name: *const c_char, scope_events: *const [ScopeEvent], begin: usize, end: usize, output: *mut String_Builder, os: Os) {
+    for i in begin..end {
+        match (*scope_events)[i] {
+            ScopeEvent::Declare    {  ..   } => {}
+            ScopeEvent::BlockBegin { index } => {
+                match os {
+                    Os::Linux | Os::Windows => sb_appendf(output, c!(".L%s_block_start_%zu:\n"), name, index),
+                    Os::Darwin              => sb_appendf(output, c!( "L%s_block_start_%zu:\n"), name, index),
+                };
+            }
+            ScopeEvent::BlockEnd   { index } => {
+                match os {
+                    Os::Linux | Os::Windows => sb_appendf(output, c!(".L%s_block_end_%zu:\n"), name, index),
+                    Os::Darwin              => sb_appendf(output, c!( "L%s_block_end_%zu:\n"), name, index),
+                };
+            }
+        };
+    }
+}
+
+pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, func_index: usize, params_count: usize, auto_vars_count: usize, os: Os, attributes: *const [(*const c_char, Array<Attribute>)], body: *const [OpWithLocation], scope_events: *const [ScopeEvent], debug: bool, annotator: *mut Source_Annotator, output: *mut String_Builder) {
+    let stack_size = align_bytes(auto_vars_count*8, 16);
+    generate_function_label(name, attributes, output, os);
+
+    if debug {
+        sb_appendf(output, c!("    .file %lld \"%s\"\n"), func_index, name_loc.input_path);
+        sb_appendf(output, c!("    .loc %lld %lld\n"), func_index, name_loc.line_number);
+        sb_appendf(output, c!("    .cfi_startproc\n"));
+    }
     //sb_appendf(output, c!("    stp x29, x30, [sp, -%zu]!\n"), stack_size);
     sb_appendf(output, c!("    stp x29, x30, [sp, -2*8]!\n"));
+    if debug {
+        sb_appendf(output, c!("    .cfi_def_cfa_offset 16\n"));
+        sb_appendf(output, c!("    .cfi_offset x29, -16\n"));
+        sb_appendf(output, c!("    .cfi_offset x30, -8\n"));
+    }
     sb_appendf(output, c!("    mov x29, sp\n"), name);
+    if debug {
+        sb_appendf(output, c!("    .cfi_def_cfa_register x29\n"));
+    }
     sb_appendf(output, c!("    sub sp, sp, %zu\n"), stack_size);
     assert!(auto_vars_count >= params_count);
 
@@ -163,8 +241,17 @@ pub unsafe fn generate_function(name: *const c_char, _name_loc: Loc, params_coun
         sb_appendf(output, c!("    str %s, [x29, -%zu]\n"), reg, below_index*8);
     }
 
+    let mut proccessed_scope_events = 0;
     for i in 0..body.len() {
         let op = (*body)[i];
+        if debug {
+            sb_appendf(output, c!("    .loc %lld %lld\n"), func_index, op.loc.line_number);
+            generate_scope_event_labels(name, scope_events, proccessed_scope_events, op.scope_events_count, output, os);
+            proccessed_scope_events = op.scope_events_count;
+        }
+        if !annotator.is_null() {
+            annotate_op(annotator, output, op, c!("//"));
+        }
         match op.opcode {
             Op::Bogus => unreachable!("bogus-amogus"),
             Op::Return {arg} => {
@@ -316,8 +403,8 @@ pub unsafe fn generate_function(name: *const c_char, _name_loc: Loc, params_coun
                 let mut fixed_args = 0;
                 match fun {
                     Arg::External(name) | Arg::RefExternal(name) => {
-                        if let Some(variadic) = assoc_lookup_cstr(variadics, name) {
-                            fixed_args = (*variadic).fixed_args;
+                        if let Some((variadic_fixed_args, _)) = find_variadic(attributes, name) {
+                            fixed_args = variadic_fixed_args;
                         }
                     }
                     _ => {}
@@ -393,12 +480,22 @@ pub unsafe fn generate_function(name: *const c_char, _name_loc: Loc, params_coun
     sb_appendf(output, c!("    add sp, sp, %zu\n"), stack_size);
     sb_appendf(output, c!("    ldp x29, x30, [sp], 2*8\n"));
     sb_appendf(output, c!("    ret\n"));
+
+    if debug {
+        sb_appendf(output, c!("    .cfi_endproc\n"));
+        match os {
+            Os::Linux | Os::Windows => sb_appendf(output, c!(".L%s_end:\n"), name),
+            Os::Darwin              => sb_appendf(output, c!( "L%s_end:\n"), name),
+        };
+        generate_scope_event_labels(name, scope_events, proccessed_scope_events, scope_events.len(), output, os);
+    }
 }
 
-pub unsafe fn generate_funcs(output: *mut String_Builder, funcs: *const [Func], variadics: *const [(*const c_char, Variadic)], os: Os) {
+pub unsafe fn generate_funcs(output: *mut String_Builder, funcs: *const [Func], attributes: *const [(*const c_char, Array<Attribute>)], debug: bool, annotator: *mut Source_Annotator, os: Os) {
     sb_appendf(output, c!(".text\n"));
     for i in 0..funcs.len() {
-        generate_function((*funcs)[i].name, (*funcs)[i].name_loc, (*funcs)[i].params_count, (*funcs)[i].auto_vars_count, os, variadics, da_slice((*funcs)[i].body), output);
+        generate_function((*funcs)[i].name, (*funcs)[i].name_loc, i, (*funcs)[i].params_count, (*funcs)[i].auto_vars_count, os, attributes, da_slice((*funcs)[i].body), da_slice((*funcs)[i].scope_events), debug, annotator, output);
+        generate_function_end((*funcs)[i].name, attributes, output);
     }
 }
 
@@ -469,26 +566,177 @@ pub unsafe fn generate_globals(output: *mut String_Builder, globals: *const [Glo
     }
 }
 
-pub unsafe fn generate_asm_funcs(output: *mut String_Builder, asm_funcs: *const [AsmFunc], os: Os) {
+pub unsafe fn generate_asm_funcs(output: *mut String_Builder, asm_funcs: *const [AsmFunc], attributes: *const [(*const c_char, Array<Attribute>)], os: Os) {
     for i in 0..asm_funcs.len() {
         let asm_func = (*asm_funcs)[i];
-        match os {
-            Os::Linux => {
-                sb_appendf(output, c!(".global %s\n"), asm_func.name);
-                sb_appendf(output, c!(".p2align 4\n"));
-                sb_appendf(output, c!("%s:\n"), asm_func.name);
-            }
-            Os::Darwin => {
-                sb_appendf(output, c!(".global _%s\n"), asm_func.name);
-                sb_appendf(output, c!(".p2align 4\n"));
-                sb_appendf(output, c!("_%s:\n"), asm_func.name);
-            }
-            Os::Windows => missingf!(asm_func.name_loc, c!("AArch64 is not supported on windows\n")),
+        if os == Os::Windows {
+            missingf!(asm_func.name_loc, c!("AArch64 is not supported on windows\n"));
         }
+        generate_function_label(asm_func.name, attributes, output, os);
         for j in 0..asm_func.body.count {
             let stmt = *asm_func.body.items.add(j);
             sb_appendf(output, c!("    %s\n"), stmt.line);
         }
+        generate_function_end(asm_func.name, attributes, output);
+    }
+}
+
+/// Linux only. Mach-O keeps the debug information in the `__DWARF` segment instead, see generate_program().
+pub unsafe fn generate_debuginfo(output: *mut String_Builder, funcs: Array<Func>, globals: Array<Global>, os: Os) {
+    sb_appendf(output, c!(".section .debug_abbrev\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_compilation_unit);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TAG_compile_unit);
+            sb_appendf(output, c!(".byte %lld\n"),    dwarf::CHILDREN_yes);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_stmt_list);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_sec_offset);
+        sb_appendf(output, c!(".byte 0\n"));
+        sb_appendf(output, c!(".byte 0\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_function);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TAG_subprogram);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::CHILDREN_yes);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_name);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_string);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_low_pc);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_addr);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_high_pc);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_addr);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_frame_base);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_exprloc);
+        sb_appendf(output, c!(".byte 0\n"));
+        sb_appendf(output, c!(".byte 0\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_variable);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TAG_variable);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::CHILDREN_no);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_name);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_string);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_type);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_ref4);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_location);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_exprloc);
+        sb_appendf(output, c!(".byte 0\n"));
+        sb_appendf(output, c!(".byte 0\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_block);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TAG_lexical_block);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::CHILDREN_yes);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_low_pc);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_addr);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_high_pc);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_addr);
+        sb_appendf(output, c!(".byte 0\n"));
+        sb_appendf(output, c!(".byte 0\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_type);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TAG_base_type);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::CHILDREN_no);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_byte_size);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_data1);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_encoding);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_data1);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::AT_name);
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::FORM_string);
+        sb_appendf(output, c!(".byte 0\n"));
+        sb_appendf(output, c!(".byte 0\n"));
+
+    sb_appendf(output, c!(".byte 0\n"));
+
+
+    sb_appendf(output, c!(".section .debug_info\n"));
+
+        sb_appendf(output, c!(".word .debug_info_end - .debug_info_start\n"));
+        sb_appendf(output, c!(".debug_info_start: \n"));
+        sb_appendf(output, c!(".hword %lld\n"), dwarf::version);
+        sb_appendf(output, c!(".byte %lld\n"), dwarf::UT_compile);
+        sb_appendf(output, c!(".byte %lld\n"), dwarf::addr_size);
+        sb_appendf(output, c!(".word .debug_abbrev\n"));
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_compilation_unit);
+
+            sb_appendf(output, c!(".word .debug_line\n"));
+            generate_funcs_debuginfo(output, funcs, os);
+            generate_globals_debuginfo(output, globals, os);
+
+            sb_appendf(output, c!("debug_info_word_type_offset = .-.debug_info\n"));
+            sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_type);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::default_type_size);
+            sb_appendf(output, c!(".byte %lld\n"), dwarf::DW_ATE_signed);
+            sb_appendf(output, c!(".string \"word\"\n"));
+
+        sb_appendf(output, c!(".byte 0\n"));
+
+    sb_appendf(output, c!(".debug_info_end: \n"));
+}
+
+pub unsafe fn generate_globals_debuginfo(output: *mut String_Builder, globals: Array<Global>, os: Os) {
+    for i in 0..globals.count {
+        let global = *globals.items.add(i);
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_variable);
+        sb_appendf(output, c!(".string \"%s\"\n"), global.name);
+        sb_appendf(output, c!(".word debug_info_word_type_offset\n"));
+        sb_appendf(output, c!(".uleb128 0x9\n")); // .byte (1) + .xword (8) = 9
+        sb_appendf(output, c!(".byte %lld\n"), dwarf::OP_addr);
+        match os {
+            Os::Linux | Os::Windows => sb_appendf(output, c!(".xword %s\n"),  global.name),
+            Os::Darwin              => sb_appendf(output, c!(".xword _%s\n"), global.name)
+        };
+    }
+}
+
+pub unsafe fn generate_funcs_debuginfo(output: *mut String_Builder, funcs: Array<Func>, os: Os) {
+    for i in 0..funcs.count {
+        let func = *funcs.items.add(i);
+
+        sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_function);
+        sb_appendf(output, c!(".string \"%s\"\n"), func.name);
+        match os {
+            Os::Linux | Os::Windows => {
+                sb_appendf(output, c!(".xword %s\n"), func.name);
+                sb_appendf(output, c!(".xword .L%s_end\n"), func.name);
+            }
+            Os::Darwin => {
+                sb_appendf(output, c!(".xword _%s\n"), func.name);
+                sb_appendf(output, c!(".xword L%s_end\n"), func.name);
+            }
+        };
+        sb_appendf(output, c!(".uleb128 0x1\n")); // .byte (1) = 1
+        sb_appendf(output, c!(".byte %lld\n"), dwarf::OP_call_frame_cfa);
+
+        for j in 0..func.scope_events.count {
+            match *func.scope_events.items.add(j) {
+                ScopeEvent::Declare { name, index } => {
+                    sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_variable);
+                    sb_appendf(output, c!(".string \"%s\"\n"), name);
+                    sb_appendf(output, c!(".word debug_info_word_type_offset\n"));
+
+                    // The CFA is x29 + 16 (see the prologue in generate_function()) and the auto var is at x29 - index*8
+                    let offset = -(index as i64 + 2)*8;
+                    sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::sleb128_length(offset)+1);
+                    sb_appendf(output, c!(".byte %lld\n"), dwarf::OP_fbreg);
+                    sb_appendf(output, c!(".sleb128 %lld\n"), offset);
+                }
+                ScopeEvent::BlockBegin { index } => {
+                    sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::TEMPLATE_block);
+                    match os {
+                        Os::Linux | Os::Windows => {
+                            sb_appendf(output, c!(".xword .L%s_block_start_%zu\n"), func.name, index);
+                            sb_appendf(output, c!(".xword .L%s_block_end_%zu\n"), func.name, index);
+                        }
+                        Os::Darwin => {
+                            sb_appendf(output, c!(".xword L%s_block_start_%zu\n"), func.name, index);
+                            sb_appendf(output, c!(".xword L%s_block_end_%zu\n"), func.name, index);
+                        }
+                    };
+                }
+                ScopeEvent::BlockEnd { .. } => {
+                    sb_appendf(output, c!(".byte 0\n"));
+                }
+            }
+        }
+
+        sb_appendf(output, c!(".byte 0\n"));
     }
 }
 
@@ -500,6 +748,8 @@ pub unsafe fn usage(params: *const [Param]) {
 
 struct Gas_AArch64 {
     link_args: *const c_char,
+    annotate: bool,
+    annotator: Source_Annotator,
     output: String_Builder,
     cmd: Cmd,
 }
@@ -508,9 +758,11 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("gas-aarch64-linux"),
         file_ext: c!(""),
+        object_file_ext: c!(".o"),
+        assembly_file_ext: c!(".s"),
         new,
-        build: |gen, program, program_path, garbage_base, nostdlib, debug| {
-            generate_program(gen, program, program_path, garbage_base, Os::Linux, nostdlib, debug)
+        build: |gen, program, program_path, garbage_base, nostdlib, debug, stage, objects| {
+            generate_program(gen, program, program_path, garbage_base, Os::Linux, nostdlib, debug, stage, objects)
         },
         run: |gen, program_path, run_args| {
             run_program(gen, program_path, run_args, Os::Linux)
@@ -520,9 +772,11 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("gas-aarch64-darwin"),
         file_ext: c!(""),
+        object_file_ext: c!(".o"),
+        assembly_file_ext: c!(".s"),
         new,
-        build: |gen, program, program_path, garbage_base, nostdlib, debug| {
-            generate_program(gen, program, program_path, garbage_base, Os::Darwin, nostdlib, debug)
+        build: |gen, program, program_path, garbage_base, nostdlib, debug, stage, objects| {
+            generate_program(gen, program, program_path, garbage_base, Os::Darwin, nostdlib, debug, stage, objects)
         },
         run: |gen, program_path, run_args| {
             run_program(gen, program_path, run_args, Os::Darwin)
@@ -546,6 +800,11 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
             description: c!("Additional linker arguments"),
             value:       ParamValue::String { var: &mut (*gen).link_args, default: c!("") },
         },
+        Param {
+            name:        c!("annotate"),
+            description: c!("Annotate the generated assembly with the B source lines and the IR ops as comments"),
+            value:       ParamValue::Flag { var: &mut (*gen).annotate },
+        },
     ];
 
     if let Err(message) = parse_args(params, args) {
@@ -564,22 +823,30 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
 
 pub unsafe fn generate_program(
     gen: *mut c_void, program: *const Program, program_path: *const c_char, garbage_base: *const c_char, os: Os,
-    nostdlib: bool, debug: bool,
+    nostdlib: bool, debug: bool, stage: BuildStage, objects: *const [*const c_char],
 ) -> Option<()> {
     let gen = gen as *mut Gas_AArch64;
     let output = &mut (*gen).output;
     let cmd = &mut (*gen).cmd;
 
-    if debug { todo!("Debug information for aarch64") }
+    if debug && os == Os::Darwin {
+        // TODO: put the debug information into the `__DWARF,__debug_*` sections of Mach-O
+        log(Log_Level::ERROR, c!("Debug information is not supported on gas-aarch64-darwin yet"));
+        return None;
+    }
+    if debug { generate_debuginfo(output, (*program).funcs, (*program).globals, os); }
 
-    generate_funcs(output, da_slice((*program).funcs), da_slice((*program).variadics), os);
-    generate_asm_funcs(output, da_slice((*program).asm_funcs), os);
+    let annotator = if (*gen).annotate { &mut (*gen).annotator as *mut Source_Annotator } else { ptr::null_mut() };
+    generate_funcs(output, da_slice((*program).funcs), da_slice((*program).attributes), debug, annotator, os);
+    generate_asm_funcs(output, da_slice((*program).asm_funcs), da_slice((*program).attributes), os);
+    generate_entry_alias(da_slice((*program).attributes), output, os);
     generate_globals(output, da_slice((*program). globals), os);
     generate_data_section(output, da_slice((*program).data));
 
-    let output_asm_path = temp_sprintf(c!("%s.s"), garbage_base);
+    let output_asm_path = if stage == BuildStage::Assembly { program_path } else { temp_sprintf(c!("%s.s"), garbage_base) };
     write_entire_file(output_asm_path, (*output).items as *const c_void, (*output).count)?;
     log(Log_Level::INFO, c!("generated %s"), output_asm_path);
+    if stage == BuildStage::Assembly { return Some(()); }
 
     match os {
         Os::Linux => {
@@ -591,12 +858,13 @@ pub unsafe fn generate_program(
                 (c!("aarch64-linux-gnu-as"), c!("aarch64-linux-gnu-gcc"))
             };
 
-            let output_obj_path = temp_sprintf(c!("%s.o"), garbage_base);
+            let output_obj_path = if stage == BuildStage::Object { program_path } else { temp_sprintf(c!("%s.o"), garbage_base) };
             cmd_append! {
                 cmd,
                 gas, c!("-o"), output_obj_path, output_asm_path,
             }
             if !cmd_run_sync_and_reset(cmd) { return None; }
+            if stage == BuildStage::Object { return Some(()); }
 
             cmd_append! {
                 cmd,
@@ -607,6 +875,7 @@ pub unsafe fn generate_program(
                 },
                 c!("-o"), program_path, output_obj_path,
             }
+            da_append_many(cmd, objects);
             if nostdlib {
                 cmd_append!(cmd, c!("-nostdlib"));
             }
@@ -627,16 +896,18 @@ pub unsafe fn generate_program(
                 return None;
             }
 
-            let output_obj_path = temp_sprintf(c!("%s.o"), garbage_base);
+            let output_obj_path = if stage == BuildStage::Object { program_path } else { temp_sprintf(c!("%s.o"), garbage_base) };
             cmd_append! {
                 cmd,
                 gas, c!("-arch"), c!("arm64"), c!("-o"), output_obj_path, output_asm_path,
             }
             if !cmd_run_sync_and_reset(cmd) { return None; }
+            if stage == BuildStage::Object { return Some(()); }
             cmd_append! {
                 cmd,
                 cc, c!("-arch"), c!("arm64"), c!("-o"), program_path, output_obj_path,
             }
+            da_append_many(cmd, objects);
             if nostdlib {
                 cmd_append! {
                     cmd,
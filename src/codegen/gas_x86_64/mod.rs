@@ -1,14 +1,16 @@
 use core::ffi::*;
 use core::mem::zeroed;
 use core::cmp;
+use core::ptr;
 use crate::ir::*;
 use crate::nob::*;
-use crate::targets::{Os, TargetAPI};
+use crate::targets::{Os, TargetAPI, BuildStage};
 use crate::crust::libc::*;
 use crate::lexer::Loc;
 use crate::shlex::*;
 use crate::arena;
 use crate::params::*;
+use crate::dwarf;
 
 pub unsafe fn align_bytes(bytes: usize, alignment: usize) -> usize {
     let rem = bytes%alignment;
@@ -56,20 +58,58 @@ pub unsafe fn load_arg_to_reg(arg: Arg, reg: *const c_char,output: *mut String_B
     };
 }
 
-pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, func_index: usize, params_count: usize, auto_vars_count: usize, body: *const [OpWithLocation], scope_events: *const [ScopeEvent], debug: bool, output: *mut String_Builder, os: Os) {
-    let stack_size = align_bytes(auto_vars_count * 8, 16);
+pub unsafe fn generate_function_label(name: *const c_char, attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder, os: Os) {
+    if let Some(Attribute {value: AttributeValue::String(section), ..}) = find_attribute(attributes, name, AttributeKind::Section) {
+        sb_appendf(output, c!(".pushsection %s\n"), section);
+    }
+    let weak = find_attribute(attributes, name, AttributeKind::Weak).is_some();
     match os {
         Os::Linux | Os::Windows => {
-            sb_appendf(output, c!(".global %s\n"), name);
+            if weak {
+                sb_appendf(output, c!(".weak %s\n"), name);
+            } else {
+                sb_appendf(output, c!(".global %s\n"), name);
+            }
             sb_appendf(output, c!(".p2align 4, 0x90\n"));
             sb_appendf(output, c!("%s:\n"), name);
         }
         Os::Darwin => {
             sb_appendf(output, c!(".global _%s\n"), name);
+            if weak {
+                sb_appendf(output, c!(".weak_definition _%s\n"), name);
+            }
             sb_appendf(output, c!(".p2align 4, 0x90\n"));
             sb_appendf(output, c!("_%s:\n"), name);
         }
     }
+}
+
+pub unsafe fn generate_function_end(name: *const c_char, attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder) {
+    if find_attribute(attributes, name, AttributeKind::Section).is_some() {
+        sb_appendf(output, c!(".popsection\n"));
+    }
+}
+
+/// The C runtime always calls `main`, so if some other function is marked as the entry point
+/// `main` becomes an alias to it.
+pub unsafe fn generate_entry_alias(attributes: *const [(*const c_char, Array<Attribute>)], output: *mut String_Builder, os: Os) {
+    let Some(entry) = find_entry(attributes) else { return; };
+    if strcmp(entry, c!("main")) == 0 { return; }
+    match os {
+        Os::Linux | Os::Windows => {
+            sb_appendf(output, c!(".global main\n"));
+            sb_appendf(output, c!(".set main, %s\n"), entry);
+        }
+        Os::Darwin => {
+            sb_appendf(output, c!(".global _main\n"));
+            sb_appendf(output, c!(".set _main, _%s\n"), entry);
+        }
+    }
+}
+
+pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, func_index: usize, params_count: usize, auto_vars_count: usize, body: *const [OpWithLocation], scope_events: *const [ScopeEvent], attributes: *const [(*const c_char, Array<Attribute>)], debug: bool, annotator: *mut Source_Annotator, output: *mut String_Builder, os: Os) {
+    let stack_size = align_bytes(auto_vars_count * 8, 16);
+    generate_function_label(name, attributes, output, os);
 
     if debug {
         sb_appendf(output, c!("    .file %lld \"%s\"\n"), func_index, name_loc.input_path);
@@ -139,6 +179,10 @@ pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, func_index:
             proccessed_scope_events = op.scope_events_count;
         }
 
+        if !annotator.is_null() {
+            annotate_op(annotator, output, op, c!("#"));
+        }
+
         match op.opcode {
             Op::Bogus => unreachable!("bogus-amogus"),
             Op::Return { arg } => {
@@ -331,32 +375,23 @@ pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, func_index:
     }
 }
 
-pub unsafe fn generate_funcs(output: *mut String_Builder, funcs: *const [Func], debug: bool, os: Os) {
+pub unsafe fn generate_funcs(output: *mut String_Builder, funcs: *const [Func], attributes: *const [(*const c_char, Array<Attribute>)], debug: bool, annotator: *mut Source_Annotator, os: Os) {
     for i in 0..funcs.len() {
         let func = (*funcs)[i];
-        generate_function(func.name, func.name_loc, i, func.params_count, func.auto_vars_count, da_slice(func.body), da_slice(func.scope_events), debug, output, os);
+        generate_function(func.name, func.name_loc, i, func.params_count, func.auto_vars_count, da_slice(func.body), da_slice(func.scope_events), attributes, debug, annotator, output, os);
+        generate_function_end(func.name, attributes, output);
     }
 }
 
-pub unsafe fn generate_asm_funcs(output: *mut String_Builder, asm_funcs: *const [AsmFunc], os: Os) {
+pub unsafe fn generate_asm_funcs(output: *mut String_Builder, asm_funcs: *const [AsmFunc], attributes: *const [(*const c_char, Array<Attribute>)], os: Os) {
     for i in 0..asm_funcs.len() {
         let asm_func = (*asm_funcs)[i];
-        match os {
-            Os::Linux | Os::Windows => {
-                sb_appendf(output, c!(".global %s\n"), asm_func.name);
-                sb_appendf(output, c!(".p2align 4, 0x90\n"));
-                sb_appendf(output, c!("%s:\n"), asm_func.name);
-            }
-            Os::Darwin => {
-                sb_appendf(output, c!(".global _%s\n"), asm_func.name);
-                sb_appendf(output, c!(".p2align 4, 0x90\n"));
-                sb_appendf(output, c!("_%s:\n"), asm_func.name);
-            }
-        }
+        generate_function_label(asm_func.name, attributes, output, os);
         for j in 0..asm_func.body.count {
             let stmt = *asm_func.body.items.add(j);
             sb_appendf(output, c!("    %s\n"), stmt.line);
         }
+        generate_function_end(asm_func.name, attributes, output);
     }
 }
 
@@ -419,54 +454,6 @@ pub unsafe fn generate_data_section(output: *mut String_Builder, data: *const [u
     }
 }
 
-mod dwarf {
-    // arbitrary constants
-    pub const TEMPLATE_compilation_unit : u64 = 1;
-    pub const TEMPLATE_function         : u64 = 2;
-    pub const TEMPLATE_variable         : u64 = 3;
-    pub const TEMPLATE_block            : u64 = 4;
-    pub const TEMPLATE_type             : u64 = 5;
-
-    // other constants
-    pub const version                   : u64 = 5;
-    pub const addr_size                 : u64 = 8;
-    pub const default_type_size         : u64 = 8;
-
-    // taken from dwarf.h, DW_ prefix stripped
-    pub const CHILDREN_no               : u64 = 0;
-    pub const CHILDREN_yes              : u64 = 1;
-
-    pub const AT_location               : u64 = 0x02;
-    pub const AT_name                   : u64 = 0x03;
-    pub const AT_byte_size              : u64 = 0x0b;
-    pub const AT_stmt_list              : u64 = 0x10;
-    pub const AT_low_pc                 : u64 = 0x11;
-    pub const AT_high_pc                : u64 = 0x12;
-    pub const AT_encoding               : u64 = 0x3e;
-    pub const AT_frame_base             : u64 = 0x40;
-    pub const AT_type                   : u64 = 0x49;
-
-    pub const DW_ATE_signed             : u64 = 0x05;
-
-    pub const FORM_addr                 : u64 = 0x01;
-    pub const FORM_string               : u64 = 0x08;
-    pub const FORM_data1                : u64 = 0x0b;
-    pub const FORM_ref4                 : u64 = 0x13;
-    pub const FORM_sec_offset           : u64 = 0x17;
-    pub const FORM_exprloc              : u64 = 0x18;
-
-    pub const OP_addr                   : u64 = 0x03;
-    pub const OP_fbreg                  : u64 = 0x91;
-    pub const OP_call_frame_cfa         : u64 = 0x9c;
-
-    pub const UT_compile                : u64 = 0x01;
-    pub const TAG_lexical_block         : u64 = 0x0b;
-    pub const TAG_compile_unit          : u64 = 0x11;
-    pub const TAG_base_type             : u64 = 0x24;
-    pub const TAG_subprogram            : u64 = 0x2e;
-    pub const TAG_variable              : u64 = 0x34;
-}
-
 // TODO: all of this probably doesn't work on gas-x86_64-darwin
 pub unsafe fn generate_debuginfo(output: *mut String_Builder, funcs: Array<Func>, globals: Array<Global>, os: Os) {
     sb_appendf(output, c!(".section .debug_abbrev\n"));
@@ -571,18 +558,6 @@ pub unsafe fn generate_globals_debuginfo(output: *mut String_Builder, globals: A
     }
 }
 
-pub unsafe fn sleb128_length(mut n: i64) -> u64 {
-    if n == 0 { return 1 }
-
-    let mut len = 0;
-    n <<= 1;
-    while n != 0 && n != -1 {
-        n >>= 7;
-        len += 1;
-    }
-    len
-}
-
 pub unsafe fn generate_funcs_debuginfo(output: *mut String_Builder, funcs: Array<Func>, os: Os) {
     for i in 0..funcs.count {
         let func = *funcs.items.add(i);
@@ -605,7 +580,7 @@ pub unsafe fn generate_funcs_debuginfo(output: *mut String_Builder, funcs: Array
                     sb_appendf(output, c!(".long debug_info_word_type_offset\n"));
 
                     let offset = -(index as i64 + 2)*8;
-                    sb_appendf(output, c!(".uleb128 %lld\n"), sleb128_length(offset)+1);
+                    sb_appendf(output, c!(".uleb128 %lld\n"), dwarf::sleb128_length(offset)+1);
                     sb_appendf(output, c!(".byte %lld\n"), dwarf::OP_fbreg);
                     sb_appendf(output, c!(".sleb128 %lld\n"), offset);
                 }
@@ -632,6 +607,8 @@ pub unsafe fn usage(params: *const [Param]) {
 
 struct Gas_x86_64 {
     link_args: *const c_char,
+    annotate: bool,
+    annotator: Source_Annotator,
     output: String_Builder,
     cmd: Cmd,
 }
@@ -640,9 +617,11 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("gas-x86_64-linux"),
         file_ext: c!(""),
+        object_file_ext: c!(".o"),
+        assembly_file_ext: c!(".s"),
         new,
-        build: |gen, program, program_path, garbage_base, nostdlib, debug| {
-            generate_program(gen, program, program_path, garbage_base, Os::Linux, nostdlib, debug)
+        build: |gen, program, program_path, garbage_base, nostdlib, debug, stage, objects| {
+            generate_program(gen, program, program_path, garbage_base, Os::Linux, nostdlib, debug, stage, objects)
         },
         run: |gen, program_path, run_args| {
             run_program(gen, program_path, run_args, Os::Linux)
@@ -652,9 +631,11 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("gas-x86_64-windows"),
         file_ext: c!(".exe"),
+        object_file_ext: c!(".o"),
+        assembly_file_ext: c!(".s"),
         new,
-        build: |gen, program, program_path, garbage_base, nostdlib, debug| {
-            generate_program(gen, program, program_path, garbage_base, Os::Windows, nostdlib, debug)
+        build: |gen, program, program_path, garbage_base, nostdlib, debug, stage, objects| {
+            generate_program(gen, program, program_path, garbage_base, Os::Windows, nostdlib, debug, stage, objects)
         },
         run: |gen, program_path, run_args| {
             run_program(gen, program_path, run_args, Os::Windows)
@@ -664,9 +645,11 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("gas-x86_64-darwin"),
         file_ext: c!(""),
+        object_file_ext: c!(".o"),
+        assembly_file_ext: c!(".s"),
         new,
-        build: |gen, program, program_path, garbage_base, nostdlib, debug| {
-            generate_program(gen, program, program_path, garbage_base, Os::Darwin, nostdlib, debug)
+        build: |gen, program, program_path, garbage_base, nostdlib, debug, stage, objects| {
+            generate_program(gen, program, program_path, garbage_base, Os::Darwin, nostdlib, debug, stage, objects)
         },
         run: |gen, program_path, run_args| {
             run_program(gen, program_path, run_args, Os::Darwin)
@@ -690,6 +673,11 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
             description: c!("Additional linker arguments"),
             value:       ParamValue::String { var: &mut (*gen).link_args, default: c!("") },
         },
+        Param {
+            name:        c!("annotate"),
+            description: c!("Annotate the generated assembly with the B source lines and the IR ops as comments"),
+            value:       ParamValue::Flag { var: &mut (*gen).annotate },
+        },
     ];
 
     if let Err(message) = parse_args(params, args) {
@@ -708,7 +696,7 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
 
 pub unsafe fn generate_program(
     gen: *mut c_void, program: *const Program, program_path: *const c_char, garbage_base: *const c_char, os: Os,
-    nostdlib: bool, debug: bool,
+    nostdlib: bool, debug: bool, stage: BuildStage, objects: *const [*const c_char],
 ) -> Option<()> {
     let gen = gen as *mut Gas_x86_64;
     let output = &mut (*gen).output;
@@ -720,8 +708,10 @@ pub unsafe fn generate_program(
         Os::Darwin => sb_appendf(output, c!(".text\n")),
         Os::Linux | Os::Windows => sb_appendf(output, c!(".section .text\n")),
     };
-    generate_funcs(output, da_slice((*program).funcs), debug, os);
-    generate_asm_funcs(output, da_slice((*program).asm_funcs), os);
+    let annotator = if (*gen).annotate { &mut (*gen).annotator as *mut Source_Annotator } else { ptr::null_mut() };
+    generate_funcs(output, da_slice((*program).funcs), da_slice((*program).attributes), debug, annotator, os);
+    generate_asm_funcs(output, da_slice((*program).asm_funcs), da_slice((*program).attributes), os);
+    generate_entry_alias(da_slice((*program).attributes), output, os);
     match os {
         Os::Darwin => sb_appendf(output, c!(".data\n")),
         Os::Linux | Os::Windows => sb_appendf(output, c!(".section .data\n")),
@@ -729,9 +719,10 @@ pub unsafe fn generate_program(
     generate_data_section(output, da_slice((*program).data));
     generate_globals(output, da_slice((*program).globals), os);
 
-    let output_asm_path = temp_sprintf(c!("%s.s"), garbage_base);
+    let output_asm_path = if stage == BuildStage::Assembly { program_path } else { temp_sprintf(c!("%s.s"), garbage_base) };
     write_entire_file(output_asm_path, (*output).items as *const c_void, (*output).count)?;
     log(Log_Level::INFO, c!("generated %s"), output_asm_path);
+    if stage == BuildStage::Assembly { return Some(()); }
 
     match os {
         Os::Darwin => {
@@ -743,17 +734,19 @@ pub unsafe fn generate_program(
 
             let (gas, cc) = (c!("as"), c!("cc"));
 
-            let output_obj_path = temp_sprintf(c!("%s.o"), program_path);
+            let output_obj_path = if stage == BuildStage::Object { program_path } else { temp_sprintf(c!("%s.o"), garbage_base) };
             cmd_append! {
                 cmd,
                 gas, c!("-arch"), c!("x86_64"), c!("-o"), output_obj_path, output_asm_path,
             }
             if !cmd_run_sync_and_reset(cmd) { return None; }
+            if stage == BuildStage::Object { return Some(()); }
 
             cmd_append! {
                 cmd,
                 cc, c!("-arch"), c!("x86_64"), c!("-o"), program_path, output_obj_path,
             }
+            da_append_many(cmd, objects);
             if nostdlib {
                 cmd_append!(cmd, c!("-nostdlib"));
             }
@@ -773,17 +766,19 @@ pub unsafe fn generate_program(
                 return None;
             }
 
-            let output_obj_path = temp_sprintf(c!("%s.o"), garbage_base);
+            let output_obj_path = if stage == BuildStage::Object { program_path } else { temp_sprintf(c!("%s.o"), garbage_base) };
             cmd_append! {
                 cmd,
                 c!("as"), output_asm_path, c!("-o"), output_obj_path,
             }
             if !cmd_run_sync_and_reset(cmd) { return None; }
+            if stage == BuildStage::Object { return Some(()); }
 
             cmd_append! {
                 cmd,
                 c!("cc"), c!("-no-pie"), c!("-o"), program_path, output_obj_path,
             }
+            da_append_many(cmd, objects);
             if nostdlib {
                 cmd_append!(cmd, c!("-nostdlib"));
             }
@@ -797,17 +792,19 @@ pub unsafe fn generate_program(
             if !cmd_run_sync_and_reset(cmd) { return None; }
         }
         Os::Windows => {
-            let output_obj_path = temp_sprintf(c!("%s.o"), garbage_base);
+            let output_obj_path = if stage == BuildStage::Object { program_path } else { temp_sprintf(c!("%s.o"), garbage_base) };
             cmd_append! {
                 cmd,
                 c!("as"), output_asm_path, c!("-o"), output_obj_path,
             }
             if !cmd_run_sync_and_reset(cmd) { return None; }
+            if stage == BuildStage::Object { return Some(()); }
 
             cmd_append! {
                 cmd,
                 c!("x86_64-w64-mingw32-gcc"), c!("-no-pie"), c!("-o"), program_path, output_obj_path,
             }
+            da_append_many(cmd, objects);
             if nostdlib {
                 cmd_append!(cmd, c!("-nostdlib"));
             }
@@ -0,0 +1,554 @@
+//! Assembler of the 6502 inline assembly (`__asm__` statements and naked functions).
+//!
+//! Every string of an `__asm__` block is a statement:
+//!
+//! ```text
+//! [label:] [MNEMONIC [operand]] [; comment]
+//! [label:] [.byte expr, ...] | [.word expr, ...]
+//! ```
+//!
+//! The operands use the usual 6502 syntax: `#imm`, `zp`, `zp,X`, `zp,Y`, `abs`, `abs,X`, `abs,Y`, `(zp,X)`,
+//! `(zp),Y`, `(abs)` and `A`. The zero page modes are picked when the value is known to fit into a byte.
+//! The expressions are sums of terms: numbers (`123`, `$7B`, `%1111011`, `'{'`), the address of the current
+//! instruction `*`, the names of the B functions, globals and the global labels, the local labels and sums in
+//! parentheses. A leading `<` or `>` takes the low or the high byte of the expression, so `#<(name-1)` is the low
+//! byte of `name-1`. The parentheses right at the start of an operand still mean the indirect modes.
+//!
+//! `name:` defines a global label, which is visible everywhere like a B function. `@name:` defines a local label,
+//! which is visible only within its `__asm__` block, so the same names can be reused in the other blocks.
+//! Both can be referenced before they are defined.
+
+use core::ffi::*;
+use core::mem::zeroed;
+use crate::lexer::*;
+use crate::ir::*;
+use crate::nob::*;
+use crate::diagf;
+use crate::crust::libc::*;
+use crate::arena;
+use super::*;
+
+#[derive(Clone, Copy)]
+pub enum Value {
+    Const(i64),
+    Symbol {
+        name: *const c_char,
+        local: bool,
+        offset: i64,
+        byte: Byte,
+    },
+}
+
+impl Value {
+    /// Whether the value is known to fit into a single byte
+    fn is8(self) -> bool {
+        match self {
+            Value::Const(value) => 0 <= value && value <= 0xFF,
+            Value::Symbol{byte, ..} => byte != Byte::Both,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Local_Label {
+    pub name: *const c_char,
+    pub addr: u16,
+    pub loc: Loc,
+}
+
+/// A reference to a local label to be patched at the end of the block
+#[derive(Clone, Copy)]
+pub struct Local_Fixup {
+    pub name: *const c_char,
+    pub offset: i64,
+    pub byte: Byte,
+    pub relative: bool,
+    pub addr: u16,
+    pub loc: Loc,
+}
+
+#[derive(Clone, Copy)]
+pub struct Block {
+    pub labels: Array<Local_Label>,
+    pub fixups: Array<Local_Fixup>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Statement {
+    pub begin: *const c_char,
+    pub cur: *const c_char,
+    pub loc: Loc,
+    /// Address of the current instruction relative to code_start
+    pub addr: u16,
+}
+
+#[derive(Clone, Copy)]
+pub enum Operand {
+    None,
+    Acc,
+    Imm(Value),
+    Ind(Value),
+    IndX(Value),
+    IndY(Value),
+    Direct(Value, Option<u8>), // with optional index register b'X' or b'Y'
+}
+
+unsafe fn loc_here(s: *const Statement) -> Loc {
+    let mut loc = (*s).loc;
+    loc.line_offset += ((*s).cur as isize - (*s).begin as isize + 1) as i32;
+    loc
+}
+
+unsafe fn peek(s: *const Statement) -> u8 {
+    *(*s).cur as u8
+}
+
+unsafe fn skip_spaces(s: *mut Statement) {
+    while isspace(*(*s).cur as c_int) != 0 {
+        (*s).cur = (*s).cur.add(1);
+    }
+}
+
+unsafe fn at_end(s: *mut Statement) -> bool {
+    skip_spaces(s);
+    peek(s) == 0 || peek(s) == b';'
+}
+
+/// Skips the spaces and the `c`, if it is next
+unsafe fn skip_char(s: *mut Statement, c: u8) -> bool {
+    skip_spaces(s);
+    if toupper(peek(s) as c_int) as u8 == c {
+        (*s).cur = (*s).cur.add(1);
+        true
+    } else {
+        false
+    }
+}
+
+unsafe fn expect_char(s: *mut Statement, c: u8) -> Option<()> {
+    if skip_char(s, c) {
+        return Some(());
+    }
+    diagf!(loc_here(s), c!("ERROR: expected `%c`\n"), c as c_int);
+    None
+}
+
+/// Whether the identifier at the current position is exactly `name` (case insensitive)
+unsafe fn is_word(s: *mut Statement, name: u8) -> bool {
+    skip_spaces(s);
+    toupper(peek(s) as c_int) as u8 == name && !is_identifier(*(*s).cur.add(1))
+}
+
+unsafe fn parse_name(s: *mut Statement, asm: *mut Assembler) -> *const c_char {
+    let start = (*s).cur;
+    while is_identifier(*(*s).cur) {
+        (*s).cur = (*s).cur.add(1);
+    }
+    arena::sprintf(&mut (*asm).string_arena, c!("%.*s"), (*s).cur as usize - start as usize, start)
+}
+
+unsafe fn parse_number(s: *mut Statement) -> Option<i64> {
+    let loc = loc_here(s);
+    let base = match peek(s) {
+        b'$' => { (*s).cur = (*s).cur.add(1); 16 }
+        b'%' => { (*s).cur = (*s).cur.add(1); 2 }
+        _ => 10,
+    };
+    if isalnum(peek(s) as c_int) == 0 {
+        diagf!(loc, c!("ERROR: expected a number\n"));
+        return None;
+    }
+    let mut end = ptr::null_mut();
+    let value = strtoull((*s).cur, &mut end, base);
+    (*s).cur = end;
+    if is_identifier(*(*s).cur) {
+        diagf!(loc_here(s), c!("ERROR: unexpected character `%c` in numeric literal\n"), peek(s) as c_int);
+        return None;
+    }
+    if value > 0xFFFF {
+        diagf!(loc, c!("ERROR: constant $%llX out of range for 16 bits\n"), value);
+        return None;
+    }
+    Some(value as i64)
+}
+
+unsafe fn parse_term(s: *mut Statement, asm: *mut Assembler) -> Option<Value> {
+    skip_spaces(s);
+    let loc = loc_here(s);
+    match peek(s) {
+        b'-' => {
+            (*s).cur = (*s).cur.add(1);
+            match parse_term(s, asm)? {
+                Value::Const(value) => Some(Value::Const(-value)),
+                Value::Symbol{..} => {
+                    diagf!(loc, c!("ERROR: cannot negate an address\n"));
+                    None
+                }
+            }
+        }
+        b'*' => {
+            (*s).cur = (*s).cur.add(1);
+            Some(Value::Const(((*asm).code_start + (*s).addr) as i64))
+        }
+        b'(' => {
+            (*s).cur = (*s).cur.add(1);
+            let value = parse_sum(s, asm)?;
+            expect_char(s, b')')?;
+            Some(value)
+        }
+        b'\'' => {
+            let value = *(*s).cur.add(1) as u8;
+            if value == 0 || *(*s).cur.add(2) as u8 != b'\'' {
+                diagf!(loc, c!("ERROR: invalid character literal\n"));
+                return None;
+            }
+            (*s).cur = (*s).cur.add(3);
+            Some(Value::Const(value as i64))
+        }
+        b'@' => {
+            (*s).cur = (*s).cur.add(1);
+            if !is_identifier_start(*(*s).cur) {
+                diagf!(loc, c!("ERROR: expected a name of the local label after `@`\n"));
+                return None;
+            }
+            Some(Value::Symbol{name: parse_name(s, asm), local: true, offset: 0, byte: Byte::Both})
+        }
+        c if is_identifier_start(c as c_char) => {
+            Some(Value::Symbol{name: parse_name(s, asm), local: false, offset: 0, byte: Byte::Both})
+        }
+        _ => Some(Value::Const(parse_number(s)?)),
+    }
+}
+
+unsafe fn parse_sum(s: *mut Statement, asm: *mut Assembler) -> Option<Value> {
+    let mut value = parse_term(s, asm)?;
+    loop {
+        skip_spaces(s);
+        let sign = match peek(s) {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return Some(value),
+        };
+        (*s).cur = (*s).cur.add(1);
+        let loc = loc_here(s);
+        let term = parse_term(s, asm)?;
+        value = match (value, term) {
+            (Value::Const(a), Value::Const(b)) => Value::Const(a + sign*b),
+            (Value::Symbol{name, local, offset, byte}, Value::Const(b)) => Value::Symbol{name, local, offset: offset + sign*b, byte},
+            (Value::Const(a), Value::Symbol{name, local, offset, byte}) if sign > 0 => Value::Symbol{name, local, offset: offset + a, byte},
+            _ => {
+                diagf!(loc, c!("ERROR: only a constant can be added to or subtracted from an address\n"));
+                return None;
+            }
+        };
+    }
+}
+
+unsafe fn parse_expr(s: *mut Statement, asm: *mut Assembler) -> Option<Value> {
+    skip_spaces(s);
+    let byte = match peek(s) {
+        b'<' => Byte::Low,
+        b'>' => Byte::High,
+        _ => return parse_sum(s, asm),
+    };
+    (*s).cur = (*s).cur.add(1);
+    match parse_sum(s, asm)? {
+        Value::Const(value) => Some(Value::Const(if byte == Byte::Low { value & 0xFF } else { (value >> 8) & 0xFF })),
+        Value::Symbol{name, local, offset, ..} => Some(Value::Symbol{name, local, offset, byte}),
+    }
+}
+
+unsafe fn parse_operand(s: *mut Statement, asm: *mut Assembler) -> Option<Operand> {
+    if at_end(s) {
+        return Some(Operand::None);
+    }
+    if is_word(s, b'A') {
+        (*s).cur = (*s).cur.add(1);
+        return Some(Operand::Acc);
+    }
+    if skip_char(s, b'#') {
+        return Some(Operand::Imm(parse_expr(s, asm)?));
+    }
+    if skip_char(s, b'(') {
+        let value = parse_expr(s, asm)?;
+        if skip_char(s, b',') {
+            expect_char(s, b'X')?;
+            expect_char(s, b')')?;
+            return Some(Operand::IndX(value));
+        }
+        expect_char(s, b')')?;
+        if skip_char(s, b',') {
+            expect_char(s, b'Y')?;
+            return Some(Operand::IndY(value));
+        }
+        return Some(Operand::Ind(value));
+    }
+    let value = parse_expr(s, asm)?;
+    if skip_char(s, b',') {
+        if skip_char(s, b'X') {
+            return Some(Operand::Direct(value, Some(b'X')));
+        }
+        expect_char(s, b'Y')?;
+        return Some(Operand::Direct(value, Some(b'Y')));
+    }
+    Some(Operand::Direct(value, None))
+}
+
+unsafe fn add_local_fixup(out: *mut String_Builder, block: *mut Block, name: *const c_char, offset: i64, byte: Byte, relative: bool, loc: Loc) {
+    da_append(&mut (*block).fixups, Local_Fixup {
+        name, offset, byte, relative, loc,
+        addr: (*out).count as u16,
+    });
+    if byte == Byte::Both && !relative {
+        write_word(out, 0);
+    } else {
+        write_byte(out, 0);
+    }
+}
+
+unsafe fn emit_value8(out: *mut String_Builder, value: Value, loc: Loc, block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    match value {
+        Value::Const(value) => {
+            if value < -128 || value > 0xFF {
+                diagf!(loc, c!("ERROR: constant %lld out of range for 8 bits\n"), value);
+                return None;
+            }
+            write_byte(out, value as u8);
+        }
+        Value::Symbol{name, byte: Byte::Both, ..} => {
+            diagf!(loc, c!("ERROR: 16-bit address of `%s` used as an 8-bit value. Take its `<` low or `>` high byte\n"), name);
+            return None;
+        }
+        Value::Symbol{name, local: true, offset, byte} => add_local_fixup(out, block, name, offset, byte, false, loc),
+        Value::Symbol{name, local: false, offset, byte} => {
+            add_reloc(out, RelocationKind::External{name, offset: offset as usize, byte, relative: false}, asm);
+        }
+    }
+    Some(())
+}
+
+unsafe fn emit_value16(out: *mut String_Builder, value: Value, loc: Loc, block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    match value {
+        Value::Const(value) => {
+            if value < -0x8000 || value > 0xFFFF {
+                diagf!(loc, c!("ERROR: constant %lld out of range for 16 bits\n"), value);
+                return None;
+            }
+            write_word(out, value as u16);
+        }
+        Value::Symbol{byte: Byte::Low | Byte::High, ..} => {
+            emit_value8(out, value, loc, block, asm)?;
+            write_byte(out, 0);
+        }
+        Value::Symbol{name, local: true, offset, byte} => add_local_fixup(out, block, name, offset, byte, false, loc),
+        Value::Symbol{name, local: false, offset, byte} => {
+            add_reloc(out, RelocationKind::External{name, offset: offset as usize, byte, relative: false}, asm);
+        }
+    }
+    Some(())
+}
+
+unsafe fn emit_relative(out: *mut String_Builder, value: Value, loc: Loc, block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    match value {
+        Value::Const(target) => {
+            let rel = target - ((*asm).code_start as i64 + (*out).count as i64 + 1);
+            if rel < -128 || rel > 127 {
+                diagf!(loc, c!("ERROR: branch target is too far away (%lld bytes)\n"), rel);
+                return None;
+            }
+            write_byte(out, rel as u8);
+        }
+        Value::Symbol{name, byte: Byte::Low | Byte::High, ..} => {
+            diagf!(loc, c!("ERROR: a byte of the address of `%s` used as a branch target\n"), name);
+            return None;
+        }
+        Value::Symbol{name, local: true, offset, byte} => add_local_fixup(out, block, name, offset, byte, true, loc),
+        Value::Symbol{name, local: false, offset, byte} => {
+            add_reloc(out, RelocationKind::External{name, offset: offset as usize, byte, relative: true}, asm);
+        }
+    }
+    Some(())
+}
+
+unsafe fn assemble_instruction(out: *mut String_Builder, s: *mut Statement, name: *const c_char, loc: Loc,
+                               block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    let upper = arena::sprintf(&mut (*asm).string_arena, c!("%s"), name);
+    for i in 0..strlen(upper) {
+        *upper.add(i) = toupper(*upper.add(i) as c_int) as c_char;
+    }
+    let Some(instr) = instr_from_string(upper) else {
+        diagf!(loc, c!("ERROR: invalid instruction mnemonic `%s`\n"), name);
+        return None;
+    };
+    let has = |mode: AddrMode| OPCODES[instr as usize][mode as usize] != INVL;
+
+    let operand_loc = { skip_spaces(s); loc_here(s) };
+    let operand = parse_operand(s, asm)?;
+    let (mode, value) = match operand {
+        Operand::None  => (if has(IMPL) { IMPL } else { ACC }, None),
+        Operand::Acc   => (ACC, None),
+        Operand::Imm(value)  => (IMM, Some(value)),
+        Operand::Ind(value)  => (IND, Some(value)),
+        Operand::IndX(value) => (IND_X, Some(value)),
+        Operand::IndY(value) => (IND_Y, Some(value)),
+        Operand::Direct(value, index) => {
+            let (zp, abs) = match index {
+                None       => (ZP, ABS),
+                Some(b'X') => (ZP_X, ABS_X),
+                _          => (ZP_Y, ABS_Y),
+            };
+            // prefer zeropage instructions, if they exist
+            if index.is_none() && has(REL) {
+                (REL, Some(value))
+            } else if value.is8() && has(zp) || !has(abs) {
+                (zp, Some(value))
+            } else {
+                (abs, Some(value))
+            }
+        }
+    };
+
+    if !has(mode) {
+        diagf!(operand_loc, c!("ERROR: invalid addressing mode of instruction `%s`\n"), name);
+        return None;
+    }
+    write_byte(out, OPCODES[instr as usize][mode as usize]);
+    if let Some(value) = value {
+        match mode {
+            REL => emit_relative(out, value, operand_loc, block, asm)?,
+            ABS | ABS_X | ABS_Y | IND => emit_value16(out, value, operand_loc, block, asm)?,
+            IMM => emit_value8(out, value, operand_loc, block, asm)?,
+            _ => {
+                if let Value::Symbol{name, byte: Byte::Both, ..} = value {
+                    diagf!(operand_loc, c!("ERROR: 16-bit address of `%s` does not fit into the zero page\n"), name);
+                    return None;
+                }
+                emit_value8(out, value, operand_loc, block, asm)?
+            }
+        }
+    }
+    Some(())
+}
+
+unsafe fn assemble_directive(out: *mut String_Builder, s: *mut Statement, name: *const c_char, loc: Loc,
+                             block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    let word = if strcmp(name, c!("byte")) == 0 {
+        false
+    } else if strcmp(name, c!("word")) == 0 {
+        true
+    } else {
+        diagf!(loc, c!("ERROR: unknown directive `.%s`\n"), name);
+        return None;
+    };
+    loop {
+        skip_spaces(s);
+        let value_loc = loc_here(s);
+        let value = parse_expr(s, asm)?;
+        if word {
+            emit_value16(out, value, value_loc, block, asm)?;
+        } else {
+            emit_value8(out, value, value_loc, block, asm)?;
+        }
+        if !skip_char(s, b',') { break; }
+    }
+    Some(())
+}
+
+pub unsafe fn assemble_statement(out: *mut String_Builder, stmt: AsmStmt, block: *mut Block, asm: *mut Assembler) -> Option<()> {
+    let mut s = Statement {
+        begin: stmt.line,
+        cur: stmt.line,
+        loc: stmt.loc,
+        addr: (*out).count as u16,
+    };
+    let s = &mut s;
+
+    if at_end(s) { return Some(()); }
+
+    let loc = loc_here(s);
+    let local = skip_char(s, b'@');
+    let directive = !local && skip_char(s, b'.');
+    if !is_identifier_start(*s.cur) {
+        diagf!(loc_here(s), c!("ERROR: expected an instruction mnemonic, a directive or a label\n"));
+        return None;
+    }
+    let name = parse_name(s, asm);
+
+    if !directive && skip_char(s, b':') {
+        if local {
+            for i in 0..(*block).labels.count {
+                let label = *(*block).labels.items.add(i);
+                if strcmp(label.name, name) == 0 {
+                    diagf!(loc,       c!("ERROR: redefinition of local label `@%s`\n"), name);
+                    diagf!(label.loc, c!("INFO: previously defined here\n"));
+                    return None;
+                }
+            }
+            da_append(&mut (*block).labels, Local_Label {name, addr: (*out).count as u16, loc});
+        } else {
+            add_external(name, (*out).count as u16, loc, asm)?;
+        }
+        // the label may be followed by an instruction
+        let mut rest_loc = s.loc;
+        rest_loc.line_offset += (s.cur as isize - s.begin as isize) as i32;
+        return assemble_statement(out, AsmStmt { line: s.cur, loc: rest_loc }, block, asm);
+    }
+    if local {
+        diagf!(loc, c!("ERROR: expected `:` after local label `@%s`\n"), name);
+        return None;
+    }
+
+    if directive {
+        assemble_directive(out, s, name, loc, block, asm)?;
+    } else {
+        assemble_instruction(out, s, name, loc, block, asm)?;
+    }
+
+    if !at_end(s) {
+        diagf!(loc_here(s), c!("ERROR: trailing garbage: `%s`\n"), s.cur);
+        return None;
+    }
+    Some(())
+}
+
+/// Assembles the statements of a single `__asm__` block, which is the scope of its local labels
+pub unsafe fn assemble_block(out: *mut String_Builder, stmts: *const [AsmStmt], asm: *mut Assembler) -> Option<()> {
+    let mut block: Block = zeroed();
+    let mut result = Some(());
+    for i in 0..stmts.len() {
+        if assemble_statement(out, (*stmts)[i], &mut block, asm).is_none() {
+            result = None;
+        }
+    }
+
+    'fixups: for i in 0..block.fixups.count {
+        let fixup = *block.fixups.items.add(i);
+        for j in 0..block.labels.count {
+            let label = *block.labels.items.add(j);
+            if strcmp(label.name, fixup.name) != 0 { continue; }
+
+            let target = ((*asm).code_start as i64 + label.addr as i64 + fixup.offset) as u16;
+            if fixup.relative {
+                let rel = target as i64 - ((*asm).code_start as i64 + fixup.addr as i64 + 1);
+                if rel < -128 || rel > 127 {
+                    diagf!(fixup.loc, c!("ERROR: local label `@%s` is too far away for a branch (%lld bytes)\n"), fixup.name, rel);
+                    result = None;
+                }
+                write_byte_at(out, rel as u8, fixup.addr);
+            } else {
+                match fixup.byte {
+                    Byte::Low  => write_byte_at(out, target as u8, fixup.addr),
+                    Byte::High => write_byte_at(out, (target >> 8) as u8, fixup.addr),
+                    Byte::Both => write_word_at(out, target, fixup.addr),
+                }
+            }
+            continue 'fixups;
+        }
+        diagf!(fixup.loc, c!("ERROR: unknown local label `@%s`\n"), fixup.name);
+        result = None;
+    }
+
+    free(block.labels.items);
+    free(block.fixups.items);
+    result
+}
@@ -0,0 +1,424 @@
+//! 6502 emulator implementing the posix6502 ABI.
+//!
+//! It is the `builtin` runner of the 6502-posix target, so the programs (and the tests in particular) can be run
+//! without the posix6502 executable. The image is loaded at LOAD_OFFSET and started from its first byte. The
+//! "syscalls" of the program are JSRs to the trap addresses:
+//!
+//! - `$0000` - exit with the code in A. `JMP ($FFFC)` at the end of the entry lands here too, since the vector is zero;
+//! - `$FFEF` - write the character in A to stdout (ECHO of Wozmon).
+//!
+//! The emulator counts the cycles of the executed instructions (NMOS timings, including the page crossing
+//! penalties) and can print every executed instruction to stderr.
+//!
+//! See http://www.6502.org/tutorials/6502opcodes.html
+
+use core::ffi::*;
+use core::mem::zeroed;
+use core::ptr;
+use crate::nob::*;
+use crate::crust::libc::*;
+use super::*;
+
+const EXIT_ADDR: u16 = 0x0000;
+const PUTCHAR_ADDR: u16 = 0xFFEF;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+const FLAG_C: u8 = 0x01;
+const FLAG_Z: u8 = 0x02;
+const FLAG_I: u8 = 0x04;
+const FLAG_D: u8 = 0x08;
+const FLAG_B: u8 = 0x10;
+const FLAG_U: u8 = 0x20;
+const FLAG_V: u8 = 0x40;
+const FLAG_N: u8 = 0x80;
+
+#[derive(Clone, Copy)]
+pub struct Cpu {
+    pub mem: [u8; 0x10000],
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+    pub cycles: u64,
+    /// The 6502 stack silently wraps around the page, but for a B program it is always a bug
+    pub stack_error: *const c_char,
+    /// Instruction and addressing mode of every opcode, the reverse of the OPCODES table
+    pub decode: [Option<(Instr, AddrMode)>; 0x100],
+}
+
+pub unsafe fn read16(c: *mut Cpu, addr: u16) -> u16 {
+    (*c).mem[addr as usize] as u16 | ((*c).mem[addr.wrapping_add(1) as usize] as u16) << 8
+}
+
+pub unsafe fn push(c: *mut Cpu, value: u8) {
+    if (*c).sp == 0x00 { (*c).stack_error = c!("overflow"); }
+    (*c).mem[STACK_PAGE as usize + (*c).sp as usize] = value;
+    (*c).sp = (*c).sp.wrapping_sub(1);
+}
+
+pub unsafe fn pull(c: *mut Cpu) -> u8 {
+    if (*c).sp == 0xFF { (*c).stack_error = c!("underflow"); }
+    (*c).sp = (*c).sp.wrapping_add(1);
+    (*c).mem[STACK_PAGE as usize + (*c).sp as usize]
+}
+
+pub unsafe fn set_flag(c: *mut Cpu, flag: u8, on: bool) {
+    if on {
+        (*c).p |= flag;
+    } else {
+        (*c).p &= !flag;
+    }
+}
+
+pub unsafe fn set_zn(c: *mut Cpu, value: u8) -> u8 {
+    set_flag(c, FLAG_Z, value == 0);
+    set_flag(c, FLAG_N, value & 0x80 != 0);
+    value
+}
+
+pub fn operand_size(mode: AddrMode) -> u16 {
+    match mode {
+        IMPL | ACC => 0,
+        IMM | ZP | ZP_X | ZP_Y | IND_X | IND_Y | REL => 1,
+        ABS | ABS_X | ABS_Y | IND => 2,
+        AddrMode::COUNT => unreachable!(),
+    }
+}
+
+/// Cycles of the instruction without the penalties for the page crossing and the taken branches
+pub fn base_cycles(instr: Instr, mode: AddrMode) -> u64 {
+    match instr {
+        STA | STX | STY => match mode {
+            ZP => 3,
+            ZP_X | ZP_Y | ABS => 4,
+            ABS_X | ABS_Y => 5,
+            _ => 6,
+        },
+        ASL | LSR | ROL | ROR | INC | DEC => match mode {
+            ACC => 2,
+            ZP => 5,
+            ZP_X | ABS => 6,
+            _ => 7,
+        },
+        JMP => if mode == IND { 5 } else { 3 },
+        JSR | RTS | RTI => 6,
+        BRK => 7,
+        PHA | PHP => 3,
+        PLA | PLP => 4,
+        _ => match mode {
+            IMM | IMPL | REL => 2,
+            ZP => 3,
+            ZP_X | ZP_Y | ABS | ABS_X | ABS_Y => 4,
+            IND_Y => 5,
+            _ => 6,
+        },
+    }
+}
+
+/// Returns the effective address of the operand and whether computing it crossed a page
+pub unsafe fn operand_addr(c: *mut Cpu, mode: AddrMode) -> (u16, bool) {
+    let arg = (*c).pc.wrapping_add(1);
+    let zp = (*c).mem[arg as usize];
+    match mode {
+        IMM => (arg, false),
+        ZP => (zp as u16, false),
+        ZP_X => (zp.wrapping_add((*c).x) as u16, false),
+        ZP_Y => (zp.wrapping_add((*c).y) as u16, false),
+        ABS => (read16(c, arg), false),
+        ABS_X | ABS_Y => {
+            let base = read16(c, arg);
+            let addr = base.wrapping_add(if mode == ABS_X { (*c).x } else { (*c).y } as u16);
+            (addr, base & 0xFF00 != addr & 0xFF00)
+        }
+        IND_X => {
+            let zp = zp.wrapping_add((*c).x);
+            ((*c).mem[zp as usize] as u16 | ((*c).mem[zp.wrapping_add(1) as usize] as u16) << 8, false)
+        }
+        IND_Y => {
+            let base = (*c).mem[zp as usize] as u16 | ((*c).mem[zp.wrapping_add(1) as usize] as u16) << 8;
+            let addr = base.wrapping_add((*c).y as u16);
+            (addr, base & 0xFF00 != addr & 0xFF00)
+        }
+        IND => {
+            // The famous bug of NMOS 6502: the pointer never crosses the page
+            let ptr = read16(c, arg);
+            let high = (ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF);
+            ((*c).mem[ptr as usize] as u16 | ((*c).mem[high as usize] as u16) << 8, false)
+        }
+        REL => ((*c).pc.wrapping_add(2).wrapping_add(zp as i8 as u16), false),
+        IMPL | ACC => (0, false),
+        AddrMode::COUNT => unreachable!(),
+    }
+}
+
+pub unsafe fn adc(c: *mut Cpu, value: u8) {
+    let a = (*c).a;
+    let carry = ((*c).p & FLAG_C) as u16;
+    let sum = a as u16 + value as u16 + carry;
+    set_flag(c, FLAG_V, (!(a ^ value) & (a ^ sum as u8)) & 0x80 != 0);
+    if (*c).p & FLAG_D != 0 {
+        let mut low = (a & 0x0F) as u16 + (value & 0x0F) as u16 + carry;
+        let mut high = (a >> 4) as u16 + (value >> 4) as u16;
+        if low > 9 {
+            low += 6;
+            high += 1;
+        }
+        if high > 9 {
+            high += 6;
+        }
+        set_flag(c, FLAG_C, high > 0x0F);
+        (*c).a = set_zn(c, ((high << 4) | (low & 0x0F)) as u8);
+    } else {
+        set_flag(c, FLAG_C, sum > 0xFF);
+        (*c).a = set_zn(c, sum as u8);
+    }
+}
+
+pub unsafe fn sbc(c: *mut Cpu, value: u8) {
+    if (*c).p & FLAG_D != 0 {
+        let a = (*c).a;
+        let borrow = 1 - ((*c).p & FLAG_C) as i16;
+        let diff = a as i16 - value as i16 - borrow;
+        set_flag(c, FLAG_V, ((a ^ value) & (a ^ diff as u8)) & 0x80 != 0);
+        let mut low = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+        let mut high = (a >> 4) as i16 - (value >> 4) as i16;
+        if low < 0 {
+            low += 10;
+            high -= 1;
+        }
+        set_flag(c, FLAG_C, high >= 0);
+        if high < 0 {
+            high += 10;
+        }
+        (*c).a = set_zn(c, ((high << 4) | (low & 0x0F)) as u8);
+    } else {
+        // a - b - !c = a + !b + c
+        adc(c, !value);
+    }
+}
+
+pub unsafe fn compare(c: *mut Cpu, reg: u8, value: u8) {
+    set_flag(c, FLAG_C, reg >= value);
+    set_zn(c, reg.wrapping_sub(value));
+}
+
+/// Executes a single instruction. Returns None on an illegal opcode or a stack overflow.
+pub unsafe fn step(c: *mut Cpu) -> Option<()> {
+    let opcode = (*c).mem[(*c).pc as usize];
+    let Some((instr, mode)) = (*c).decode[opcode as usize] else {
+        log(Log_Level::ERROR, c!("6502: illegal opcode $%02X at $%04X"), opcode as c_uint, (*c).pc as c_uint);
+        return None;
+    };
+    let start = (*c).pc;
+    let (addr, page_crossed) = operand_addr(c, mode);
+    let next = (*c).pc.wrapping_add(1 + operand_size(mode));
+    (*c).cycles += base_cycles(instr, mode);
+    (*c).pc = next;
+
+    let mem = &mut (*c).mem as *mut [u8; 0x10000];
+    let read = || (*mem)[addr as usize];
+    // Only the instructions that merely read the operand pay for the page crossing
+    let read_penalty = |c: *mut Cpu| if page_crossed { (*c).cycles += 1; };
+    let branch = |c: *mut Cpu, taken: bool| if taken {
+        (*c).cycles += if next & 0xFF00 != addr & 0xFF00 { 2 } else { 1 };
+        (*c).pc = addr;
+    };
+    let modify = |c: *mut Cpu, f: &dyn Fn(*mut Cpu, u8) -> u8| if mode == ACC {
+        (*c).a = f(c, (*c).a);
+    } else {
+        (*mem)[addr as usize] = f(c, (*mem)[addr as usize]);
+    };
+
+    match instr {
+        LDA => { read_penalty(c); (*c).a = set_zn(c, read()); }
+        LDX => { read_penalty(c); (*c).x = set_zn(c, read()); }
+        LDY => { read_penalty(c); (*c).y = set_zn(c, read()); }
+        STA => (*mem)[addr as usize] = (*c).a,
+        STX => (*mem)[addr as usize] = (*c).x,
+        STY => (*mem)[addr as usize] = (*c).y,
+        ADC => { read_penalty(c); adc(c, read()); }
+        SBC => { read_penalty(c); sbc(c, read()); }
+        AND => { read_penalty(c); (*c).a = set_zn(c, (*c).a & read()); }
+        ORA => { read_penalty(c); (*c).a = set_zn(c, (*c).a | read()); }
+        EOR => { read_penalty(c); (*c).a = set_zn(c, (*c).a ^ read()); }
+        CMP => { read_penalty(c); compare(c, (*c).a, read()); }
+        CPX => compare(c, (*c).x, read()),
+        CPY => compare(c, (*c).y, read()),
+        BIT => {
+            let value = read();
+            set_flag(c, FLAG_Z, (*c).a & value == 0);
+            set_flag(c, FLAG_V, value & 0x40 != 0);
+            set_flag(c, FLAG_N, value & 0x80 != 0);
+        }
+        ASL => modify(c, &|c, v| { set_flag(c, FLAG_C, v & 0x80 != 0); set_zn(c, v << 1) }),
+        LSR => modify(c, &|c, v| { set_flag(c, FLAG_C, v & 0x01 != 0); set_zn(c, v >> 1) }),
+        ROL => modify(c, &|c, v| {
+            let carry = (*c).p & FLAG_C;
+            set_flag(c, FLAG_C, v & 0x80 != 0);
+            set_zn(c, (v << 1) | carry)
+        }),
+        ROR => modify(c, &|c, v| {
+            let carry = (*c).p & FLAG_C;
+            set_flag(c, FLAG_C, v & 0x01 != 0);
+            set_zn(c, (v >> 1) | (carry << 7))
+        }),
+        INC => modify(c, &|c, v| set_zn(c, v.wrapping_add(1))),
+        DEC => modify(c, &|c, v| set_zn(c, v.wrapping_sub(1))),
+        INX => (*c).x = set_zn(c, (*c).x.wrapping_add(1)),
+        INY => (*c).y = set_zn(c, (*c).y.wrapping_add(1)),
+        DEX => (*c).x = set_zn(c, (*c).x.wrapping_sub(1)),
+        DEY => (*c).y = set_zn(c, (*c).y.wrapping_sub(1)),
+        TAX => (*c).x = set_zn(c, (*c).a),
+        TAY => (*c).y = set_zn(c, (*c).a),
+        TXA => (*c).a = set_zn(c, (*c).x),
+        TYA => (*c).a = set_zn(c, (*c).y),
+        TSX => (*c).x = set_zn(c, (*c).sp),
+        TXS => (*c).sp = (*c).x,
+        PHA => push(c, (*c).a),
+        PHP => push(c, (*c).p | FLAG_B | FLAG_U),
+        PLA => (*c).a = set_zn(c, pull(c)),
+        PLP => (*c).p = (pull(c) & !FLAG_B) | FLAG_U,
+        CLC => set_flag(c, FLAG_C, false),
+        CLD => set_flag(c, FLAG_D, false),
+        CLI => set_flag(c, FLAG_I, false),
+        CLV => set_flag(c, FLAG_V, false),
+        SEC => set_flag(c, FLAG_C, true),
+        SED => set_flag(c, FLAG_D, true),
+        SEI => set_flag(c, FLAG_I, true),
+        BCC => branch(c, (*c).p & FLAG_C == 0),
+        BCS => branch(c, (*c).p & FLAG_C != 0),
+        BNE => branch(c, (*c).p & FLAG_Z == 0),
+        BEQ => branch(c, (*c).p & FLAG_Z != 0),
+        BPL => branch(c, (*c).p & FLAG_N == 0),
+        BMI => branch(c, (*c).p & FLAG_N != 0),
+        BVC => branch(c, (*c).p & FLAG_V == 0),
+        BVS => branch(c, (*c).p & FLAG_V != 0),
+        JMP => (*c).pc = addr,
+        JSR => {
+            // The return address points to the last byte of the JSR
+            let ret = next.wrapping_sub(1);
+            push(c, (ret >> 8) as u8);
+            push(c, ret as u8);
+            (*c).pc = addr;
+        }
+        RTS => rts(c),
+        RTI => {
+            (*c).p = (pull(c) & !FLAG_B) | FLAG_U;
+            let low = pull(c) as u16;
+            (*c).pc = low | (pull(c) as u16) << 8;
+        }
+        BRK => {
+            let ret = next.wrapping_add(1);
+            push(c, (ret >> 8) as u8);
+            push(c, ret as u8);
+            push(c, (*c).p | FLAG_B | FLAG_U);
+            set_flag(c, FLAG_I, true);
+            (*c).pc = read16(c, IRQ_VECTOR);
+        }
+        NOP => {}
+        Instr::COUNT => unreachable!(),
+    }
+    if !(*c).stack_error.is_null() {
+        log(Log_Level::ERROR, c!("6502: stack %s at $%04X"), (*c).stack_error, start as c_uint);
+        return None;
+    }
+    Some(())
+}
+
+pub unsafe fn rts(c: *mut Cpu) {
+    let low = pull(c) as u16;
+    (*c).pc = (low | (pull(c) as u16) << 8).wrapping_add(1);
+}
+
+pub unsafe fn trace(c: *mut Cpu) {
+    let opcode = (*c).mem[(*c).pc as usize];
+    let Some((instr, mode)) = (*c).decode[opcode as usize] else { return; };
+    let arg8 = (*c).mem[(*c).pc.wrapping_add(1) as usize] as c_uint;
+    let arg16 = read16(c, (*c).pc.wrapping_add(1)) as c_uint;
+
+    let mut bytes = [0 as c_char; 16];
+    let mut operand = [0 as c_char; 16];
+    let size = operand_size(mode);
+    match size {
+        0 => snprintf(bytes.as_mut_ptr(), bytes.len(), c!("%02X"), opcode as c_uint),
+        1 => snprintf(bytes.as_mut_ptr(), bytes.len(), c!("%02X %02X"), opcode as c_uint, arg8),
+        _ => snprintf(bytes.as_mut_ptr(), bytes.len(), c!("%02X %02X %02X"), opcode as c_uint, arg16 & 0xFF, arg16 >> 8),
+    };
+    let (fmt, arg) = match mode {
+        IMPL  => (c!(""), 0),
+        ACC   => (c!("A"), 0),
+        IMM   => (c!("#$%02X"), arg8),
+        ZP    => (c!("$%02X"), arg8),
+        ZP_X  => (c!("$%02X,X"), arg8),
+        ZP_Y  => (c!("$%02X,Y"), arg8),
+        ABS   => (c!("$%04X"), arg16),
+        ABS_X => (c!("$%04X,X"), arg16),
+        ABS_Y => (c!("$%04X,Y"), arg16),
+        IND_X => (c!("($%02X,X)"), arg8),
+        IND_Y => (c!("($%02X),Y"), arg8),
+        IND   => (c!("($%04X)"), arg16),
+        REL   => (c!("$%04X"), operand_addr(c, REL).0 as c_uint),
+        AddrMode::COUNT => unreachable!(),
+    };
+    snprintf(operand.as_mut_ptr(), operand.len(), fmt, arg);
+    fprintf(stderr(), c!("%04X  %-8s  %s %-9s  A:%02X X:%02X Y:%02X P:%02X SP:%02X CYC:%llu\n"),
+            (*c).pc as c_uint, bytes.as_ptr(), instr_name(instr), operand.as_ptr(),
+            (*c).a as c_uint, (*c).x as c_uint, (*c).y as c_uint, (*c).p as c_uint, (*c).sp as c_uint,
+            (*c).cycles as c_ulonglong);
+}
+
+/// Runs the image until it exits. Returns the exit code along with the amount of the spent cycles.
+pub unsafe fn run(image_path: *const c_char, load_offset: u16, trace_instrs: bool) -> Option<(c_int, u64)> {
+    let mut image: String_Builder = zeroed();
+    read_entire_file(image_path, &mut image)?;
+    if image.count > 0x10000 - load_offset as usize {
+        log(Log_Level::ERROR, c!("%s: the image does not fit into memory at $%04X (%zu bytes)"), image_path, load_offset as c_uint, image.count);
+        free(image.items);
+        return None;
+    }
+
+    let c = realloc_items(ptr::null_mut::<Cpu>(), 1);
+    memset(c as *mut c_void, 0, size_of::<Cpu>());
+    ptr::copy_nonoverlapping(image.items as *const u8, (*c).mem.as_mut_ptr().add(load_offset as usize), image.count);
+    free(image.items);
+
+    // The zeroed Option is not necessarily None
+    (*c).decode = [None; 0x100];
+    for i in 0..Instr::COUNT as u8 {
+        for j in 0..AddrMode::COUNT as u8 {
+            let opcode = OPCODES[i as usize][j as usize];
+            if opcode != INVL {
+                (*c).decode[opcode as usize] = Some((core::mem::transmute(i), core::mem::transmute(j)));
+            }
+        }
+    }
+    (*c).pc = load_offset;
+    (*c).sp = 0xFF;
+    (*c).p = FLAG_U | FLAG_I;
+
+    let mut result = None;
+    loop {
+        match (*c).pc {
+            EXIT_ADDR => {
+                result = Some(((*c).a as c_int, (*c).cycles));
+                break;
+            }
+            PUTCHAR_ADDR => {
+                fwrite(&(*c).a as *const u8 as *const c_void, 1, 1, stdout());
+                // Pretend the routine is just an RTS
+                rts(c);
+                (*c).cycles += base_cycles(RTS, IMPL);
+            }
+            _ => {
+                if trace_instrs { trace(c); }
+                if step(c).is_none() { break; }
+            }
+        }
+    }
+    fflush(stdout());
+
+    free(c);
+    result
+}
@@ -13,11 +13,13 @@ use crate::ir::*;
 use crate::nob::*;
 use crate::diagf;
 use crate::crust::libc::*;
-use crate::lexer::{is_identifier_start, is_identifier};
 use crate::arena::{self, Arena};
-use crate::targets::TargetAPI;
+use crate::targets::{TargetAPI, BuildStage};
 use crate::params::*;
 
+pub mod emulator;
+pub mod assembler;
+
 // TODO: does this have to be a macro?
 macro_rules! instr_enum {
     (enum $n:ident { $($instr:ident),* }) => {
@@ -38,6 +40,13 @@ macro_rules! instr_enum {
             )*
             return None;
         }
+
+        pub fn instr_name(instr: $n) -> *const c_char {
+            match instr {
+                $($n::$instr => c!(stringify!($instr)),)*
+                $n::COUNT => unreachable!(),
+            }
+        }
     }
 }
 
@@ -262,6 +271,8 @@ pub struct Assembler {
     pub addresses: Array<u16>,
     pub code_start: u16, // load address of code section
     pub frame_sz: u8, // current stack frame size in bytes, because 6502 has no base register
+    pub divmod: usize, // address label of the division routine, see generate_divmod()
+    pub uses_divmod: bool,
     pub string_arena: Arena, // used for inline assembly labels
 }
 
@@ -463,310 +474,6 @@ pub unsafe fn load_two_args(out: *mut String_Builder, lhs: Arg, rhs: Arg, op: Op
     load_arg(lhs, op.loc, out, asm);
 }
 
-#[derive(Clone, Copy)]
-#[repr(C)]
-pub enum Address {
-    Literal(u16),
-    Label(*const c_char),
-}
-
-
-pub unsafe fn parse_num(line_begin: *const c_char, mut line: *const c_char, mut loc: Loc) -> (u16, *const c_char) {
-    while isspace(*line as i32) != 0 {line = line.add(1);}
-
-    let (v, mut end) = match *line as u8 {
-        b'$' => {
-            let mut end = ptr::null_mut();
-            let v = strtoull(line.add(1), &mut end, 16);
-            (v, end as *const c_char)
-        }
-        b'0'..=b'9' => {
-            let mut end = ptr::null_mut();
-            let v = strtoull(line, &mut end, 10);
-            (v, end as *const c_char)
-        },
-        c => {
-            loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-            diagf!(loc, c!("ERROR: unexpected character `%c` in numberic literal\n"),
-                   c as c_int);
-            abort();
-        }
-    };
-    if v > 0xFFFF {
-        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-        diagf!(loc, c!("ERROR: contant $%X out of range for 16 bits\n"), v);
-        abort();
-    }
-    while isspace(*end as i32) != 0 {end = end.add(1);}
-    (v as u16, end)
-}
-
-pub unsafe fn parse_addr_or_label(line_begin: *const c_char, mut line: *const c_char, loc: Loc,
-                                  asm: *mut Assembler) -> (Address, *const c_char) {
-    while isspace(*line as i32) != 0 {line = line.add(1);}
-
-    let (v, mut end) = match *line as u8 {
-        c if is_identifier_start(c as i8) => {
-            let start = line;
-            while is_identifier(*line as i8) {line = line.add(1);}
-            let len = line as isize - start as isize;
-
-            let label = arena::sprintf(&mut (*asm).string_arena, c!("%.*s"), len, start);
-            (Address::Label(label), line)
-        },
-        _ => {
-            let (v, line) = parse_num(line_begin, line, loc);
-            (Address::Literal(v), line)
-        }
-    };
-    while isspace(*end as i32) != 0 {end = end.add(1);}
-    (v, end)
-}
-
-pub unsafe fn assemble_statement(out: *mut String_Builder,
-                                 mut line: *const c_char, mut loc: Loc,
-                                 asm: *mut Assembler) {
-
-    let line_begin = line;
-    // TODO: IMPORTANT! What we are doing in here is basically lexing.
-    // Consider maybe reusing and adapting our B lexer in here?
-    while isspace(*line as i32) != 0 {
-        line = line.add(1);
-    }
-
-    let inst_start = line;
-    while *line != 0 && isspace(*line as i32) == 0 {
-        line = line.add(1);
-    }
-    let len = line as usize - inst_start as usize;
-    let name = arena::sprintf(&mut (*asm).string_arena, c!("%.*s"), len, inst_start);
-
-    if len > 0 && *name.add(len-1) as u8 == b':' {
-        *name.add(len-1) = 0;
-        let label_addr = (*out).count as u16;
-        let mut lloc = loc;
-        lloc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-
-        add_external(name, label_addr, lloc, asm);
-
-        if *line != 0 {
-            diagf!(lloc, c!("ERROR: trailing garbage after label: `%s`\n"), line);
-            abort();
-        }
-        return;
-    }
-
-    for i in 0..len {
-        *name.add(i) = toupper(*name.add(i) as i32) as i8;
-    }
-    let instr = match instr_from_string(name) {
-        Some(v) => v,
-        None => {
-            loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-            diagf!(loc, c!("ERROR: invalid instruction mnemonic `%s`\n"), name);
-            abort();
-        }
-    };
-
-    while isspace(*line as i32) != 0 {line = line.add(1);}
-
-    let operand = line;
-    let mut arg8 = None;
-    let mut arg16 = None;
-    let mut arg_label = None;
-
-    let mut mode = match *line as u8 {
-        0    => IMPL,
-        b'*' => {
-            line = line.add(1);
-
-            let rel = match *line as u8 {
-                b'+' | b'-' => {
-                    let mut end = ptr::null_mut();
-                    let num = strtoull(line, &mut end, 10) as u16 as i16;
-                    line = end;
-                    num as i8
-                },
-                _ => 0
-            };
-            arg8 = Some((rel - 2) as u8);
-            REL
-        },
-        b'#' => {
-            line = line.add(1);
-
-            let num;
-            (num, line) = parse_num(line_begin, line, loc);
-            if num > 0xFF {
-                loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                diagf!(loc, c!("ERROR: constant $%X out of range for 8 bit immediate\n"),
-                       num as c_uint);
-                abort();
-            }
-            arg8 = Some(num as u8);
-            IMM
-        },
-        b'(' => {
-            line = line.add(1);
-            let addr;
-            (addr, line) = parse_addr_or_label(line_begin, line, loc, asm);
-
-            if *line as u8 == b',' {
-                let num = match addr {
-                    Address::Literal(l) => l,
-                    Address::Label(_) => {
-                        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                        diagf!(loc, c!("ERROR: cannot use 16-bit label address for X-inderect addressing\n"));
-                        abort();
-                    },
-                };
-
-                if num > 0xFF {
-                    loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                    diagf!(loc, c!("ERROR: constant $%X out of 8-bit range for indirect X\n"),
-                           num as c_uint);
-                    abort();
-                }
-                arg8 = Some(num as u8);
-
-                line = line.add(1);
-                while isspace(*line as i32) != 0 {line = line.add(1);}
-                if toupper(*line as i32) as u8 != b'X' {
-                    loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                    diagf!(loc, c!("ERROR: X expected for indirect addressing mode\n"), *line as c_int);
-                    abort();
-                }
-                line = line.add(1);
-                while isspace(*line as i32) != 0 {line = line.add(1);}
-                if toupper(*line as i32) as u8 != b')' {
-                    loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                    diagf!(loc, c!("ERROR: ) expected after X-indirect address\n"), *line as c_int);
-                    abort();
-                }
-                line = line.add(1);
-                IND_X
-            } else {
-                if *line as u8 != b')' {
-                    loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                    diagf!(loc, c!("ERROR: expected ',' or ')' after indirect address\n"), *line as c_int);
-                    abort();
-                }
-                line = line.add(1);
-                while isspace(*line as i32) != 0 {line = line.add(1);}
-
-                if *line as u8 == b',' {
-                    let num = match addr {
-                        Address::Literal(l) => l,
-                        Address::Label(_) => {
-                            loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                            diagf!(loc, c!("ERROR: cannot use 16-bit label address for Y-inderect addressing\n"));
-                            abort();
-                        },
-                    };
-
-                    if num > 0xFF {
-                        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                        diagf!(loc, c!("ERROR: constant $%X out of 8-bit range for indirect Y\n"),
-                               num as c_uint);
-                        abort();
-                    }
-                    arg8 = Some(num as u8);
-
-                    line = line.add(1);
-                    while isspace(*line as i32) != 0 {line = line.add(1);}
-                    if toupper(*line as i32) as u8 != b'Y' {
-                        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-                        diagf!(loc, c!("ERROR: Y expected for Y-indirect addressing mode\n"), *line as c_int);
-                        abort();
-                    }
-                    line = line.add(1);
-                    IND_Y
-                } else {
-                    match addr {
-                        Address::Literal(l) => arg16 = Some(l),
-                        Address::Label(s) => arg_label = Some(s),
-                    }
-                    IND
-                }
-            }
-        },
-        _  => {
-            let addr;
-            (addr, line) = parse_addr_or_label(line_begin, line, loc, asm);
-            match addr {
-                Address::Literal(l) => arg16 = Some(l),
-                Address::Label(s) => arg_label = Some(s),
-            }
-
-            if *line as u8 == b',' {
-                line = line.add(1);
-                while isspace(*line as i32) != 0 {line = line.add(1);}
-
-                if toupper(*line as i32) as u8 == b'X' {
-                    line = line.add(1);
-                    ABS_X
-                } else if toupper(*line as i32) as u8 == b'Y' {
-                    line = line.add(1);
-                    ABS_Y
-                } else {
-                    ABS
-                }
-            } else {
-                ABS
-            }
-        },
-    };
-
-    // prefer zeropage instructions, if they exist
-    if let Some(v) = arg16 {
-        if mode == ABS && v <= 0xFF && OPCODES[instr as usize][ZP as usize] != INVL {
-            mode = ZP;
-            arg8 = Some(v as u8);
-            arg16 = None;
-        } else if mode == ABS_X && v <= 0xFF && OPCODES[instr as usize][ZP_X as usize] != INVL {
-            mode = ZP_X;
-            arg8 = Some(v as u8);
-            arg16 = None;
-        } else if mode == ABS_Y && v <= 0xFF && OPCODES[instr as usize][ZP_Y as usize] != INVL {
-            mode = ZP_Y;
-            arg8 = Some(v as u8);
-            arg16 = None;
-        }
-    }
-
-    // labels for REL-only instructions should use REL
-    if let Some(_) = arg_label {
-        if mode == ABS && OPCODES[instr as usize][ABS as usize] == INVL &&
-            OPCODES[instr as usize][REL as usize] != INVL {
-            mode = REL;
-        }
-    }
-
-    let opcode = OPCODES[instr as usize][mode as usize];
-    if opcode == INVL {
-        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-        diagf!(loc, c!("ERROR: invalid combination of instruction `%s` and operand `%s`\n"),
-               name, operand);
-        abort();
-    }
-
-    write_byte(out, opcode);
-    if let Some(a) = arg8 {
-        write_byte(out, a);
-    } else if let Some(a) = arg16 {
-        write_word(out, a);
-    } else if let Some(name) = arg_label {
-        add_reloc(out, RelocationKind::External {name, offset: 0, byte: Byte::Both,
-                                                 relative: mode == REL}, asm);
-    }
-
-    if *line != 0 {
-        loc.line_offset += (line as isize - line_begin as isize + 1) as i32;
-        diagf!(loc, c!("ERROR: trailing garbage: `%s`\n"), line);
-        abort();
-    }
-}
-
 // repetitve code for emulating 16bit instructions
 // TODO: most of these could probably be converted
 // to intrinsic functions
@@ -816,14 +523,103 @@ mod ops {
         // }
         link_address_label_here(if1_end, out, asm);
     }
+
+    // (low+1):low = -(low+1):low
+    pub unsafe fn negate_zp(out: *mut String_Builder, low: u8) {
+        instr8(out, LDA, IMM, 0);
+        instr(out, SEC);
+        instr8(out, SBC, ZP, low);
+        instr8(out, STA, ZP, low);
+        instr8(out, LDA, IMM, 0);
+        instr8(out, SBC, ZP, low + 1);
+        instr8(out, STA, ZP, low + 1);
+    }
+
+    // Y:A = Y:A << count or Y:A >> count (logical)
+    pub unsafe fn shift_by_constant(out: *mut String_Builder, left: bool, count: u64) {
+        if count >= 16 {
+            instr8(out, LDA, IMM, 0);
+            instr(out, TAY);
+        } else if count >= 8 {
+            // move one byte into the other, then shift only it
+            if left {
+                for _ in 8..count { instr0(out, ASL, ACC); }
+                instr(out, TAY);
+                instr8(out, LDA, IMM, 0);
+            } else {
+                instr(out, TYA);
+                for _ in 8..count { instr0(out, LSR, ACC); }
+                instr8(out, LDY, IMM, 0);
+            }
+        } else if count > 0 {
+            instr8(out, STY, ZP, ZP_TMP_1);
+            for _ in 0..count {
+                if left {
+                    instr0(out, ASL, ACC);
+                    instr8(out, ROL, ZP, ZP_TMP_1);
+                } else {
+                    instr8(out, LSR, ZP, ZP_TMP_1);
+                    instr0(out, ROR, ACC);
+                }
+            }
+            instr8(out, LDY, ZP, ZP_TMP_1);
+        }
+    }
+
+    // Y:A = Y:A << RHS or Y:A >> RHS (logical)
+    pub unsafe fn shift_by_rhs(out: *mut String_Builder, left: bool, asm: *mut Assembler) {
+        let shift_loop = create_address_label(asm);
+        let zero = create_address_label(asm);
+        let done = create_address_label(asm);
+
+        instr8(out, STA, ZP, ZP_TMP_0);
+        instr8(out, STY, ZP, ZP_TMP_1);
+
+        // everything is shifted out if count >= 16
+        // TODO: do we have to handle negative shifts?
+        instr8(out, LDX, ZP, ZP_RHS_L);
+        instr8(out, LDA, ZP, ZP_RHS_H);
+        instr0(out, BNE, REL);
+        add_reloc(out, RelocationKind::Address{idx: zero, relative: true}, asm);
+        instr8(out, CPX, IMM, 16);
+        instr0(out, BCS, REL);
+        add_reloc(out, RelocationKind::Address{idx: zero, relative: true}, asm);
+        instr8(out, CPX, IMM, 0);
+        instr0(out, BEQ, REL);
+        add_reloc(out, RelocationKind::Address{idx: done, relative: true}, asm);
+
+        link_address_label_here(shift_loop, out, asm);
+        if left {
+            instr8(out, ASL, ZP, ZP_TMP_0);
+            instr8(out, ROL, ZP, ZP_TMP_1);
+        } else {
+            instr8(out, LSR, ZP, ZP_TMP_1);
+            instr8(out, ROR, ZP, ZP_TMP_0);
+        }
+        instr(out, DEX);
+        instr0(out, BNE, REL);
+        add_reloc(out, RelocationKind::Address{idx: shift_loop, relative: true}, asm);
+        // Z is set here, so this is an unconditional jump
+        instr0(out, BEQ, REL);
+        add_reloc(out, RelocationKind::Address{idx: done, relative: true}, asm);
+
+        link_address_label_here(zero, out, asm);
+        instr8(out, LDA, IMM, 0);
+        instr8(out, STA, ZP, ZP_TMP_0);
+        instr8(out, STA, ZP, ZP_TMP_1);
+
+        link_address_label_here(done, out, asm);
+        instr8(out, LDA, ZP, ZP_TMP_0);
+        instr8(out, LDY, ZP, ZP_TMP_1);
+    }
 }
 
 pub unsafe fn generate_function(name: *const c_char, loc: Loc, params_count: usize, auto_vars_count: usize,
                                 body: *const [OpWithLocation], out: *mut String_Builder,
-                                asm: *mut Assembler) {
+                                asm: *mut Assembler) -> Option<()> {
     (*asm).frame_sz = 0;
     let fun_addr = (*out).count as u16;
-    add_external(name, fun_addr, loc, asm);
+    add_external(name, fun_addr, loc, asm)?;
 
     // prepare function labels for each op and the end of the function
     let mut op_addresses: Array<usize> = zeroed();
@@ -963,52 +759,15 @@ pub unsafe fn generate_function(name: *const c_char, loc: Loc, params_count: usi
                         instr(out, TAY);
                         instr(out, TXA);
                     },
-                    Binop::BitShl => {
-                        load_two_args(out, lhs, rhs, op, asm);
-
-                        instr8(out, STA, ZP, ZP_TMP_0);
-                        instr8(out, STY, ZP, ZP_TMP_1);
-
-                        // as maximum shift is 16, Y can be ignored.
-                        // TODO: only shift 16 times if value > 16 provided
-                        // TODO: do we have to handle negative shifts?
-                        instr8(out, LDX, ZP, ZP_RHS_L);
-
-                        let loop_start = create_address_label_here(out, asm);
-                        instr8(out, BEQ, REL, 8);
-
-                        instr8(out, ASL, ZP, ZP_TMP_0);
-                        instr8(out, ROL, ZP, ZP_TMP_1);
-
-                        instr(out, DEX);
-                        instr0(out, JMP, ABS);
-                        add_reloc(out, RelocationKind::Address{idx: loop_start, relative: false}, asm);
-
-                        instr8(out, LDA, ZP, ZP_TMP_0);
-                        instr8(out, LDY, ZP, ZP_TMP_1);
-                    },
-                    Binop::BitShr => {
-                        load_two_args(out, lhs, rhs, op, asm);
-
-                        instr8(out, STA, ZP, ZP_TMP_0);
-                        instr8(out, STY, ZP, ZP_TMP_1);
-
-                        // as maximum shift is 16, Y can be ignored.
-                        // TODO: only shift 16 times if value > 16 provided
-                        // TODO: do we have to handle negative shifts?
-                        instr8(out, LDX, ZP, ZP_RHS_L);
-
-                        let loop_start = create_address_label_here(out, asm);
-                        instr8(out, BEQ, REL, 8);
-
-                        instr8(out, LSR, ZP, ZP_TMP_1);
-                        instr8(out, ROR, ZP, ZP_TMP_0);
-
-                        instr(out, DEX);
-                        instr0(out, JMP, ABS);
-                        add_reloc(out, RelocationKind::Address{idx: loop_start, relative: false}, asm);
-                        instr8(out, LDA, ZP, ZP_TMP_0);
-                        instr8(out, LDY, ZP, ZP_TMP_1);
+                    Binop::BitShl | Binop::BitShr => {
+                        let left = binop == Binop::BitShl;
+                        if let Arg::Literal(count) = rhs {
+                            load_arg(lhs, op.loc, out, asm);
+                            ops::shift_by_constant(out, left, count);
+                        } else {
+                            load_two_args(out, lhs, rhs, op, asm);
+                            ops::shift_by_rhs(out, left, asm);
+                        }
                     },
                     Binop::Plus => {
                         load_two_args(out, lhs, rhs, op, asm);
@@ -1032,33 +791,20 @@ pub unsafe fn generate_function(name: *const c_char, loc: Loc, params_count: usi
                         instr(out, TAY);
                         instr(out, TXA);
                     },
-                    Binop::Mod => {
-                        // !! TODO !! this should be implemented here and not as a B functions.
-                        // TODO: current mod implementation is linear, we can do better.
-                        load_arg(rhs, op.loc, out, asm);
-                        push16(out, asm);
-                        load_arg(lhs, op.loc, out, asm);
+                    Binop::Mod | Binop::Div => {
+                        load_two_args(out, lhs, rhs, op, asm);
 
+                        (*asm).uses_divmod = true;
                         instr0(out, JSR, ABS);
-                        add_reloc(out, RelocationKind::External{name: c!("_rem"), offset: 0,
-                                                                   byte: Byte::Both, relative: false}, asm);
-                        instr(out, TAX);
-                        pop16_discard(out, asm);
-                        instr(out, TXA);
-                    },
-                    Binop::Div => {
-                        // !! TODO !! this should be implemented here and not as a B functions.
-                        // TODO: current div implementation is linear, we can do better.
-                        load_arg(rhs, op.loc, out, asm);
-                        push16(out, asm);
-                        load_arg(lhs, op.loc, out, asm);
+                        add_reloc(out, RelocationKind::Address{idx: (*asm).divmod, relative: false}, asm);
 
-                        instr0(out, JSR, ABS);
-                        add_reloc(out, RelocationKind::External{name: c!("_div"), offset: 0,
-                                                                   byte: Byte::Both, relative: false}, asm);
-                        instr(out, TAX);
-                        pop16_discard(out, asm);
-                        instr(out, TXA);
+                        if binop == Binop::Div {
+                            instr8(out, LDA, ZP, ZP_TMP_0);
+                            instr8(out, LDY, ZP, ZP_TMP_1);
+                        } else {
+                            instr8(out, LDA, ZP, ZP_TMP_2);
+                            instr8(out, LDY, ZP, ZP_TMP_3);
+                        }
                     },
                     Binop::Mult => {
                         load_two_args(out, lhs, rhs, op, asm);
@@ -1322,12 +1068,7 @@ pub unsafe fn generate_function(name: *const c_char, loc: Loc, params_count: usi
                 }
                 store_auto(out, result, asm);
             },
-            Op::Asm {stmts} => {
-                for i in 0..stmts.count {
-                    let stmt = *stmts.items.add(i);
-                    assemble_statement(out, stmt.line, stmt.loc, asm);
-                }
-            },
+            Op::Asm {stmts} => assembler::assemble_block(out, da_slice(stmts), asm)?,
             Op::Label{label} => {
                 // RE: https://github.com/tsoding/b/pull/147#issue-3154667157
                 // > For this thing I introduces a new NOP instruction because it would be a bit too
@@ -1392,12 +1133,14 @@ pub unsafe fn generate_function(name: *const c_char, loc: Loc, params_count: usi
         instr8(out, LDA, ZP, ZP_TMP_0);
     }
     instr(out, RTS);
+    Some(())
 }
 
-pub unsafe fn generate_funcs(out: *mut String_Builder, funcs: *const [Func], asm: *mut Assembler) {
+pub unsafe fn generate_funcs(out: *mut String_Builder, funcs: *const [Func], asm: *mut Assembler) -> Option<()> {
     for i in 0..funcs.len() {
-        generate_function((*funcs)[i].name, (*funcs)[i].name_loc, (*funcs)[i].params_count, (*funcs)[i].auto_vars_count, da_slice((*funcs)[i].body), out, asm);
+        generate_function((*funcs)[i].name, (*funcs)[i].name_loc, (*funcs)[i].params_count, (*funcs)[i].auto_vars_count, da_slice((*funcs)[i].body), out, asm)?;
     }
+    Some(())
 }
 
 pub unsafe fn apply_relocations(out: *mut String_Builder, data_start: u16, asm: *mut Assembler) {
@@ -1428,9 +1171,13 @@ pub unsafe fn apply_relocations(out: *mut String_Builder, data_start: u16, asm:
                 for i in 0..(*asm).externals.count {
                     let label = *(*asm).externals.items.add(i);
                     if strcmp(label.name, name) == 0 {
-                        let faddr = (*asm).code_start + label.addr + offset as u16;
+                        let faddr = ((*asm).code_start + label.addr).wrapping_add(offset as u16);
                         if relative {
-                            let rel = (faddr as i64) - ((caddr + 1) as i64);
+                            let rel = (faddr as i64) - (((*asm).code_start + caddr + 1) as i64);
+                            if rel < -128 || rel > 127 {
+                                log(Log_Level::ERROR, c!("6502: Linking failed. `%s' is too far away for a branch (%lld bytes)"), name, rel);
+                                abort();
+                            }
                             write_byte_at(out, rel as i8 as u8, caddr);
                         } else {
                             match byte {
@@ -1459,6 +1206,16 @@ pub unsafe fn apply_relocations(out: *mut String_Builder, data_start: u16, asm:
     }
 }
 
+/// Generates the label file in the format of the VICE monitor (`al C:<addr> .<name>` per line), which can be loaded
+/// with its `ll` command. It covers the functions, the labels of the inline assembly, the globals and the data section.
+pub unsafe fn generate_labels(out: *mut String_Builder, data_start: u16, asm: *mut Assembler) {
+    for i in 0..(*asm).externals.count {
+        let ext = *(*asm).externals.items.add(i);
+        sb_appendf(out, c!("al C:%04x .%s\n"), ((*asm).code_start + ext.addr) as c_uint, ext.name);
+    }
+    sb_appendf(out, c!("al C:%04x .__data__\n"), data_start as c_uint);
+}
+
 pub unsafe fn generate_extrns(_out: *mut String_Builder, extrns: *const [*const c_char],
                               funcs: *const [Func], globals: *const [Global],
                               asm_funcs: *const [AsmFunc], _asm: *mut Assembler) {
@@ -1522,26 +1279,153 @@ pub unsafe fn generate_data_section(out: *mut String_Builder, data: *const [u8])
     }
 }
 
-pub unsafe fn generate_entry(out: *mut String_Builder, asm: *mut Assembler) {
+pub unsafe fn generate_entry(out: *mut String_Builder, attributes: *const [(*const c_char, Array<Attribute>)], asm: *mut Assembler) {
+    let entry = find_entry(attributes).unwrap_or(c!("main"));
     instr0(out, JSR, ABS);
-    add_reloc(out, RelocationKind::External{name: c!("main"), offset: 0, byte: Byte::Both, relative: false}, asm);
+    add_reloc(out, RelocationKind::External{name: entry, offset: 0, byte: Byte::Both, relative: false}, asm);
 
     instr16(out, JMP, IND, 0xFFFC);
 }
 
+/// `main` is an alias of the function marked as the entry point just like on the gas targets
+pub unsafe fn generate_main_alias(attributes: *const [(*const c_char, Array<Attribute>)], asm: *mut Assembler) -> Option<()> {
+    let Some(entry) = find_entry(attributes) else { return Some(()); };
+    if strcmp(entry, c!("main")) == 0 { return Some(()); }
+    for i in 0..(*asm).externals.count {
+        let ext = *(*asm).externals.items.add(i);
+        if strcmp(ext.name, entry) == 0 {
+            return add_external(c!("main"), ext.addr, ext.loc, asm);
+        }
+    }
+    Some(())
+}
+
+// The unsigned entry point of the division routine, used by `_udiv` and `_urem` of libb
+const UDIVMOD: *const c_char = c!("__udivmod");
+
+pub unsafe fn uses_udivmod(asm: *const Assembler) -> bool {
+    for i in 0..(*asm).relocs.count {
+        if let RelocationKind::External{name, ..} = (*(*asm).relocs.items.add(i)).kind {
+            if strcmp(name, UDIVMOD) == 0 { return true; }
+        }
+    }
+    false
+}
+
+// Signed 16-bit division of Y:A by RHS with the shift-and-subtract algorithm.
+// Leaves the quotient in TMP_0:TMP_1 and the remainder in TMP_2:TMP_3. Like in C the quotient is truncated
+// towards zero and the remainder has the sign of the dividend.
+// `__udivmod` right before it does the same for unsigned operands.
+// see: https://en.wikipedia.org/wiki/Division_algorithm#Integer_division_(unsigned)_with_remainder
+pub unsafe fn generate_divmod(out: *mut String_Builder, asm: *mut Assembler) -> Option<()> {
+    let lhs_positive = create_address_label(asm);
+    let rhs_positive = create_address_label(asm);
+
+    for i in 0..(*asm).externals.count {
+        let ext = *(*asm).externals.items.add(i);
+        if strcmp(ext.name, UDIVMOD) == 0 {
+            diagf!(ext.loc, c!("ERROR: the name `%s` is reserved for the division routine\n"), UDIVMOD);
+            return None;
+        }
+    }
+    da_append(&mut (*asm).externals, External {
+        name: UDIVMOD, addr: (*out).count as u16, loc: zeroed(),
+    });
+    instr8(out, STA, ZP, ZP_TMP_0);
+    instr8(out, STY, ZP, ZP_TMP_1);
+    instr8(out, LDA, IMM, 0);
+    instr8(out, STA, ZP, ZP_TMP_4);
+    instr0(out, BEQ, REL);
+    add_reloc(out, RelocationKind::Address{idx: rhs_positive, relative: true}, asm);
+
+    link_address_label_here((*asm).divmod, out, asm);
+    let div_loop = create_address_label(asm);
+    let no_subtract = create_address_label(asm);
+    let quotient_positive = create_address_label(asm);
+    let remainder_positive = create_address_label(asm);
+
+    instr8(out, STA, ZP, ZP_TMP_0);
+    instr8(out, STY, ZP, ZP_TMP_1);
+
+    // bit 0 of TMP_4: the quotient is negative, bit 7: the remainder is negative
+    instr8(out, LDA, IMM, 0);
+    instr8(out, STA, ZP, ZP_TMP_4);
+    instr8(out, CPY, IMM, 0);
+    instr0(out, BPL, REL);
+    add_reloc(out, RelocationKind::Address{idx: lhs_positive, relative: true}, asm);
+    ops::negate_zp(out, ZP_TMP_0);
+    instr8(out, LDA, IMM, 0x81);
+    instr8(out, STA, ZP, ZP_TMP_4);
+    link_address_label_here(lhs_positive, out, asm);
+
+    instr8(out, LDA, ZP, ZP_RHS_H);
+    instr0(out, BPL, REL);
+    add_reloc(out, RelocationKind::Address{idx: rhs_positive, relative: true}, asm);
+    ops::negate_zp(out, ZP_RHS_L);
+    instr8(out, LDA, ZP, ZP_TMP_4);
+    instr8(out, EOR, IMM, 1);
+    instr8(out, STA, ZP, ZP_TMP_4);
+    link_address_label_here(rhs_positive, out, asm);
+
+    // from here on: unsigned division, the dividend is shifted into the remainder bit by bit
+    // and replaced by the quotient
+    instr8(out, LDA, IMM, 0);
+    instr8(out, STA, ZP, ZP_TMP_2);
+    instr8(out, STA, ZP, ZP_TMP_3);
+    instr8(out, LDX, IMM, 16);
+
+    link_address_label_here(div_loop, out, asm);
+    instr8(out, ASL, ZP, ZP_TMP_0);
+    instr8(out, ROL, ZP, ZP_TMP_1);
+    instr8(out, ROL, ZP, ZP_TMP_2);
+    instr8(out, ROL, ZP, ZP_TMP_3);
+
+    // if (remainder >= rhs) { remainder -= rhs; quotient |= 1; }
+    instr8(out, LDA, ZP, ZP_TMP_2);
+    instr(out, SEC);
+    instr8(out, SBC, ZP, ZP_RHS_L);
+    instr(out, TAY);
+    instr8(out, LDA, ZP, ZP_TMP_3);
+    instr8(out, SBC, ZP, ZP_RHS_H);
+    instr0(out, BCC, REL);
+    add_reloc(out, RelocationKind::Address{idx: no_subtract, relative: true}, asm);
+    instr8(out, STA, ZP, ZP_TMP_3);
+    instr8(out, STY, ZP, ZP_TMP_2);
+    instr8(out, INC, ZP, ZP_TMP_0);
+    link_address_label_here(no_subtract, out, asm);
+
+    instr(out, DEX);
+    instr0(out, BNE, REL);
+    add_reloc(out, RelocationKind::Address{idx: div_loop, relative: true}, asm);
+
+    // restore the signs
+    instr8(out, LDA, ZP, ZP_TMP_4);
+    instr0(out, LSR, ACC);
+    instr0(out, BCC, REL);
+    add_reloc(out, RelocationKind::Address{idx: quotient_positive, relative: true}, asm);
+    ops::negate_zp(out, ZP_TMP_0);
+    link_address_label_here(quotient_positive, out, asm);
+
+    instr8(out, LDA, ZP, ZP_TMP_4);
+    instr0(out, BPL, REL);
+    add_reloc(out, RelocationKind::Address{idx: remainder_positive, relative: true}, asm);
+    ops::negate_zp(out, ZP_TMP_2);
+    link_address_label_here(remainder_positive, out, asm);
+
+    instr(out, RTS);
+    Some(())
+}
+
 pub unsafe fn generate_asm_funcs(out: *mut String_Builder, asm_funcs: *const [AsmFunc],
-                                 asm: *mut Assembler) {
+                                 asm: *mut Assembler) -> Option<()> {
     for i in 0..asm_funcs.len() {
         let asm_func = (*asm_funcs)[i];
 
         let fun_addr = (*out).count as u16;
-        add_external(asm_func.name, fun_addr, asm_func.name_loc, asm);
-
-        for j in 0..asm_func.body.count {
-            let stmt = *asm_func.body.items.add(j);
-            assemble_statement(out, stmt.line, stmt.loc, asm);
-        }
+        add_external(asm_func.name, fun_addr, asm_func.name_loc, asm)?;
+        assembler::assemble_block(out, da_slice(asm_func.body), asm)?;
     }
+    Some(())
 }
 
 pub unsafe fn usage(params: *const [Param]) {
@@ -1550,8 +1434,44 @@ pub unsafe fn usage(params: *const [Param]) {
     print_params_help(params);
 }
 
+enum_with_order! {
+    #[derive(Clone, Copy)]
+    enum Mos6502_Runner in MOS6502_RUNNER_ORDER {
+        Posix6502,
+        Builtin,
+    }
+}
+
+impl Mos6502_Runner {
+    fn name(self) -> *const c_char {
+        match self {
+            Mos6502_Runner::Posix6502 => c!("posix6502"),
+            Mos6502_Runner::Builtin   => c!("builtin"),
+        }
+    }
+
+    fn description(self) -> *const c_char {
+        match self {
+            Mos6502_Runner::Posix6502 => c!("The posix6502 emulator"),
+            Mos6502_Runner::Builtin   => c!("Built-in emulator implementing the posix6502 ABI"),
+        }
+    }
+
+    unsafe fn from_name(name: *const c_char) -> Option<Self> {
+        for i in 0..MOS6502_RUNNER_ORDER.len() {
+            let runner = (*MOS6502_RUNNER_ORDER)[i];
+            if strcmp(runner.name(), name) == 0 {
+                return Some(runner);
+            }
+        }
+        None
+    }
+}
+
 struct Mos6502 {
     load_offset: u64,
+    runner: Mos6502_Runner,
+    trace: bool,
     out: String_Builder,
     cmd: Cmd,
 }
@@ -1560,6 +1480,8 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("6502-posix"),
         file_ext: c!(".6502"),
+        object_file_ext: ptr::null(),
+        assembly_file_ext: ptr::null(),
         new,
         build: generate_program,
         run: run_program,
@@ -1571,6 +1493,7 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
     memset(gen as _ , 0, size_of::<Mos6502>());
 
     let mut help = false;
+    let mut runner_name = zeroed();
     let params = &[
         Param {
             name:        c!("help"),
@@ -1582,6 +1505,16 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
             description: c!("Offset at which the rom is expected to be loaded"),
             value:       ParamValue::Hex { var: &mut (*gen).load_offset, default: 0x8000 },
         },
+        Param {
+            name:        c!("runner"),
+            description: c!("What runner to use for the programs"),
+            value:       ParamValue::String { var: &mut runner_name, default: Mos6502_Runner::Posix6502.name() },
+        },
+        Param {
+            name:        c!("trace"),
+            description: c!("Print every instruction executed by the builtin runner to stderr"),
+            value:       ParamValue::Flag { var: &mut (*gen).trace },
+        },
     ];
 
     if let Err(message) = parse_args(params, args) {
@@ -1595,37 +1528,63 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
         return None;
     }
 
+    if let Some(runner) = Mos6502_Runner::from_name(runner_name) {
+        (*gen).runner = runner;
+    } else {
+        usage(params);
+        log(Log_Level::ERROR, c!("Invalid 6502 runner name `%s`!"), runner_name);
+        log(Log_Level::ERROR, c!("Valid names:"));
+        for i in 0..MOS6502_RUNNER_ORDER.len() {
+            let runner = (*MOS6502_RUNNER_ORDER)[i];
+            log(Log_Level::ERROR, c!("    %s - %s"), runner.name(), runner.description());
+        }
+        return None;
+    }
+
     Some(gen as *mut c_void)
 }
 
 pub unsafe fn generate_program(
     gen: *mut c_void, p: *const Program, program_path: *const c_char, _garbage_base: *const c_char,
-    _nostdlib: bool, debug: bool,
+    _nostdlib: bool, debug: bool, stage: BuildStage, _objects: *const [*const c_char],
 ) -> Option<()> {
+    assert!(stage == BuildStage::Program, "The target does not support native object files or assembly");
     let gen = gen as *mut Mos6502;
     let out = &mut (*gen).out;
 
-    if debug { todo!("Debug information for 6502") }
-
     let mut asm: Assembler = zeroed();
-    generate_entry(out, &mut asm);
+    generate_entry(out, da_slice((*p).attributes), &mut asm);
     asm.code_start = (*gen).load_offset as u16;
 
-    generate_funcs(out, da_slice((*p).funcs), &mut asm);
-    generate_asm_funcs(out, da_slice((*p).asm_funcs), &mut asm);
+    asm.divmod = create_address_label(&mut asm);
+
+    generate_funcs(out, da_slice((*p).funcs), &mut asm)?;
+    generate_asm_funcs(out, da_slice((*p).asm_funcs), &mut asm)?;
+    if asm.uses_divmod || uses_udivmod(&asm) {
+        generate_divmod(out, &mut asm)?;
+    }
     generate_extrns(out, da_slice((*p).extrns), da_slice((*p).funcs), da_slice((*p).globals), da_slice((*p).asm_funcs), &mut asm);
 
     let data_start = (*gen).load_offset as u16 + (*out).count as u16;
     generate_data_section(out, da_slice((*p).data));
     generate_globals(out, da_slice((*p).globals), &mut asm);
+    generate_main_alias(da_slice((*p).attributes), &mut asm)?;
 
     log(Log_Level::INFO, c!("Generated size: 0x%x"), (*out).count as c_uint);
     apply_relocations(out, data_start, &mut asm);
-    arena::reset(&mut asm.string_arena);
 
     write_entire_file(program_path, (*out).items as *const c_void, (*out).count)?;
     log(Log_Level::INFO, c!("generated %s"), program_path);
 
+    if debug {
+        let labels_path = temp_sprintf(c!("%s.lbl"), program_path);
+        (*out).count = 0;
+        generate_labels(out, data_start, &mut asm);
+        write_entire_file(labels_path, (*out).items as *const c_void, (*out).count)?;
+        log(Log_Level::INFO, c!("generated %s"), labels_path);
+    }
+    arena::reset(&mut asm.string_arena);
+
     Some(())
 }
 
@@ -1633,6 +1592,16 @@ pub unsafe fn run_program(
     gen: *mut c_void, program_path: *const c_char, run_args: *const [*const c_char],
 ) -> Option<()> {
     let gen = gen as *mut Mos6502;
+    if let Mos6502_Runner::Builtin = (*gen).runner {
+        // TODO: pass the run_args to the program once libb has a way to get them
+        let (code, cycles) = emulator::run(program_path, (*gen).load_offset as u16, (*gen).trace)?;
+        log(Log_Level::INFO, c!("%s took %llu cycles"), program_path, cycles as c_ulonglong);
+        if code != 0 {
+            log(Log_Level::ERROR, c!("%s exited with code %d"), program_path, code);
+            return None;
+        }
+        return Some(());
+    }
     let cmd = &mut (*gen).cmd;
     cmd_append!{
         cmd,
@@ -158,6 +158,7 @@ printn(n, b) _fprintn(n, b, 0);
 
 /* TODO: Consider adding support for negative numbers to Uxn's printf. */
 /* TODO: Consider adding support for %ul to Uxn's printf. */
+__variadic__(fprintf, 2);
 fprintf(fd, string, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12) {
     auto i, j, c, arg;
     i = 0;
@@ -205,6 +206,7 @@ fprintf(fd, string, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12) {
     }
 }
 
+__variadic__(printf, 1);
 printf(string, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12) {
     fprintf(0, string, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12);
 }
@@ -1,5 +1,7 @@
 use core::ffi::*;
 use core::mem::zeroed;
+use core::ptr;
+use core::slice;
 use crate::ir::*;
 use crate::nob::*;
 use crate::crust::libc::*;
@@ -9,9 +11,11 @@ use crate::diagf;
 use crate::arena;
 use crate::lexer;
 use crate::lexer::{Token, loc};
-use crate::targets::TargetAPI;
+use crate::targets::{TargetAPI, BuildStage};
 use crate::params::*;
 
+pub mod vm;
+
 // UXN memory map
 // 0x0000 - 0x00ff - zero page
 // 0x0100 - entry point. ROM file gets loaded here
@@ -31,6 +35,7 @@ pub struct Assembler {
     pub data_section_label: usize,
     pub resolved_addresses: Array<u16>, // maps label index to its byte offset
     pub patches: Array<Patch>,
+    pub scope: *const c_char, // the parent of the `&sublabels` in the inline assembly
 }
 
 #[derive(Clone, Copy)]
@@ -53,6 +58,7 @@ pub enum PatchKind {
     LowerAbsolute,
     UpperRelative,
     LowerRelative,
+    ByteRelative,
 }
 
 pub unsafe fn get_or_create_label_by_name(a: *mut Assembler, name: *const c_char) -> usize {
@@ -93,18 +99,225 @@ pub unsafe fn apply_patches(output: *mut String_Builder, a: *mut Assembler) -> O
             log(Log_Level::ERROR, c!("uxn: Label #%ld was never linked"), patch.label);
             return None;
         }
-        let offset = patch.offset;
+        // The labels of the zero page wrap around to the end of the address space
+        let target = addr.wrapping_add(patch.offset);
         let byte = match patch.kind {
-            PatchKind::UpperAbsolute => ((addr + 0x100 + offset) >> 8) & 0xff,
-            PatchKind::LowerAbsolute => (addr + 0x100 + offset) & 0xff,
-            PatchKind::UpperRelative => ((addr + offset).wrapping_sub(patch.addr).wrapping_sub(2) >> 8) & 0xff,
-            PatchKind::LowerRelative => ((addr + offset).wrapping_sub(patch.addr).wrapping_sub(1)) & 0xff,
+            PatchKind::UpperAbsolute => (target.wrapping_add(0x100) >> 8) & 0xff,
+            PatchKind::LowerAbsolute => target.wrapping_add(0x100) & 0xff,
+            PatchKind::UpperRelative => (target.wrapping_sub(patch.addr).wrapping_sub(2) >> 8) & 0xff,
+            PatchKind::LowerRelative => target.wrapping_sub(patch.addr).wrapping_sub(1) & 0xff,
+            PatchKind::ByteRelative  => {
+                let rel = target.wrapping_sub(patch.addr).wrapping_sub(2) as i16;
+                if !(-128..=127).contains(&rel) {
+                    log(Log_Level::ERROR, c!("uxn: Label '%s' is too far for a relative byte reference"), tal_label_name(a, patch.label));
+                    return None;
+                }
+                rel as u16 & 0xff
+            }
         };
         *(*output).items.add(patch.addr as usize) = byte as c_char;
     }
     Some(())
 }
 
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    pub addr: u16,
+    pub name: *const c_char,
+}
+
+unsafe extern "C" fn compar_symbol(a: *const c_void, b: *const c_void) -> c_int {
+    let a = *(a as *const Symbol);
+    let b = *(b as *const Symbol);
+    if a.addr != b.addr {
+        return a.addr as c_int - b.addr as c_int;
+    }
+    strcmp(a.name, b.name)
+}
+
+/// Generates the symbol file in the format of uxnasm: for each label sorted by address, a big-endian absolute
+/// address followed by the NUL-terminated name of the label. Uxn debuggers pick it up from `<rom>.sym`.
+pub unsafe fn generate_symbols(output: *mut String_Builder, a: *mut Assembler) {
+    let mut symbols: Array<Symbol> = zeroed();
+    for i in 0..(*a).named_labels.count {
+        let named_label = *(*a).named_labels.items.add(i);
+        let addr = *(*a).resolved_addresses.items.add(named_label.label);
+        da_append(&mut symbols, Symbol {addr, name: named_label.name});
+    }
+    let data_addr = *(*a).resolved_addresses.items.add((*a).data_section_label);
+    da_append(&mut symbols, Symbol {addr: data_addr, name: c!("__data__")});
+    qsort(symbols.items as *mut c_void, symbols.count, size_of::<Symbol>(), compar_symbol);
+
+    for i in 0..symbols.count {
+        let symbol = *symbols.items.add(i);
+        write_short(output, symbol.addr.wrapping_add(0x100));
+        da_append_many(output, slice::from_raw_parts(symbol.name, strlen(symbol.name) + 1));
+    }
+    free(symbols.items);
+}
+
+/// Name of the label in the Uxntal output. The B names that uxnasm would take for something else (hex numbers,
+/// opcodes, runes) get the `b:` prefix, which can't appear in B names. The same prefix is used for the labels
+/// that have no names in B.
+pub unsafe fn tal_label_name(a: *mut Assembler, label: usize) -> *const c_char {
+    if label == (*a).data_section_label {
+        return c!("b:data");
+    }
+    for i in 0..(*a).named_labels.count {
+        let named_label = *(*a).named_labels.items.add(i);
+        if named_label.label == label {
+            let name = named_label.name;
+            let len = strlen(name);
+            let mut hex = len == 2 || len == 4;
+            for j in 0..len {
+                let x = *name.add(j) as u8;
+                hex = hex && (x.is_ascii_digit() || (b'a'..=b'f').contains(&x));
+            }
+            let opcode = (*name as u8).is_ascii_uppercase() && find_opcode_by_name(name).is_some();
+            if hex || opcode || isalpha(*name as c_int) == 0 {
+                return temp_sprintf(c!("b:%s"), name);
+            }
+            return name;
+        }
+    }
+    temp_sprintf(c!("b:L%zu"), label)
+}
+
+/// Generates Uxntal source that uxnasm assembles into exactly the same ROM. The code is disassembled back into
+/// opcodes and the patches are turned into references to the labels, everything after the code is dumped as raw
+/// bytes. Must be called after apply_patches().
+pub unsafe fn generate_tal(output: *mut String_Builder, rom: *const [u8], a: *mut Assembler) {
+    // Labels sorted by address. The patches with offsets need their own labels in the middle of the data.
+    let mut symbols: Array<Symbol> = zeroed();
+    for label in 0..(*a).label_count {
+        let addr = *(*a).resolved_addresses.items.add(label);
+        da_append(&mut symbols, Symbol {addr, name: tal_label_name(a, label)});
+    }
+    // Maps address to the index of the patch of the upper byte that starts there
+    let mut patch_at: Array<usize> = zeroed();
+    for _ in 0..rom.len() {
+        da_append(&mut patch_at, usize::MAX);
+    }
+    let mut patch_names: Array<*const c_char> = zeroed();
+    for i in 0..(*a).patches.count {
+        let patch = *(*a).patches.items.add(i);
+        let mut name = ptr::null();
+        match patch.kind {
+            PatchKind::UpperAbsolute | PatchKind::UpperRelative => {
+                *patch_at.items.add(patch.addr as usize) = i;
+                name = tal_label_name(a, patch.label);
+                if patch.offset != 0 {
+                    name = temp_sprintf(c!("%s+%u"), name, patch.offset as c_uint);
+                    let addr = (*(*a).resolved_addresses.items.add(patch.label)).wrapping_add(patch.offset);
+                    da_append(&mut symbols, Symbol {addr, name});
+                }
+            }
+            PatchKind::LowerAbsolute | PatchKind::LowerRelative | PatchKind::ByteRelative => {}
+        }
+        da_append(&mut patch_names, name);
+    }
+    qsort(symbols.items as *mut c_void, symbols.count, size_of::<Symbol>(), compar_symbol);
+
+    let code_end = *(*a).resolved_addresses.items.add((*a).data_section_label) as usize;
+    // Whether the bytes in [begin, end) can be emitted as a single item, that is no label points inside of them
+    let fits = |symbol: usize, begin: usize, end: usize| -> bool {
+        end <= rom.len() && (symbol >= symbols.count || (*symbols.items.add(symbol)).addr as usize >= end || (*symbols.items.add(symbol)).addr as usize <= begin)
+    };
+    let patch_kind = |addr: usize| -> Option<PatchKind> {
+        if addr >= rom.len() { return None; }
+        let i = *patch_at.items.add(addr);
+        if i == usize::MAX { None } else { Some((*(*a).patches.items.add(i)).kind) }
+    };
+
+    // The labels of the zero page come last, since their addresses wrap around
+    for i in 0..symbols.count {
+        let sym = *symbols.items.add(i);
+        if sym.addr.wrapping_add(0x100) < 0x100 {
+            sb_appendf(output, c!("|%02x @%s\n"), sym.addr.wrapping_add(0x100) as c_uint, sym.name);
+        }
+    }
+    sb_appendf(output, c!("|0100\n"));
+    let mut symbol = 0;
+    let mut items_in_line = 0;
+    let mut pos = 0;
+    loop {
+        let mut labeled = false;
+        while symbol < symbols.count && (*symbols.items.add(symbol)).addr as usize <= pos {
+            let sym = *symbols.items.add(symbol);
+            // Duplicates come from the patches that point to the same place
+            if symbol == 0 || strcmp(sym.name, (*symbols.items.add(symbol - 1)).name) != 0 {
+                sb_appendf(output, c!("%s@%s"), if pos > 0 { c!("\n") } else { c!("") }, sym.name);
+                labeled = true;
+            }
+            symbol += 1;
+        }
+        // The labels past the end of the ROM are still referenced
+        if pos >= rom.len() { break; }
+        if labeled || items_in_line >= 16 {
+            sb_appendf(output, c!("\n"));
+            items_in_line = 0;
+        }
+        sb_appendf(output, c!("%s"), if items_in_line > 0 { c!(" ") } else { c!("    ") });
+        items_in_line += 1;
+
+        let byte = (*rom)[pos];
+        let short_at = |addr: usize| -> c_uint { ((*rom)[addr] as c_uint) << 8 | (*rom)[addr + 1] as c_uint };
+        let name_of_patch_at = |addr: usize| -> *const c_char { *patch_names.items.add(*patch_at.items.add(addr)) };
+        if pos < code_end {
+            let op: UxnOp = core::mem::transmute(byte);
+            if has_relative_immediate(op) && fits(symbol, pos, pos + 3) && matches!(patch_kind(pos + 1), Some(PatchKind::UpperRelative)) {
+                let rune = match op {
+                    UxnOp::JCI => c!("?"),
+                    UxnOp::JMI => c!("!"),
+                    _          => c!(""),
+                };
+                sb_appendf(output, c!("%s%s"), rune, name_of_patch_at(pos + 1));
+                pos += 3;
+            } else if (op == UxnOp::LIT2 || op == UxnOp::LIT2r) && fits(symbol, pos, pos + 3) && matches!(patch_kind(pos + 1), Some(PatchKind::UpperAbsolute)) {
+                let rune = if op == UxnOp::LIT2 { c!(";") } else { c!("LIT2r =") };
+                sb_appendf(output, c!("%s%s"), rune, name_of_patch_at(pos + 1));
+                pos += 3;
+            } else if matches!(patch_kind(pos), Some(PatchKind::UpperAbsolute)) && fits(symbol, pos, pos + 2) {
+                sb_appendf(output, c!("=%s"), name_of_patch_at(pos));
+                pos += 2;
+            } else if has_immediate(op) && patch_kind(pos + 1).is_none() && fits(symbol, pos, pos + 2) && (has_byte_immediate(op) || (patch_kind(pos + 2).is_none() && fits(symbol, pos, pos + 3))) {
+                let opcode = match op {
+                    UxnOp::LIT | UxnOp::LIT2 => c!("#"),
+                    UxnOp::LITr              => c!("LITr "),
+                    UxnOp::LIT2r             => c!("LIT2r "),
+                    _                        => temp_sprintf(c!("%02x "), byte as c_uint),
+                };
+                if has_byte_immediate(op) {
+                    sb_appendf(output, c!("%s%02x"), opcode, (*rom)[pos + 1] as c_uint);
+                    pos += 2;
+                } else {
+                    sb_appendf(output, c!("%s%04x"), opcode, short_at(pos + 1));
+                    pos += 3;
+                }
+            } else if has_immediate(op) {
+                // Only the opcodes without immediates have mnemonics that produce exactly one byte
+                sb_appendf(output, c!("%02x"), byte as c_uint);
+                pos += 1;
+            } else {
+                let name = (*AsmOpNames)[byte as usize];
+                sb_appendf(output, c!("%c%c%c%s"), toupper(*name as c_int), toupper(*name.add(1) as c_int), toupper(*name.add(2) as c_int), name.add(3));
+                pos += 1;
+            }
+        } else if matches!(patch_kind(pos), Some(PatchKind::UpperAbsolute)) && fits(symbol, pos, pos + 2) {
+            sb_appendf(output, c!("=%s"), name_of_patch_at(pos));
+            pos += 2;
+        } else {
+            sb_appendf(output, c!("%02x"), byte as c_uint);
+            pos += 1;
+        }
+    }
+    sb_appendf(output, c!("\n"));
+
+    free(symbols.items);
+    free(patch_at.items);
+    free(patch_names.items);
+}
+
 const SP: u8 = 0;
 const BP: u8 = 2;
 const FIRST_ARG: u8 = 4;
@@ -112,6 +325,7 @@ const FIRST_ARG: u8 = 4;
 pub unsafe fn generate_asm_funcs(output: *mut String_Builder, asm_funcs: *const [AsmFunc], assembler: *mut Assembler) -> Option<()> {
     for i in 0..asm_funcs.len() {
         let asm_func = (*asm_funcs)[i];
+        (*assembler).scope = asm_func.name;
         link_label(assembler, get_or_create_label_by_name(assembler, asm_func.name), (*output).count);
         process_asm_statements(output, da_slice(asm_func.body), assembler)?;
     }
@@ -129,6 +343,7 @@ enum_with_order! {
     enum Uxn_Runner in UXN_RUNNER_ORDER {
         Uxncli,
         Uxnemu,
+        Builtin,
     }
 }
 
@@ -137,6 +352,7 @@ impl Uxn_Runner {
         match self {
             Uxn_Runner::Uxncli => c!("uxncli"),
             Uxn_Runner::Uxnemu => c!("uxnemu"),
+            Uxn_Runner::Builtin => c!("builtin"),
         }
     }
 
@@ -144,6 +360,7 @@ impl Uxn_Runner {
         match self {
             Uxn_Runner::Uxncli => c!("CLI Emulator"),
             Uxn_Runner::Uxnemu => c!("GUI Emulator"),
+            Uxn_Runner::Builtin => c!("Built-in headless VM with the System, Console and File devices"),
         }
     }
 
@@ -160,6 +377,7 @@ impl Uxn_Runner {
 
 struct Uxn {
     runner: Uxn_Runner,
+    emit_tal: bool,
     output: String_Builder,
     cmd: Cmd,
 }
@@ -168,6 +386,8 @@ pub unsafe fn get_apis(targets: *mut Array<TargetAPI>) {
     da_append(targets, TargetAPI::V1 {
         name: c!("uxn"),
         file_ext: c!(".rom"),
+        object_file_ext: ptr::null(),
+        assembly_file_ext: c!(".tal"),
         new,
         build: generate_program,
         run: run_program,
@@ -180,6 +400,7 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
 
     let mut help = false;
     let mut runner_name = zeroed();
+    let mut emit = zeroed();
     let params = &[
         Param {
             name:        c!("help"),
@@ -191,6 +412,11 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
             description: c!("What runner to use for the Uxn roms"),
             value:       ParamValue::String { var: &mut runner_name, default: Uxn_Runner::Uxnemu.name() },
         },
+        Param {
+            name:        c!("emit"),
+            description: c!("What to emit: `rom` or `tal` to also write the equivalent Uxntal source of the rom to <program>.tal"),
+            value:       ParamValue::String { var: &mut emit, default: c!("rom") },
+        },
     ];
 
     if let Err(message) = parse_args(params, args) {
@@ -217,24 +443,31 @@ pub unsafe fn new(a: *mut arena::Arena, args: *const [*const c_char]) -> Option<
         return None;
     }
 
+    if strcmp(emit, c!("tal")) == 0 {
+        (*gen).emit_tal = true;
+    } else if strcmp(emit, c!("rom")) != 0 {
+        usage(params);
+        log(Log_Level::ERROR, c!("Invalid value of emit `%s`! Valid values: rom, tal"), emit);
+        return None;
+    }
+
     Some(gen as *mut c_void)
 }
 
 pub unsafe fn generate_program(
     gen: *mut c_void, program: *const Program, program_path: *const c_char, _garbage_base: *const c_char,
-    _nostdlib: bool, debug: bool,
+    _nostdlib: bool, debug: bool, stage: BuildStage, _objects: *const [*const c_char],
 ) -> Option<()> {
+    assert!(stage != BuildStage::Object, "The target does not support native object files");
     let gen = gen as *mut Uxn;
     let output = &mut (*gen).output;
 
-    if debug { todo!("Debug information for uxn") }
-
     let mut assembler: Assembler = zeroed();
     assembler.data_section_label = create_label(&mut assembler);
     // set the top of the stack
     write_lit2(output, 0xffff);
     write_lit_stz2(output, SP);
-    // call main or _start, _start having a priority
+    // call the function marked as the entry point, otherwise main or _start, _start having a priority
     let mut main_proc = c!("main");
     for i in 0..(*program).funcs.count {
         let name = (*(*program).funcs.items.add(i)).name;
@@ -243,6 +476,9 @@ pub unsafe fn generate_program(
             break;
         }
     }
+    if let Some(entry) = find_entry(da_slice((*program).attributes)) {
+        main_proc = entry;
+    }
     write_op(output, UxnOp::JSI);
     write_label_rel(output, get_or_create_label_by_name(&mut assembler, main_proc), &mut assembler, 0);
     // break out of the vector we were returned from
@@ -258,12 +494,42 @@ pub unsafe fn generate_program(
     generate_extrns(da_slice((*program).extrns), da_slice((*program).funcs), da_slice((*program).asm_funcs), da_slice((*program).globals))?;
     generate_data_section(output, da_slice((*program).data), &mut assembler);
     generate_globals(output, da_slice((*program).globals), &mut assembler);
+    if let Some(entry) = find_entry(da_slice((*program).attributes)) {
+        // libb still calls `main`, which is an alias of the entry point just like on the gas targets
+        let main_label = get_or_create_label_by_name(&mut assembler, c!("main"));
+        let entry_label = get_or_create_label_by_name(&mut assembler, entry);
+        *assembler.resolved_addresses.items.add(main_label) = *assembler.resolved_addresses.items.add(entry_label);
+    }
 
     apply_patches(output, &mut assembler)?;
+    // uxnasm does not write the trailing zeros either, so the ROM stays byte for byte the same as the one assembled
+    // from the Uxntal output. The memory past the end of the ROM is zeroed anyway.
+    while (*output).count > 0 && *(*output).items.add((*output).count - 1) == 0 {
+        (*output).count -= 1;
+    }
+
+    if stage == BuildStage::Assembly || (*gen).emit_tal {
+        let mut tal: String_Builder = zeroed();
+        generate_tal(&mut tal, da_slice(*output) as *const [u8], &mut assembler);
+        let tal_path = if stage == BuildStage::Assembly { program_path } else { temp_sprintf(c!("%s.tal"), program_path) };
+        let result = write_entire_file(tal_path, tal.items as *const c_void, tal.count);
+        free(tal.items);
+        result?;
+        log(Log_Level::INFO, c!("generated %s"), tal_path);
+        if stage == BuildStage::Assembly { return Some(()); }
+    }
 
     write_entire_file(program_path, (*output).items as *const c_void, (*output).count)?;
     log(Log_Level::INFO, c!("generated %s"), program_path);
 
+    if debug {
+        let symbols_path = temp_sprintf(c!("%s.sym"), program_path);
+        (*output).count = 0;
+        generate_symbols(output, &mut assembler);
+        write_entire_file(symbols_path, (*output).items as *const c_void, (*output).count)?;
+        log(Log_Level::INFO, c!("generated %s"), symbols_path);
+    }
+
     Some(())
 }
 
@@ -271,6 +537,14 @@ pub unsafe fn run_program(
     gen: *mut c_void, program_path: *const c_char, run_args: *const [*const c_char],
 ) -> Option<()> {
     let gen = gen as *mut Uxn;
+    if let Uxn_Runner::Builtin = (*gen).runner {
+        let code = vm::run(program_path, run_args)?;
+        if code != 0 {
+            log(Log_Level::ERROR, c!("%s exited with code %d"), program_path, code);
+            return None;
+        }
+        return Some(());
+    }
     let cmd = &mut (*gen).cmd;
     cmd_append! {cmd, (*gen).runner.name(), program_path}
     da_append_many(cmd, run_args);
@@ -286,6 +560,7 @@ pub unsafe fn generate_funcs(output: *mut String_Builder, funcs: *const [Func],
 }
 
 pub unsafe fn generate_function(name: *const c_char, name_loc: Loc, params_count: usize, auto_vars_count: usize, body: *const [OpWithLocation], output: *mut String_Builder, assembler: *mut Assembler) -> Option<()> {
+    (*assembler).scope = name;
     link_label(assembler, get_or_create_label_by_name(assembler, name), (*output).count);
 
     const MAX_ARGS: usize = (256 - FIRST_ARG as usize) / 2;
@@ -723,6 +998,16 @@ pub unsafe fn write_label_abs(output: *mut String_Builder, label: usize, a: *mut
     write_byte(output, 0xff);
 }
 
+pub unsafe fn write_label_byte(output: *mut String_Builder, kind: PatchKind, label: usize, a: *mut Assembler) {
+    da_append(&mut (*a).patches, Patch{
+        kind,
+        label: label,
+        addr: (*output).count as u16,
+        offset: 0,
+    });
+    write_byte(output, 0xff);
+}
+
 pub unsafe fn write_short(output: *mut String_Builder, short: u16) {
     write_byte(output, (short >> 8) as u8);
     write_byte(output, (short & 0xff) as u8);
@@ -1219,113 +1504,259 @@ pub unsafe fn has_immediate(op: UxnOp) -> bool {
 }
 
 pub unsafe fn process_asm_statements(output: *mut String_Builder, asm_stmts: *const [AsmStmt], assembler: *mut Assembler) -> Option<()> {
+    // The address of the next label while the zero page is laid out with `|` paddings below `|0100`
+    let mut zero_page: Option<u16> = None;
     for i in 0..asm_stmts.len() {
         let asm_stmt = (*asm_stmts)[i];
-        process_asm_statement(output, asm_stmt, assembler)?;
+        process_asm_statement(output, asm_stmt, assembler, &mut zero_page)?;
     }
     Some(())
 }
 
-pub unsafe fn process_asm_statement(output: *mut String_Builder, asm_stmt: AsmStmt, assembler: *mut Assembler) -> Option<()> {
-    // TODO: leaky function, but beware holding onto strings produced by the lexer
+/// Parses a hex number of Uxntal, which has no prefix and at most 4 digits. Returns the value and the amount of digits.
+unsafe fn parse_hex(s: *const c_char) -> Option<(u16, usize)> {
+    let len = strlen(s);
+    if len == 0 || len > 4 {
+        return None;
+    }
+    let mut value: u16 = 0;
+    for i in 0..len {
+        let x = *s.add(i) as u8;
+        let digit = match x {
+            b'0'..=b'9' => x - b'0',
+            b'a'..=b'f' => x - b'a' + 10,
+            b'A'..=b'F' => x - b'A' + 10,
+            _ => return None,
+        };
+        value = value << 4 | digit as u16;
+    }
+    Some((value, len))
+}
 
-    let mut lexer_name: String_Builder = zeroed();
-    sb_appendf(&mut lexer_name, c!("%s:%d:%d <asm>"), asm_stmt.loc.input_path, asm_stmt.loc.line_number, asm_stmt.loc.line_offset);
-    da_append(&mut lexer_name, 0);
-    let mut l = lexer::new(lexer_name.items, asm_stmt.line, asm_stmt.line.add(strlen(asm_stmt.line)), false);
-    let saved_point = l.parse_point;
-    lexer::get_token(&mut l)?;
-    match l.token {
-        Token::EOF => { /* Allow empty asm line, not sure if useful */ }
-        Token::ID => {
-            // label or opcode
-            lexer::get_token(&mut l)?;
-            match l.token {
-                Token::Colon => {
-                    // label
-                    link_label(assembler, get_or_create_label_by_name(assembler, l.string), (*output).count);
-                    lexer::get_token(&mut l)?;
-                    match l.token {
-                        Token::ID => { /* must be an an opcode */ }
-                        Token::EOF => { return Some(()); }
-                        _ => {
-                            diagf!(loc(&mut l), c!("ERROR: expected %s but got %s\n"),
-                                lexer::display_token(Token::ID),
-                                lexer::display_token(l.token));
-                            return None;
-                        }
-                    }
+/// Finds the label referenced in the inline assembly. `&name` is the sublabel `name` of the current scope.
+unsafe fn asm_label(assembler: *mut Assembler, name: *const c_char) -> usize {
+    let name = if *name == '&' as c_char {
+        temp_sprintf(c!("%s/%s"), (*assembler).scope, name.add(1))
+    } else {
+        name
+    };
+    get_or_create_label_by_name(assembler, strdup(name))
+}
+
+unsafe fn define_asm_label(assembler: *mut Assembler, name: *const c_char, addr: u16, loc: Loc) -> Option<()> {
+    let label = asm_label(assembler, name);
+    if *(*assembler).resolved_addresses.items.add(label) != 0 {
+        diagf!(loc, c!("ERROR: label `%s` is already defined\n"), name);
+        return None;
+    }
+    *(*assembler).resolved_addresses.items.add(label) = addr;
+    Some(())
+}
+
+/// Assembles a line of Uxntal. Besides the opcodes it supports the runes
+///   @label &sublabel            - define a label, `@` also starts the scope of the following `&sublabels`
+///   ;label .label ,label        - push the absolute, the zero page or the relative address of the label
+///   =label -label               - the raw absolute or zero page address of the label
+///   !label ?label label         - JMI, JCI and JSI to the label
+///   #12 #1234 12 1234           - the literal or the raw hex byte and short
+///   "text                       - the raw ASCII bytes of the word
+///   |1234 $12                   - the absolute and the relative padding
+///   ( comment ) [ ]             - the comments and the brackets that are ignored
+/// Any B function or global is a label. The paddings below `|0100` lay out the labels of the zero page till the end
+/// of the `__asm__`. For the backward compatibility `label:` defines a label and the opcodes with immediates (`LIT`,
+/// `LIT2`, `JCI`, etc) take it in the B syntax as the next word, like `lit 'B'` or `jmi label`.
+pub unsafe fn process_asm_statement(output: *mut String_Builder, asm_stmt: AsmStmt, assembler: *mut Assembler, zero_page: *mut Option<u16>) -> Option<()> {
+    let line = asm_stmt.line;
+    let mut comment = 0;
+    let mut i = 0;
+    loop {
+        while isspace(*line.add(i) as c_int) != 0 { i += 1; }
+        if *line.add(i) == 0 { break; }
+        let start = i;
+        while *line.add(i) != 0 && isspace(*line.add(i) as c_int) == 0 { i += 1; }
+        let word = temp_sprintf(c!("%.*s"), (i - start) as c_int, line.add(start));
+        let len = i - start;
+        let rest = word.add(1);
+        let mut loc = asm_stmt.loc;
+        loc.line_offset += start as c_int + 1;
+        loc.span = len as c_int;
+
+        if strcmp(word, c!("(")) == 0 {
+            comment += 1;
+            continue;
+        }
+        if comment > 0 {
+            if strcmp(word, c!(")")) == 0 {
+                comment -= 1;
+            }
+            continue;
+        }
+        if strcmp(word, c!("[")) == 0 || strcmp(word, c!("]")) == 0 {
+            continue;
+        }
+
+        let rune = *word as u8;
+        let label_definition = rune == b'@' || rune == b'&' || *word.add(len - 1) == ':' as c_char;
+        if let Some(addr) = *zero_page {
+            match rune {
+                b'@' => {
+                    define_asm_label(assembler, rest, addr.wrapping_sub(0x100), loc)?;
+                    (*assembler).scope = strdup(rest);
+                    continue;
                 }
+                b'&' => {
+                    define_asm_label(assembler, word, addr.wrapping_sub(0x100), loc)?;
+                    continue;
+                }
+                b'|' | b'$' => {}
                 _ => {
-                    l.parse_point = saved_point;
-                    lexer::get_token(&mut l)?;
+                    diagf!(loc, c!("ERROR: the zero page may contain only labels and paddings\n"));
+                    diagf!(loc, c!("NOTE: the zero page is laid out till the end of the `__asm__`\n"));
+                    return None;
                 }
             }
         }
-        _ => {
-            diagf!(loc(&mut l), c!("ERROR: expected %s but got %s\n"),
-                lexer::display_token(Token::ID), lexer::display_token(l.token));
-            return None;
-        }
-    }
-    // must be an opcode
-    if let Some(opcode) = find_opcode_by_name(l.string) {
-        write_op(output, opcode);
-        lexer::get_token(&mut l)?;
-        if has_immediate(opcode) {
-            match l.token {
-                Token::ID => {
-                    // must be a label, only valid for short opcodes
-                    if has_short_immediate(opcode) {
-                        let label = get_or_create_label_by_name(assembler, l.string);
-                        if has_relative_immediate(opcode) {
-                            write_label_rel(output, label, assembler, 0);
-                        } else {
-                            write_label_abs(output, label, assembler, 0);
-                        }
-                    } else {
-                        diagf!(loc(&mut l), c!("ERROR: label is not a valid short immediate\n"));
+        let here = (*output).count as u16;
+        match rune {
+            b'@' => {
+                define_asm_label(assembler, rest, here, loc)?;
+                (*assembler).scope = strdup(rest);
+            }
+            b'&' => define_asm_label(assembler, word, here, loc)?,
+            b'|' | b'$' => {
+                let Some((value, _)) = parse_hex(rest) else {
+                    diagf!(loc, c!("ERROR: invalid padding `%s`, expected a hex number\n"), word);
+                    return None;
+                };
+                let current = match *zero_page {
+                    Some(addr) => addr as usize,
+                    None => here as usize + 0x100,
+                };
+                let target = if rune == b'|' { value as usize } else { current + value as usize };
+                if (*zero_page).is_some() {
+                    if target > 0x100 {
+                        diagf!(loc, c!("ERROR: can't pad to %04X, the zero page ends at 00FF\n"), target as c_uint);
                         return None;
                     }
-                }
-                Token::IntLit | Token::CharLit => {
-                    // immediate number literal
-                    if has_short_immediate(opcode) {
-                        write_short(output, l.int_number as u16);
-                    } else {
-                        write_byte(output, l.int_number as u8);
+                    *zero_page = Some(target as u16);
+                } else if target < 0x100 {
+                    *zero_page = Some(target as u16);
+                } else {
+                    if target < current || target > 0x10000 {
+                        diagf!(loc, c!("ERROR: can't pad to %04X, the code is already at %04X\n"), target as c_uint, current as c_uint);
+                        return None;
+                    }
+                    for _ in current..target {
+                        write_byte(output, 0);
                     }
                 }
+            }
+            b'#' => match parse_hex(rest) {
+                Some((value, 2)) => write_lit(output, value as u8),
+                Some((value, 4)) => write_lit2(output, value),
                 _ => {
-                    diagf!(loc(&mut l), c!("ERROR: expected %s, %s, or %s but got %s\n"),
-                        lexer::display_token(Token::ID),
-                        lexer::display_token(Token::IntLit),
-                        lexer::display_token(Token::CharLit),
-                        lexer::display_token(l.token));
+                    diagf!(loc, c!("ERROR: invalid literal `%s`, expected 2 or 4 hex digits\n"), word);
                     return None;
                 }
+            },
+            b';' => {
+                write_op(output, UxnOp::LIT2);
+                write_label_abs(output, asm_label(assembler, rest), assembler, 0);
             }
-        } else {
-            match l.token {
-                Token::EOF => { return Some(()); }
-                _ => {
-                    diagf!(loc(&mut l), c!("ERROR: expected end of the line but got %s\n"),
-                        lexer::display_token(l.token));
-                    return None;
+            b'.' => {
+                write_op(output, UxnOp::LIT);
+                write_label_byte(output, PatchKind::LowerAbsolute, asm_label(assembler, rest), assembler);
+            }
+            b',' => {
+                write_op(output, UxnOp::LIT);
+                write_label_byte(output, PatchKind::ByteRelative, asm_label(assembler, rest), assembler);
+            }
+            b'=' => write_label_abs(output, asm_label(assembler, rest), assembler, 0),
+            b'-' => write_label_byte(output, PatchKind::LowerAbsolute, asm_label(assembler, rest), assembler),
+            b'!' | b'?' => {
+                write_op(output, if rune == b'!' { UxnOp::JMI } else { UxnOp::JCI });
+                write_label_rel(output, asm_label(assembler, rest), assembler, 0);
+            }
+            b'"' => {
+                for j in 0..len - 1 {
+                    write_byte(output, *rest.add(j) as u8);
+                }
+            }
+            _ if label_definition => {
+                *word.add(len - 1) = 0;
+                define_asm_label(assembler, word, here, loc)?;
+            }
+            _ => if let Some(opcode) = find_opcode_by_name(word) {
+                write_op(output, opcode);
+                if has_immediate(opcode) {
+                    i = process_asm_immediate(output, asm_stmt, i, opcode, assembler)?;
+                }
+            } else if let Some((value, digits @ (2 | 4))) = parse_hex(word) {
+                if digits == 2 {
+                    write_byte(output, value as u8);
+                } else {
+                    write_short(output, value);
                 }
+            } else if isalpha(rune as c_int) != 0 || rune == b'_' {
+                write_op(output, UxnOp::JSI);
+                write_label_rel(output, asm_label(assembler, word), assembler, 0);
+            } else {
+                diagf!(loc, c!("ERROR: invalid uxn opcode: %s\n"), word);
+                return None;
             }
         }
-    } else {
-        diagf!(loc(&mut l), c!("ERROR: invalid uxn opcode: %s\n"), l.string);
+    }
+    if comment > 0 {
+        diagf!(asm_stmt.loc, c!("ERROR: unclosed comment\n"));
         return None;
     }
+    Some(())
+}
+
+/// Parses the immediate that follows an opcode at the position `i` of the line in the B syntax: an integer, a character
+/// or a label. Returns the position after it.
+unsafe fn process_asm_immediate(output: *mut String_Builder, asm_stmt: AsmStmt, i: usize, opcode: UxnOp, assembler: *mut Assembler) -> Option<usize> {
+    // TODO: leaky function, but beware holding onto strings produced by the lexer
+    let mut lexer_name: String_Builder = zeroed();
+    sb_appendf(&mut lexer_name, c!("%s:%d:%d <asm>"), asm_stmt.loc.input_path, asm_stmt.loc.line_number, asm_stmt.loc.line_offset);
+    da_append(&mut lexer_name, 0);
+    let begin = asm_stmt.line.add(i);
+    let mut l = lexer::new(lexer_name.items, begin, begin.add(strlen(begin)), false);
     lexer::get_token(&mut l)?;
     match l.token {
-        Token::EOF => { return Some(()); }
+        Token::ID => {
+            // must be a label, only valid for short opcodes
+            if has_short_immediate(opcode) {
+                let label = asm_label(assembler, l.string);
+                if has_relative_immediate(opcode) {
+                    write_label_rel(output, label, assembler, 0);
+                } else {
+                    write_label_abs(output, label, assembler, 0);
+                }
+            } else {
+                diagf!(loc(&mut l), c!("ERROR: label is not a valid short immediate\n"));
+                return None;
+            }
+        }
+        Token::IntLit | Token::CharLit => {
+            // immediate number literal
+            if has_short_immediate(opcode) {
+                write_short(output, l.int_number as u16);
+            } else {
+                write_byte(output, l.int_number as u8);
+            }
+        }
         _ => {
-            diagf!(loc(&mut l), c!("ERROR: expected nothing but got %s\n"),
+            diagf!(loc(&mut l), c!("ERROR: expected %s, %s, or %s but got %s\n"),
+                lexer::display_token(Token::ID),
+                lexer::display_token(Token::IntLit),
+                lexer::display_token(Token::CharLit),
                 lexer::display_token(l.token));
             return None;
         }
     }
+    if l.error_count > 0 {
+        return None;
+    }
+    Some(i + (l.parse_point.current as usize - begin as usize))
 }
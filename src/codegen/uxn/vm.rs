@@ -0,0 +1,429 @@
+//! Headless Uxn virtual machine with the Varvara System, Console and File devices.
+//!
+//! It is the `builtin` runner of the uxn target, so the uxn programs (and the tests in particular) can be run
+//! without the emulators from the upstream. It behaves like uxncli: the program is started at the reset vector,
+//! then the command line arguments and the stdin are fed into the Console vector until the program sets
+//! System/state.
+//!
+//! See https://wiki.xxiivv.com/site/uxntal_opcodes.html and https://wiki.xxiivv.com/site/varvara.html
+
+use core::ffi::*;
+use core::mem::zeroed;
+use core::ptr;
+use crate::nob::*;
+use crate::crust::libc::*;
+
+const PAGE_PROGRAM: u16 = 0x0100;
+
+const SYSTEM_DEBUG: u8 = 0x0e;
+const SYSTEM_STATE: u8 = 0x0f;
+const CONSOLE_VECTOR: u8 = 0x10;
+const CONSOLE_READ: u8 = 0x12;
+const CONSOLE_TYPE: u8 = 0x17;
+const CONSOLE_WRITE: u8 = 0x18;
+const CONSOLE_ERROR: u8 = 0x19;
+const FILE0: u8 = 0xa0;
+const FILE1: u8 = 0xb0;
+
+// Values of Console/type
+const CONSOLE_STD: u8 = 1;
+const CONSOLE_ARG: u8 = 2;
+const CONSOLE_EOA: u8 = 3;
+const CONSOLE_END: u8 = 4;
+
+// Ports of a File device relative to its base
+const FILE_SUCCESS: u8 = 0x2;
+const FILE_STAT: u8 = 0x5;
+const FILE_DELETE: u8 = 0x6;
+const FILE_APPEND: u8 = 0x7;
+const FILE_NAME: u8 = 0x9;
+const FILE_LENGTH: u8 = 0xa;
+const FILE_READ: u8 = 0xd;
+const FILE_WRITE: u8 = 0xf;
+
+#[derive(Clone, Copy)]
+pub struct Stack {
+    pub dat: [u8; 0x100],
+    pub ptr: u8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum File_Mode {
+    Closed,
+    Reading,
+    Writing,
+}
+
+#[derive(Clone, Copy)]
+pub struct File_Device {
+    pub file: *mut FILE,
+    pub mode: File_Mode,
+}
+
+pub struct Uxn_Vm {
+    pub ram: [u8; 0x10000],
+    pub wst: Stack,
+    pub rst: Stack,
+    pub dev: [u8; 0x100],
+    pub files: [File_Device; 2],
+}
+
+unsafe fn pop_value(s: *mut Stack, sp: *mut u8, short: bool) -> u16 {
+    *sp = (*sp).wrapping_sub(1);
+    let lo = (*s).dat[*sp as usize] as u16;
+    if !short { return lo; }
+    *sp = (*sp).wrapping_sub(1);
+    let hi = (*s).dat[*sp as usize] as u16;
+    hi << 8 | lo
+}
+
+unsafe fn push_value(s: *mut Stack, value: u16, short: bool) {
+    if short {
+        (*s).dat[(*s).ptr as usize] = (value >> 8) as u8;
+        (*s).ptr = (*s).ptr.wrapping_add(1);
+    }
+    (*s).dat[(*s).ptr as usize] = value as u8;
+    (*s).ptr = (*s).ptr.wrapping_add(1);
+}
+
+unsafe fn peek(u: *mut Uxn_Vm, addr: u16, short: bool) -> u16 {
+    if short {
+        ((*u).ram[addr as usize] as u16) << 8 | (*u).ram[addr.wrapping_add(1) as usize] as u16
+    } else {
+        (*u).ram[addr as usize] as u16
+    }
+}
+
+unsafe fn poke(u: *mut Uxn_Vm, addr: u16, value: u16, short: bool) {
+    if short {
+        (*u).ram[addr as usize] = (value >> 8) as u8;
+        (*u).ram[addr.wrapping_add(1) as usize] = value as u8;
+    } else {
+        (*u).ram[addr as usize] = value as u8;
+    }
+}
+
+unsafe fn dev_peek2(u: *mut Uxn_Vm, port: u8) -> u16 {
+    ((*u).dev[port as usize] as u16) << 8 | (*u).dev[port.wrapping_add(1) as usize] as u16
+}
+
+unsafe fn dev_poke2(u: *mut Uxn_Vm, port: u8, value: u16) {
+    (*u).dev[port as usize] = (value >> 8) as u8;
+    (*u).dev[port.wrapping_add(1) as usize] = value as u8;
+}
+
+unsafe fn print_stack(name: *const c_char, s: *const Stack) {
+    fprintf(stderr(), c!("%s "), name);
+    for i in 0..(*s).ptr {
+        fprintf(stderr(), c!("%02x "), (*s).dat[i as usize] as c_uint);
+    }
+    fprintf(stderr(), c!("\n"));
+}
+
+/// Copies the NUL-terminated string from the memory of the VM. The result must be free()d.
+/// It's not in the temporary memory, since a program may do any amount of device calls.
+unsafe fn ram_string(u: *mut Uxn_Vm, addr: u16) -> *mut c_char {
+    let mut sb: String_Builder = zeroed();
+    let mut addr = addr as usize;
+    while addr < (*u).ram.len() && (*u).ram[addr] != 0 {
+        da_append(&mut sb, (*u).ram[addr] as c_char);
+        addr += 1;
+    }
+    da_append(&mut sb, 0);
+    sb.items
+}
+
+unsafe fn file_close(f: *mut File_Device) {
+    if !(*f).file.is_null() {
+        fclose((*f).file);
+    }
+    (*f).file = ptr::null_mut();
+    (*f).mode = File_Mode::Closed;
+}
+
+unsafe fn file_deo(u: *mut Uxn_Vm, f: *mut File_Device, base: u8, port: u8) {
+    let name_addr = dev_peek2(u, base + FILE_NAME - 1);
+    let length = dev_peek2(u, base + FILE_LENGTH) as usize;
+    match port {
+        FILE_NAME => file_close(f),
+        FILE_STAT => {
+            let addr = dev_peek2(u, base + FILE_STAT - 1) as usize;
+            let length = length.min((*u).ram.len() - addr);
+            // The size in hex padded to the length, `!` if the file does not exist, `-` if it's a directory
+            // and `?` if the size does not fit
+            let mut fill = '!' as u8;
+            let mut size = 0;
+            let name = ram_string(u, name_addr);
+            match get_file_type(name) {
+                Some(File_Type::DIRECTORY) => fill = '-' as u8,
+                Some(_) => {
+                    let mut content: String_Builder = zeroed();
+                    if read_entire_file(name, &mut content).is_some() {
+                        fill = '0' as u8;
+                        size = content.count;
+                        if length < 16 && size >> (length*4) != 0 {
+                            fill = '?' as u8;
+                        }
+                    }
+                    free(content.items);
+                }
+                None => {}
+            }
+            free(name as *mut c_void);
+            for i in 0..length {
+                (*u).ram[addr + i] = fill;
+            }
+            if fill == '0' as u8 {
+                for i in 0..length {
+                    let digit = ((size >> (i*4)) & 0xf) as u8;
+                    (*u).ram[addr + length - 1 - i] = if digit < 10 { '0' as u8 + digit } else { 'a' as u8 + digit - 10 };
+                }
+            }
+            dev_poke2(u, base + FILE_SUCCESS, length as u16);
+        }
+        FILE_DELETE => {
+            file_close(f);
+            let name = ram_string(u, name_addr);
+            dev_poke2(u, base + FILE_SUCCESS, delete_file(name) as u16);
+            free(name as *mut c_void);
+        }
+        FILE_READ => {
+            let addr = dev_peek2(u, base + FILE_READ - 1) as usize;
+            if (*f).mode != File_Mode::Reading {
+                file_close(f);
+                let name = ram_string(u, name_addr);
+                if matches!(get_file_type(name), Some(File_Type::REGULAR)) {
+                    (*f).file = fopen(name, c!("rb"));
+                }
+                free(name as *mut c_void);
+                if !(*f).file.is_null() {
+                    (*f).mode = File_Mode::Reading;
+                }
+            }
+            let mut n = 0;
+            if !(*f).file.is_null() {
+                n = fread((*u).ram.as_mut_ptr().add(addr) as *mut c_void, 1, length.min((*u).ram.len() - addr), (*f).file);
+            }
+            dev_poke2(u, base + FILE_SUCCESS, n as u16);
+        }
+        FILE_WRITE => {
+            let addr = dev_peek2(u, base + FILE_WRITE - 1) as usize;
+            if (*f).mode != File_Mode::Writing {
+                file_close(f);
+                let append = (*u).dev[(base + FILE_APPEND) as usize] != 0;
+                let name = ram_string(u, name_addr);
+                (*f).file = fopen(name, if append { c!("ab") } else { c!("wb") });
+                free(name as *mut c_void);
+                if !(*f).file.is_null() {
+                    (*f).mode = File_Mode::Writing;
+                }
+            }
+            let mut n = 0;
+            if !(*f).file.is_null() {
+                n = fwrite((*u).ram.as_ptr().add(addr) as *const c_void, 1, length.min((*u).ram.len() - addr), (*f).file);
+                fflush((*f).file);
+            }
+            dev_poke2(u, base + FILE_SUCCESS, n as u16);
+        }
+        _ => {}
+    }
+}
+
+unsafe fn dei(u: *mut Uxn_Vm, port: u8) -> u8 {
+    match port {
+        0x04 => (*u).wst.ptr,
+        0x05 => (*u).rst.ptr,
+        _    => (*u).dev[port as usize],
+    }
+}
+
+unsafe fn deo(u: *mut Uxn_Vm, port: u8) {
+    let value = (*u).dev[port as usize];
+    match port {
+        0x04 => (*u).wst.ptr = value,
+        0x05 => (*u).rst.ptr = value,
+        SYSTEM_DEBUG => if value != 0 {
+            print_stack(c!("WST"), &(*u).wst);
+            print_stack(c!("RST"), &(*u).rst);
+        }
+        CONSOLE_WRITE => { fwrite(&value as *const u8 as *const c_void, 1, 1, stdout()); }
+        CONSOLE_ERROR => { fwrite(&value as *const u8 as *const c_void, 1, 1, stderr()); }
+        _ => if port & 0xf0 == FILE0 {
+            file_deo(u, &mut (*u).files[0], FILE0, port & 0x0f);
+        } else if port & 0xf0 == FILE1 {
+            file_deo(u, &mut (*u).files[1], FILE1, port & 0x0f);
+        }
+    }
+}
+
+/// Evaluates the vector at `pc` until BRK. Returns false if the program halted by setting System/state.
+pub unsafe fn eval(u: *mut Uxn_Vm, mut pc: u16) -> bool {
+    if pc == 0 || (*u).dev[SYSTEM_STATE as usize] != 0 { return false; }
+    loop {
+        let ins = (*u).ram[pc as usize];
+        pc = pc.wrapping_add(1);
+        let short = ins & 0x20 != 0;
+        let (s, other): (*mut Stack, *mut Stack) = if ins & 0x40 != 0 {
+            (&mut (*u).rst, &mut (*u).wst)
+        } else {
+            (&mut (*u).wst, &mut (*u).rst)
+        };
+        // In the keep mode the operands are read without being popped
+        let mut sp = (*s).ptr;
+        let keep = ins & 0x80 != 0;
+        macro_rules! pop  { () => { pop_value(s, &mut sp, short) } }
+        macro_rules! pop8 { () => { pop_value(s, &mut sp, false) } }
+        macro_rules! commit { () => { if !keep { (*s).ptr = sp; } } }
+
+        match ins & 0x1f {
+            0x00 => match ins {
+                0x00 /* BRK */ => return true,
+                0x20 /* JCI */ => {
+                    let wst: *mut Stack = &mut (*u).wst;
+                    let cond = pop_value(wst, &mut (*wst).ptr, false);
+                    if cond != 0 {
+                        pc = pc.wrapping_add(peek(u, pc, true)).wrapping_add(2);
+                    } else {
+                        pc = pc.wrapping_add(2);
+                    }
+                }
+                0x40 /* JMI */ => pc = pc.wrapping_add(peek(u, pc, true)).wrapping_add(2),
+                0x60 /* JSI */ => {
+                    push_value(&mut (*u).rst, pc.wrapping_add(2), true);
+                    pc = pc.wrapping_add(peek(u, pc, true)).wrapping_add(2);
+                }
+                _ /* LIT */ => {
+                    push_value(s, peek(u, pc, short), short);
+                    pc = pc.wrapping_add(if short { 2 } else { 1 });
+                }
+            },
+            0x01 /* INC */ => { let a = pop!(); commit!(); push_value(s, a.wrapping_add(1), short); }
+            0x02 /* POP */ => { pop!(); commit!(); }
+            0x03 /* NIP */ => { let b = pop!(); pop!(); commit!(); push_value(s, b, short); }
+            0x04 /* SWP */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, b, short); push_value(s, a, short); }
+            0x05 /* ROT */ => {
+                let c = pop!(); let b = pop!(); let a = pop!(); commit!();
+                push_value(s, b, short); push_value(s, c, short); push_value(s, a, short);
+            }
+            0x06 /* DUP */ => { let a = pop!(); commit!(); push_value(s, a, short); push_value(s, a, short); }
+            0x07 /* OVR */ => {
+                let b = pop!(); let a = pop!(); commit!();
+                push_value(s, a, short); push_value(s, b, short); push_value(s, a, short);
+            }
+            0x08 /* EQU */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, (a == b) as u16, false); }
+            0x09 /* NEQ */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, (a != b) as u16, false); }
+            0x0a /* GTH */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, (a >  b) as u16, false); }
+            0x0b /* LTH */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, (a <  b) as u16, false); }
+            0x0c /* JMP */ => {
+                let addr = pop!(); commit!();
+                pc = if short { addr } else { pc.wrapping_add(addr as u8 as i8 as u16) };
+            }
+            0x0d /* JCN */ => {
+                let addr = pop!(); let cond = pop8!(); commit!();
+                if cond != 0 {
+                    pc = if short { addr } else { pc.wrapping_add(addr as u8 as i8 as u16) };
+                }
+            }
+            0x0e /* JSR */ => {
+                let addr = pop!(); commit!();
+                push_value(other, pc, true);
+                pc = if short { addr } else { pc.wrapping_add(addr as u8 as i8 as u16) };
+            }
+            0x0f /* STH */ => { let a = pop!(); commit!(); push_value(other, a, short); }
+            0x10 /* LDZ */ => { let addr = pop8!(); commit!(); push_value(s, peek(u, addr, short), short); }
+            0x11 /* STZ */ => { let addr = pop8!(); let value = pop!(); commit!(); poke(u, addr, value, short); }
+            0x12 /* LDR */ => {
+                let rel = pop8!(); commit!();
+                push_value(s, peek(u, pc.wrapping_add(rel as u8 as i8 as u16), short), short);
+            }
+            0x13 /* STR */ => {
+                let rel = pop8!(); let value = pop!(); commit!();
+                poke(u, pc.wrapping_add(rel as u8 as i8 as u16), value, short);
+            }
+            0x14 /* LDA */ => { let addr = pop_value(s, &mut sp, true); commit!(); push_value(s, peek(u, addr, short), short); }
+            0x15 /* STA */ => { let addr = pop_value(s, &mut sp, true); let value = pop!(); commit!(); poke(u, addr, value, short); }
+            0x16 /* DEI */ => {
+                let port = pop8!() as u8; commit!();
+                if short {
+                    push_value(s, (dei(u, port) as u16) << 8 | dei(u, port.wrapping_add(1)) as u16, true);
+                } else {
+                    push_value(s, dei(u, port) as u16, false);
+                }
+            }
+            0x17 /* DEO */ => {
+                let port = pop8!() as u8; let value = pop!(); commit!();
+                if short {
+                    dev_poke2(u, port, value);
+                    deo(u, port);
+                    deo(u, port.wrapping_add(1));
+                } else {
+                    (*u).dev[port as usize] = value as u8;
+                    deo(u, port);
+                }
+                if (*u).dev[SYSTEM_STATE as usize] != 0 { return false; }
+            }
+            0x18 /* ADD */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a.wrapping_add(b), short); }
+            0x19 /* SUB */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a.wrapping_sub(b), short); }
+            0x1a /* MUL */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a.wrapping_mul(b), short); }
+            0x1b /* DIV */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, if b == 0 { 0 } else { a/b }, short); }
+            0x1c /* AND */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a & b, short); }
+            0x1d /* ORA */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a | b, short); }
+            0x1e /* EOR */ => { let b = pop!(); let a = pop!(); commit!(); push_value(s, a ^ b, short); }
+            0x1f /* SFT */ => {
+                let shift = pop8!(); let a = pop!(); commit!();
+                push_value(s, (a >> (shift & 0x0f)) << (shift >> 4), short);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+unsafe fn console_input(u: *mut Uxn_Vm, c: u8, typ: u8) -> bool {
+    (*u).dev[CONSOLE_READ as usize] = c;
+    (*u).dev[CONSOLE_TYPE as usize] = typ;
+    eval(u, dev_peek2(u, CONSOLE_VECTOR))
+}
+
+/// Runs the ROM like uxncli does and returns the exit code of the program
+pub unsafe fn run(rom_path: *const c_char, args: *const [*const c_char]) -> Option<c_int> {
+    let mut rom: String_Builder = zeroed();
+    read_entire_file(rom_path, &mut rom)?;
+    if rom.count > 0x10000 - PAGE_PROGRAM as usize {
+        log(Log_Level::ERROR, c!("%s: the rom is too big for Uxn (%zu bytes)"), rom_path, rom.count);
+        free(rom.items);
+        return None;
+    }
+
+    let u = realloc_items(ptr::null_mut::<Uxn_Vm>(), 1);
+    memset(u as *mut c_void, 0, size_of::<Uxn_Vm>());
+    ptr::copy_nonoverlapping(rom.items as *const u8, (*u).ram.as_mut_ptr().add(PAGE_PROGRAM as usize), rom.count);
+    free(rom.items);
+
+    (*u).dev[CONSOLE_TYPE as usize] = args.len().min(0xff) as u8;
+    if eval(u, PAGE_PROGRAM) {
+        for i in 0..args.len() {
+            let arg = (*args)[i];
+            for j in 0..strlen(arg) {
+                console_input(u, *arg.add(j) as u8, CONSOLE_ARG);
+            }
+            console_input(u, '\n' as u8, if i + 1 == args.len() { CONSOLE_END } else { CONSOLE_EOA });
+        }
+        // Unlike uxncli we don't wait for the stdin if nobody listens to it
+        while (*u).dev[SYSTEM_STATE as usize] == 0 && dev_peek2(u, CONSOLE_VECTOR) != 0 {
+            let c = fgetc(stdin());
+            if c < 0 {
+                console_input(u, 0, CONSOLE_END);
+                break;
+            }
+            console_input(u, c as u8, CONSOLE_STD);
+        }
+    }
+    fflush(stdout());
+
+    for i in 0..(*u).files.len() {
+        file_close(&mut (*u).files[i]);
+    }
+    let code = ((*u).dev[SYSTEM_STATE as usize] & 0x7f) as c_int;
+    free(u);
+    Some(code)
+}
@@ -93,12 +93,15 @@ pub mod libc {
         pub fn stderr() -> *mut FILE;
         pub fn fopen(pathname: *const c_char, mode: *const c_char) -> *mut FILE;
         pub fn fclose(stream: *mut FILE) -> c_int;
+        pub fn fflush(stream: *mut FILE) -> c_int;
         pub fn strcmp(s1: *const c_char, s2: *const c_char) -> c_int;
         pub fn strchr(s: *const c_char, c: c_int) -> *const c_char;
         pub fn strrchr(s: *const c_char, c: c_int) -> *const c_char;
         pub fn strlen(s: *const c_char) -> usize;
         pub fn strtoull(nptr: *const c_char, endptr: *mut*mut c_char, base: c_int) -> c_ulonglong;
         pub fn fwrite(ptr: *const c_void, size: usize, nmemb: usize, stream: *mut FILE) -> usize;
+        pub fn fread(ptr: *mut c_void, size: usize, nmemb: usize, stream: *mut FILE) -> usize;
+        pub fn fgetc(stream: *mut FILE) -> c_int;
 
         pub fn abort() -> !;
         pub fn strdup(s: *const c_char) -> *mut c_char;
@@ -115,6 +118,15 @@ pub mod libc {
         pub fn toupper(c: c_int) -> c_int;
         pub fn qsort(base: *mut c_void, nmemb: usize, size: usize, compar: unsafe extern "C" fn(*const c_void, *const c_void) -> c_int);
         pub fn dirname(path: *const c_char) -> *const c_char;
+        pub fn strncmp(s1: *const c_char, s2: *const c_char, n: usize) -> c_int;
+        pub fn snprintf(str: *mut c_char, size: usize, fmt: *const c_char, ...) -> c_int;
+        pub fn getenv(name: *const c_char) -> *const c_char;
+        #[link_name = "get_isatty"]
+        pub fn isatty(fd: c_int) -> c_int;
+        #[link_name = "get_fileno"]
+        pub fn fileno(stream: *mut FILE) -> c_int;
+        #[link_name = "get_realpath"]
+        pub fn realpath(path: *const c_char) -> *mut c_char;
     }
 
     // count is the amount of items, not bytes
@@ -0,0 +1,60 @@
+//! Constants of the DWARF debug information format shared by the gas codegens.
+//! See https://dwarfstd.org/doc/DWARF5.pdf
+
+// arbitrary constants
+pub const TEMPLATE_compilation_unit : u64 = 1;
+pub const TEMPLATE_function         : u64 = 2;
+pub const TEMPLATE_variable         : u64 = 3;
+pub const TEMPLATE_block            : u64 = 4;
+pub const TEMPLATE_type             : u64 = 5;
+
+// other constants
+pub const version                   : u64 = 5;
+pub const addr_size                 : u64 = 8;
+pub const default_type_size         : u64 = 8;
+
+// taken from dwarf.h, DW_ prefix stripped
+pub const CHILDREN_no               : u64 = 0;
+pub const CHILDREN_yes              : u64 = 1;
+
+pub const AT_location               : u64 = 0x02;
+pub const AT_name                   : u64 = 0x03;
+pub const AT_byte_size              : u64 = 0x0b;
+pub const AT_stmt_list              : u64 = 0x10;
+pub const AT_low_pc                 : u64 = 0x11;
+pub const AT_high_pc                : u64 = 0x12;
+pub const AT_encoding               : u64 = 0x3e;
+pub const AT_frame_base             : u64 = 0x40;
+pub const AT_type                   : u64 = 0x49;
+
+pub const DW_ATE_signed             : u64 = 0x05;
+
+pub const FORM_addr                 : u64 = 0x01;
+pub const FORM_string               : u64 = 0x08;
+pub const FORM_data1                : u64 = 0x0b;
+pub const FORM_ref4                 : u64 = 0x13;
+pub const FORM_sec_offset           : u64 = 0x17;
+pub const FORM_exprloc              : u64 = 0x18;
+
+pub const OP_addr                   : u64 = 0x03;
+pub const OP_fbreg                  : u64 = 0x91;
+pub const OP_call_frame_cfa         : u64 = 0x9c;
+
+pub const UT_compile                : u64 = 0x01;
+pub const TAG_lexical_block         : u64 = 0x0b;
+pub const TAG_compile_unit          : u64 = 0x11;
+pub const TAG_base_type             : u64 = 0x24;
+pub const TAG_subprogram            : u64 = 0x2e;
+pub const TAG_variable              : u64 = 0x34;
+
+pub unsafe fn sleb128_length(mut n: i64) -> u64 {
+    if n == 0 { return 1 }
+
+    let mut len = 0;
+    n <<= 1;
+    while n != 0 && n != -1 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
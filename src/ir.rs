@@ -1,6 +1,10 @@
 use core::ffi::*;
+use core::ptr;
+use core::mem::zeroed;
 use crate::lexer::*;
 use crate::nob::*;
+use crate::crust::libc::*;
+use crate::crust::assoc_lookup_cstr;
 
 #[derive(Clone, Copy)]
 pub enum Arg {
@@ -76,13 +80,90 @@ pub enum Op {
     Return         {arg: Option<Arg>},
 }
 
-// TODO: instead of the variadics we should have a general purpose
-// mechanism that adds attributes to to functions and passes them to
-// codegens
+enum_with_order! {
+    #[derive(Clone, Copy, PartialEq)]
+    enum AttributeKind in ATTRIBUTE_KIND_ORDER {
+        Variadic,
+        NoReturn,
+        Naked,
+        Weak,
+        Section,
+        Entry,
+    }
+}
+
+impl AttributeKind {
+    pub unsafe fn name(self) -> *const c_char {
+        match self {
+            Self::Variadic => c!("variadic"),
+            Self::NoReturn => c!("noreturn"),
+            Self::Naked    => c!("naked"),
+            Self::Weak     => c!("weak"),
+            Self::Section  => c!("section"),
+            Self::Entry    => c!("entry"),
+        }
+    }
+
+    pub unsafe fn from_name(name: *const c_char) -> Option<Self> {
+        for i in 0..ATTRIBUTE_KIND_ORDER.len() {
+            let kind = (*ATTRIBUTE_KIND_ORDER)[i];
+            if strcmp(kind.name(), name) == 0 {
+                return Some(kind)
+            }
+        }
+        None
+    }
+}
+
 #[derive(Clone, Copy)]
-pub struct Variadic {
+pub enum AttributeValue {
+    None,
+    Int(u64),
+    String(*const c_char),
+}
+
+/// Attribute attached to a function (or anything else with a name) with the
+/// `__attr__(name, key, value...)` top-level form.
+///
+/// The attributes are passed to the codegens which may use them to generate
+/// a slightly different code (see docs/bext.md for the list of the attributes).
+#[derive(Clone, Copy)]
+pub struct Attribute {
+    pub kind: AttributeKind,
+    pub value: AttributeValue,
     pub loc: Loc,
-    pub fixed_args: usize,
+}
+
+pub type Attributes = Array<(*const c_char, Array<Attribute>)>;
+
+pub unsafe fn find_attribute(attributes: *const [(*const c_char, Array<Attribute>)], name: *const c_char, kind: AttributeKind) -> Option<Attribute> {
+    let attrs = assoc_lookup_cstr(attributes, name)?;
+    for i in 0..(*attrs).count {
+        let attr = *(*attrs).items.add(i);
+        if attr.kind == kind {
+            return Some(attr)
+        }
+    }
+    None
+}
+
+/// Returns the number of fixed arguments if the function was declared variadic
+pub unsafe fn find_variadic(attributes: *const [(*const c_char, Array<Attribute>)], name: *const c_char) -> Option<(usize, Loc)> {
+    match find_attribute(attributes, name, AttributeKind::Variadic)? {
+        Attribute {value: AttributeValue::Int(fixed_args), loc, ..} => Some((fixed_args as usize, loc)),
+        _ => unreachable!("the arguments of the attributes are checked by the compiler"),
+    }
+}
+
+/// Returns the name of the function marked with the `entry` attribute if there is any
+pub unsafe fn find_entry(attributes: *const [(*const c_char, Array<Attribute>)]) -> Option<*const c_char> {
+    for i in 0..attributes.len() {
+        let (name, _) = (*attributes)[i];
+        if find_attribute(attributes, name, AttributeKind::Entry).is_some() {
+            return Some(name)
+        }
+    }
+    None
 }
 
 #[derive(Clone, Copy)]
@@ -130,7 +211,7 @@ pub struct Program {
     pub funcs: Array<Func>,
     pub data: Array<u8>,
     pub extrns: Array<*const c_char>,
-    pub variadics: Array<(*const c_char, Variadic)>,
+    pub attributes: Attributes,
     pub globals: Array<Global>,
     pub asm_funcs: Array<AsmFunc>,
 }
@@ -362,3 +443,70 @@ pub unsafe fn dump_program(output: *mut String_Builder, p: *const Program) {
     dump_globals(output, da_slice((*p).globals));
     dump_data_section(output, da_slice((*p).data));
 }
+
+/// Annotates the generated code with the B source lines and the IR ops it was generated from.
+/// Used by the codegens to make the generated code reviewable.
+#[derive(Clone, Copy)]
+pub struct Source_Annotator {
+    /// Content of the source files that were annotated so far. Null content means the file could not be read.
+    pub files: Array<(*const c_char, *const c_char)>,
+    pub last_path: *const c_char,
+    pub last_line: c_int,
+}
+
+unsafe fn annotator_source(a: *mut Source_Annotator, path: *const c_char) -> *const c_char {
+    for i in 0..(*a).files.count {
+        let (file_path, content) = *(*a).files.items.add(i);
+        if strcmp(file_path, path) == 0 {
+            return content;
+        }
+    }
+    let mut sb: String_Builder = zeroed();
+    let content = if read_entire_file(path, &mut sb).is_some() {
+        da_append(&mut sb, 0);
+        sb.items as *const c_char
+    } else {
+        free(sb.items);
+        ptr::null()
+    };
+    da_append(&mut (*a).files, (path, content));
+    content
+}
+
+/// Appends the source line of the `op` (unless it is the same line as the previous op) and the op itself
+/// as comments that start with `comment`.
+pub unsafe fn annotate_op(a: *mut Source_Annotator, output: *mut String_Builder, op: OpWithLocation, comment: *const c_char) {
+    let loc = op.loc;
+    if (*a).last_path.is_null() || strcmp((*a).last_path, loc.input_path) != 0 || (*a).last_line != loc.line_number {
+        (*a).last_path = loc.input_path;
+        (*a).last_line = loc.line_number;
+
+        let mut line = annotator_source(a, loc.input_path);
+        let mut line_number = 1;
+        while !line.is_null() && *line != 0 && line_number < loc.line_number {
+            if *line == '\n' as c_char { line_number += 1; }
+            line = line.add(1);
+        }
+        if !line.is_null() && line_number == loc.line_number {
+            let mut len = 0;
+            while *line.add(len) != 0 && *line.add(len) != '\n' as c_char { len += 1; }
+            sb_appendf(output, c!("    %s %s:%d: %.*s\n"), comment, loc.input_path, loc.line_number, len as c_int, line);
+        } else {
+            sb_appendf(output, c!("    %s %s:%d\n"), comment, loc.input_path, loc.line_number);
+        }
+    }
+
+    let mut dump: String_Builder = zeroed();
+    dump_op(op, &mut dump);
+    let mut start = 0;
+    for i in 0..dump.count {
+        if *dump.items.add(i) == '\n' as c_char {
+            let mut line = dump.items.add(start);
+            let mut len = i - start;
+            while len > 0 && *line == ' ' as c_char { line = line.add(1); len -= 1; }
+            sb_appendf(output, c!("    %s     %.*s\n"), comment, len as c_int, line);
+            start = i + 1;
+        }
+    }
+    free(dump.items);
+}
@@ -14,6 +14,8 @@ pub struct Jim {
 
 extern "C" {
     pub fn jim_begin(jim: *mut Jim);
+    pub fn jim_null(jim: *mut Jim);
+    pub fn jim_integer(jim: *mut Jim, x: c_longlong);
     pub fn jim_object_begin(jim: *mut Jim);
     pub fn jim_member_key(jim: *mut Jim, s: *const c_char);
     pub fn jim_object_end(jim: *mut Jim);
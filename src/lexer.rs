@@ -1,20 +1,38 @@
 use core::ffi::*;
 use core::mem::zeroed;
+use core::ptr;
+use core::cmp;
 use crate::nob::*;
 use crate::crust::libc::*;
+use crate::jim::*;
 
 #[derive(Clone, Copy)]
 pub struct Loc {
     pub input_path: *const c_char,
     pub line_number: c_int,
     pub line_offset: c_int,
+    // Amount of columns covered by the thing at this location. 0 means unknown, in which case a single caret is rendered.
+    pub span: c_int,
+    // Location of the `__include__` that brought the file of this location in. Null for the files passed to the compiler directly.
+    pub included_from: *const Loc,
 }
 
+/// Reports a diagnostic at `loc`. The message must start with a severity prefix like `ERROR:` or `NOTE:` (see DIAG_SEVERITIES).
+/// The optional `code = ...` argument assigns a stable identifier to the diagnostic which is only visible in the
+/// machine-readable output (see Diag_Format). Unlike the wording of the message it is not supposed to ever change.
 #[macro_export]
 macro_rules! diagf {
+    ($loc:expr, code = $code:expr, $($args:tt)*) => {{
+        let loc = $loc;
+        let mark = $crate::nob::temp_save();
+        $crate::lexer::print_diagnostic(loc, $code, $crate::nob::temp_sprintf($($args)*));
+        $crate::nob::temp_rewind(mark);
+    }};
     ($loc:expr, $($args:tt)*) => {{
-        fprintf(stderr(), c!("%s:%d:%d: "), $loc.input_path, $loc.line_number, $loc.line_offset);
-        fprintf(stderr(), $($args)*);
+        let loc = $loc;
+        let mark = $crate::nob::temp_save();
+        $crate::lexer::print_diagnostic(loc, core::ptr::null(), $crate::nob::temp_sprintf($($args)*));
+        $crate::nob::temp_rewind(mark);
     }};
 }
 
@@ -22,13 +40,324 @@ macro_rules! diagf {
 macro_rules! missingf {
     ($loc:expr, $($args:tt)*) => {{
         let file = file!();
-        fprintf(stderr(), c!("%s:%d:%d: TODO: "), $loc.input_path, $loc.line_number, $loc.line_offset);
-        fprintf(stderr(), $($args)*);
+        let loc = $loc;
+        let mark = $crate::nob::temp_save();
+        let message = $crate::nob::temp_sprintf($($args)*);
+        $crate::lexer::print_diagnostic(loc, c!("todo"), $crate::nob::temp_sprintf(c!("TODO: %s"), message));
+        $crate::nob::temp_rewind(mark);
+        $crate::lexer::diag_flush();
         fprintf(stderr(), c!("%.*s:%d: INFO: implementation should go here\n"), file.len(), file.as_ptr(), line!());
         abort();
     }}
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum Diag_Format {
+    /// Human readable diagnostics with source excerpts printed to stderr
+    Text,
+    /// One JSON object per line per diagnostic. NOTEs are attached to the diagnostic they follow.
+    Json,
+}
+
+impl Diag_Format {
+    pub unsafe fn from_name(name: *const c_char) -> Option<Self> {
+        if strcmp(name, c!("text")) == 0 { return Some(Self::Text) }
+        if strcmp(name, c!("json")) == 0 { return Some(Self::Json) }
+        None
+    }
+}
+
+pub static mut diag_format: Diag_Format = Diag_Format::Text;
+/// Where the JSON diagnostics go. NULL means stdout.
+pub static mut diag_json_output: *mut FILE = ptr::null_mut();
+
+#[derive(Clone, Copy)]
+struct Diag_Record {
+    severity: *const c_char,
+    code: *const c_char,
+    message: *const c_char,
+    loc: Loc,
+}
+
+// The last reported diagnostic is kept around until the next one comes in case it gets some NOTEs attached to it.
+static mut diag_pending: Option<Diag_Record> = None;
+static mut diag_pending_notes: Array<Diag_Record> = Array { items: ptr::null_mut(), count: 0, capacity: 0 };
+
+const DIAG_RESET: *const c_char = c!("\x1b[0m");
+const DIAG_BOLD:  *const c_char = c!("\x1b[1m");
+const DIAG_RED:   *const c_char = c!("\x1b[1;31m");
+const DIAG_MAGENTA: *const c_char = c!("\x1b[1;35m");
+const DIAG_CYAN:  *const c_char = c!("\x1b[1;36m");
+
+struct Diag_Severity {
+    prefix: *const c_char,
+    color: *const c_char,
+    // The name of the severity in the machine-readable output
+    name: *const c_char,
+    // Notes explain the diagnostic that was reported right before them
+    is_note: bool,
+}
+
+// The message of a diagnostic is expected to start with one of these prefixes followed by a colon.
+const DIAG_SEVERITIES: *const [Diag_Severity] = &[
+    Diag_Severity { prefix: c!("ERROR"),       color: DIAG_RED,     name: c!("error"),   is_note: false },
+    Diag_Severity { prefix: c!("LEXER ERROR"), color: DIAG_RED,     name: c!("error"),   is_note: false },
+    Diag_Severity { prefix: c!("WARNING"),     color: DIAG_MAGENTA, name: c!("warning"), is_note: false },
+    Diag_Severity { prefix: c!("TODO"),        color: DIAG_MAGENTA, name: c!("todo"),    is_note: false },
+    Diag_Severity { prefix: c!("NOTE"),        color: DIAG_CYAN,    name: c!("note"),    is_note: true  },
+    Diag_Severity { prefix: c!("INFO"),        color: DIAG_CYAN,    name: c!("note"),    is_note: true  },
+    Diag_Severity { prefix: c!("LEXER INFO"),  color: DIAG_CYAN,    name: c!("note"),    is_note: true  },
+];
+
+pub unsafe fn diag_use_color() -> bool {
+    isatty(fileno(stderr())) != 0 && getenv(c!("NO_COLOR")).is_null()
+}
+
+/// Returns the severity of the message along with the length of its prefix including the colon.
+unsafe fn diag_severity_of_message(message: *const c_char) -> Option<(*const Diag_Severity, usize)> {
+    let colon = strchr(message, ':' as c_int);
+    if colon.is_null() { return None; }
+    let len = colon.offset_from(message) as usize;
+    for i in 0..DIAG_SEVERITIES.len() {
+        let severity = &(*DIAG_SEVERITIES)[i] as *const Diag_Severity;
+        if strlen((*severity).prefix) == len && strncmp((*severity).prefix, message, len) == 0 {
+            return Some((severity, len + 1));
+        }
+    }
+    None
+}
+
+/// This is what diagf!() and missingf!() expand to.
+pub unsafe fn print_diagnostic(loc: Loc, code: *const c_char, message: *const c_char) {
+    match diag_format {
+        Diag_Format::Text => print_diagnostic_text(loc, message),
+        Diag_Format::Json => record_diagnostic_json(loc, code, message),
+    }
+}
+
+/// Prints `path:line:col: MESSAGE` followed by the excerpt of the source code the `loc` is pointing at.
+pub unsafe fn print_diagnostic_text(loc: Loc, message: *const c_char) {
+    let color = diag_use_color();
+
+    let mut severity_color: *const c_char = ptr::null();
+    let mut severity_len = 0;
+    let mut is_note = false;
+    if let Some((severity, len)) = diag_severity_of_message(message) {
+        severity_color = (*severity).color;
+        severity_len = len;
+        is_note = (*severity).is_note;
+    }
+
+    // The NOTEs usually follow the diagnostic they belong to, no need to repeat the include chain for them
+    let mut included_from = if is_note { ptr::null() } else { loc.included_from };
+    while !included_from.is_null() {
+        fprintf(stderr(), c!("In file included from %s:%d:%d\n"), (*included_from).input_path, (*included_from).line_number, (*included_from).line_offset);
+        included_from = (*included_from).included_from;
+    }
+
+    if color {
+        fprintf(stderr(), c!("%s%s:%d:%d:%s "), DIAG_BOLD, loc.input_path, loc.line_number, loc.line_offset, DIAG_RESET);
+    } else {
+        fprintf(stderr(), c!("%s:%d:%d: "), loc.input_path, loc.line_number, loc.line_offset);
+    }
+    if color && !severity_color.is_null() {
+        fprintf(stderr(), c!("%s%.*s%s%s"), severity_color, severity_len as c_int, message, DIAG_RESET, message.add(severity_len));
+    } else {
+        fprintf(stderr(), c!("%s"), message);
+    }
+
+    print_source_excerpt(loc, if color { severity_color } else { ptr::null() });
+}
+
+unsafe fn record_diagnostic_json(loc: Loc, code: *const c_char, message: *const c_char) {
+    let mut severity_name = c!("error");
+    let mut is_note = false;
+    let mut message = message;
+    if let Some((severity, len)) = diag_severity_of_message(message) {
+        severity_name = (*severity).name;
+        is_note = (*severity).is_note;
+        message = message.add(len);
+        while isspace(*message as c_int) != 0 {
+            message = message.add(1);
+        }
+    }
+
+    // The message is temporary memory of diagf!() and is usually terminated with a newline which has no place in JSON
+    let mut message_len = strlen(message);
+    while message_len > 0 && isspace(*message.add(message_len - 1) as c_int) != 0 {
+        message_len -= 1;
+    }
+    let message = strdup(message);
+    *message.add(message_len) = 0;
+
+    let record = Diag_Record {
+        severity: severity_name,
+        code: if code.is_null() { code } else { strdup(code) },
+        message,
+        loc: Loc {
+            input_path: if loc.input_path.is_null() { loc.input_path } else { strdup(loc.input_path) },
+            ..loc
+        },
+    };
+
+    if is_note && (*ptr::addr_of!(diag_pending)).is_some() {
+        da_append(ptr::addr_of_mut!(diag_pending_notes), record);
+    } else {
+        diag_flush();
+        diag_pending = Some(record);
+    }
+}
+
+unsafe fn jim_diag_record(jim: *mut Jim, record: Diag_Record) {
+    jim_member_key(jim, c!("severity"));
+    jim_string(jim, record.severity);
+    jim_member_key(jim, c!("message"));
+    jim_string(jim, record.message);
+    jim_member_key(jim, c!("location"));
+    if record.loc.input_path.is_null() {
+        jim_null(jim);
+    } else {
+        jim_object_begin(jim);
+        jim_member_key(jim, c!("path"));
+        jim_string(jim, record.loc.input_path);
+        jim_member_key(jim, c!("line"));
+        jim_integer(jim, record.loc.line_number as c_longlong);
+        jim_member_key(jim, c!("column"));
+        jim_integer(jim, record.loc.line_offset as c_longlong);
+        jim_member_key(jim, c!("span"));
+        jim_integer(jim, record.loc.span as c_longlong);
+        jim_member_key(jim, c!("included_from"));
+        jim_array_begin(jim);
+        let mut included_from = record.loc.included_from;
+        while !included_from.is_null() {
+            jim_object_begin(jim);
+            jim_member_key(jim, c!("path"));
+            jim_string(jim, (*included_from).input_path);
+            jim_member_key(jim, c!("line"));
+            jim_integer(jim, (*included_from).line_number as c_longlong);
+            jim_member_key(jim, c!("column"));
+            jim_integer(jim, (*included_from).line_offset as c_longlong);
+            jim_object_end(jim);
+            included_from = (*included_from).included_from;
+        }
+        jim_array_end(jim);
+        jim_object_end(jim);
+    }
+}
+
+unsafe fn free_diag_record(record: Diag_Record) {
+    free(record.code as *mut c_char);
+    free(record.message as *mut c_char);
+    free(record.loc.input_path as *mut c_char);
+}
+
+/// Writes out the diagnostic that is still waiting for its NOTEs. Must be called before the compiler exits.
+pub unsafe fn diag_flush() {
+    let Some(record) = diag_pending else { return; };
+    let notes = ptr::addr_of_mut!(diag_pending_notes);
+
+    let mut jim: Jim = zeroed();
+    jim_begin(&mut jim);
+    jim_object_begin(&mut jim);
+    jim_diag_record(&mut jim, record);
+    jim_member_key(&mut jim, c!("code"));
+    if record.code.is_null() {
+        jim_null(&mut jim);
+    } else {
+        jim_string(&mut jim, record.code);
+    }
+    jim_member_key(&mut jim, c!("notes"));
+    jim_array_begin(&mut jim);
+    for i in 0..(*notes).count {
+        let note = *(*notes).items.add(i);
+        jim_object_begin(&mut jim);
+        jim_diag_record(&mut jim, note);
+        jim_object_end(&mut jim);
+        free_diag_record(note);
+    }
+    jim_array_end(&mut jim);
+    jim_object_end(&mut jim);
+
+    let output = if diag_json_output.is_null() { stdout() } else { diag_json_output };
+    fprintf(output, c!("%.*s\n"), jim.sink_count as c_int, jim.sink);
+    fflush(output);
+
+    free(jim.sink);
+    free(jim.scopes);
+    free_diag_record(record);
+    (*notes).count = 0;
+    diag_pending = None;
+}
+
+/// Renders the line `loc` is pointing at with a caret/underline under the span of the location:
+///
+/// ```text
+///     3 |     x = 99999999999999999999999;
+///       |         ^~~~~~~~~~~~~~~~~~~~~~~
+/// ```
+///
+/// The file is reread from the disk, which is fine since we only do that when something went wrong.
+/// If the file is not available (for example the location is inside of an inline assembly line) nothing is printed.
+pub unsafe fn print_source_excerpt(loc: Loc, color: *const c_char) {
+    if loc.input_path.is_null() || loc.line_number <= 0 || loc.line_offset <= 0 { return; }
+    if !matches!(file_exists(loc.input_path), Some(true)) { return; }
+
+    let mut source: String_Builder = zeroed();
+    if read_entire_file(loc.input_path, &mut source).is_none() {
+        free(source.items);
+        return;
+    }
+    let end = source.items.add(source.count);
+
+    let mut line_start: *const c_char = source.items;
+    for _ in 1..loc.line_number {
+        while line_start < end && *line_start != '\n' as c_char {
+            line_start = line_start.add(1);
+        }
+        if line_start >= end {
+            free(source.items);
+            return;
+        }
+        line_start = line_start.add(1);
+    }
+    let mut line_end = line_start;
+    while line_end < end && *line_end != '\n' as c_char && *line_end != '\r' as c_char {
+        line_end = line_end.add(1);
+    }
+    let line_len = line_end.offset_from(line_start) as c_int;
+
+    let number_width = snprintf(ptr::null_mut(), 0, c!("%d"), loc.line_number);
+    fprintf(stderr(), c!(" %d | %.*s\n"), loc.line_number, line_len, line_start);
+    fprintf(stderr(), c!(" %*s | "), number_width, c!(""));
+    let caret = loc.line_offset - 1;
+    for i in 0..caret {
+        // Keep the tabs so the caret lines up with the source line no matter the tab width of the terminal
+        if i < line_len && *line_start.add(i as usize) == '\t' as c_char {
+            fprintf(stderr(), c!("\t"));
+        } else {
+            fprintf(stderr(), c!(" "));
+        }
+    }
+    let span = if caret < line_len {
+        cmp::min(cmp::max(loc.span, 1), line_len - caret)
+    } else {
+        1
+    };
+    if !color.is_null() {
+        fprintf(stderr(), c!("%s"), color);
+    }
+    fprintf(stderr(), c!("^"));
+    for _ in 1..span {
+        fprintf(stderr(), c!("~"));
+    }
+    if !color.is_null() {
+        fprintf(stderr(), c!("%s"), DIAG_RESET);
+    }
+    fprintf(stderr(), c!("\n"));
+
+    free(source.items);
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum Token {
     // Terminal
@@ -93,6 +422,9 @@ pub enum Token {
     Return,
     Asm,
     Variadic,
+    Attr,
+    IfTarget,
+    Include,
 }
 
 pub unsafe fn display_token(token: Token) -> *const c_char {
@@ -160,6 +492,9 @@ pub unsafe fn display_token(token: Token) -> *const c_char {
         // TODO: document all this magical extension keywords somewhere
         Token::Asm        => c!("keyword `__asm__`"),
         Token::Variadic   => c!("keyword `__variadic__`"),
+        Token::Attr       => c!("keyword `__attr__`"),
+        Token::IfTarget   => c!("keyword `__if_target__`"),
+        Token::Include    => c!("keyword `__include__`"),
     }
 }
 
@@ -264,6 +599,9 @@ const KEYWORDS: *const [(*const c_char, Token)] = &[
     (c!("return"), Token::Return),
     (c!("__asm__"), Token::Asm),
     (c!("__variadic__"), Token::Variadic),
+    (c!("__attr__"), Token::Attr),
+    (c!("__if_target__"), Token::IfTarget),
+    (c!("__include__"), Token::Include),
 ];
 
 #[derive(Clone, Copy)]
@@ -276,6 +614,8 @@ pub struct Parse_Point {
 #[derive(Clone, Copy)]
 pub struct Lexer {
     pub input_path: *const c_char,
+    /// See Loc::included_from
+    pub included_from: *const Loc,
     pub input_stream: *const c_char,
     pub eof: *const c_char,
     pub parse_point: Parse_Point,
@@ -286,6 +626,34 @@ pub struct Lexer {
     pub string: *const c_char,
     pub int_number: u64,
     pub loc: Loc,
+    pub error_count: usize,
+    pub error_point: *const c_char,
+}
+
+pub const MAX_ERROR_COUNT: usize = 100;
+/// Same idea as bump_error_count() in b.rs but for the errors of the Lexer itself. The Lexer always recovers from its errors
+/// by producing some plausible token and skipping to a resync point, so the parser does not even notice that anything happened.
+/// The caller is supposed to check Lexer.error_count after the whole input has been processed.
+pub unsafe fn bump_error_count(l: *mut Lexer) -> Option<()> {
+    (*l).error_count += 1;
+    if (*l).error_count >= MAX_ERROR_COUNT {
+        fprintf(stderr(), c!("TOO MANY ERRORS! Fix your program!\n"));
+        (*l).token = Token::ParseError;
+        return None
+    }
+    Some(())
+}
+
+/// The parser backtracks by restoring the parse_point, which makes the Lexer go through the same piece of the input
+/// several times. This makes sure each lexer error is reported only once. `point` is where the lexer ended up after
+/// recovering from the error.
+unsafe fn is_new_error(l: *mut Lexer, point: *const c_char) -> bool {
+    if point > (*l).error_point {
+        (*l).error_point = point;
+        true
+    } else {
+        false
+    }
 }
 
 pub unsafe fn new(input_path: *const c_char, input_stream: *const c_char, eof: *const c_char, historical: bool) -> Lexer {
@@ -369,6 +737,8 @@ pub unsafe fn loc(l: *mut Lexer) -> Loc {
         input_path:  (*l).input_path,
         line_number: (*l).parse_point.line_number as i32,
         line_offset: (*l).parse_point.current.offset_from((*l).parse_point.line_start) as i32 + 1,
+        span:        0,
+        included_from: (*l).included_from,
     }
 }
 
@@ -380,9 +750,8 @@ pub unsafe fn parse_string_into_storage(l: *mut Lexer, delim: c_char) -> Option<
             x if x == escape_char => {
                 skip_char(l);
                 let Some(x) = peek_char(l) else {
-                    (*l).token = Token::ParseError;
-                    diagf!(loc(l), c!("LEXER ERROR: Unfinished escape sequence\n"));
-                    return None;
+                    // The caller reports the unfinished literal
+                    break;
                 };
                 let x = match x {
                     x if x == '0'   as c_char => '\0' as c_char,
@@ -392,9 +761,11 @@ pub unsafe fn parse_string_into_storage(l: *mut Lexer, delim: c_char) -> Option<
                     x if x == delim           => delim,
                     x if x == escape_char     => escape_char,
                     x => {
-                        (*l).token = Token::ParseError;
-                        diagf!(loc(l), c!("LEXER ERROR: Unknown escape sequence starting with `%c`\n"), x as c_int);
-                        return None;
+                        if is_new_error(l, (*l).parse_point.current) {
+                            diagf!(loc(l), code = c!("unknown-escape"), c!("LEXER ERROR: Unknown escape sequence starting with `%c`\n"), x as c_int);
+                            bump_error_count(l)?;
+                        }
+                        x
                     }
                 };
                 da_append(&mut (*l).string_storage, x);
@@ -439,42 +810,72 @@ unsafe fn parse_digit(c: c_char, radix: Radix) -> Option<u8> {
     return None;
 }
 
-unsafe fn parse_number(l: *mut Lexer, radix: Radix, report_point: Parse_Point) -> Option<()> {
+unsafe fn parse_number(l: *mut Lexer, radix: Radix) -> Option<()> {
+    let mut overflow = false;
     while let Some(x) = peek_char(l) {
         let Some(d) = parse_digit(x, radix) else {
             break;
         };
+        skip_char(l);
 
-        let Some(r) = (*l).int_number.checked_mul(radix as u64) else {
-            (*l).parse_point = report_point;
-            diagf!(loc(l), c!("LEXER ERROR: Constant integer overflow\n"));
-            return None;
-        };
-        (*l).int_number = r;
+        // Keep consuming the digits after the overflow so the literal is skipped as a whole
+        if overflow { continue; }
 
-        let Some(r) = (*l).int_number.checked_add(d as u64) else {
-            (*l).parse_point = report_point;
-            diagf!(loc(l), c!("LEXER ERROR: Constant integer overflow.\n"));
-            return None;
-        };
-        (*l).int_number = r;
-        skip_char(l);
+        match (*l).int_number.checked_mul(radix as u64).and_then(|r| r.checked_add(d as u64)) {
+            Some(r) => (*l).int_number = r,
+            None => overflow = true,
+        }
     };
 
+    if overflow {
+        (*l).int_number = 0;
+        if is_new_error(l, (*l).parse_point.current) {
+            diagf!(token_loc(l), code = c!("integer-overflow"), c!("LEXER ERROR: Constant integer overflow\n"));
+            bump_error_count(l)?;
+        }
+    }
+
     return Some(());
 }
 
+/// Resync point for the literals that were never closed. Instead of swallowing the rest of the file we pretend that the
+/// literal ends at the end of the line it started on.
+unsafe fn skip_unfinished_literal(l: *mut Lexer, start_point: Parse_Point) {
+    (*l).parse_point = start_point;
+    skip_until(l, c!("\n"));
+}
+
 pub unsafe fn get_token(l: *mut Lexer) -> Option<()> {
+    let result = lex_token(l);
+    (*l).loc = token_loc(l);
+    result
+}
+
+/// Location of the token that is currently being lexed spanning up until the current parse point.
+/// Tokens that span several lines (like multiline string literals) are only pointed at with a single caret.
+unsafe fn token_loc(l: *mut Lexer) -> Loc {
+    let mut result = (*l).loc;
+    let end = loc(l);
+    result.span = if end.line_number == result.line_number {
+        end.line_offset - result.line_offset
+    } else {
+        0
+    };
+    result
+}
+
+unsafe fn lex_token(l: *mut Lexer) -> Option<()> {
     'comments: loop {
         skip_whitespaces(l);
 
         let saved_point = (*l).parse_point;
         if skip_prefix(l, c!("//")) {
-            if (*l).historical {
+            if (*l).historical && is_new_error(l, (*l).parse_point.current) {
+                let comment_point = (*l).parse_point;
                 (*l).parse_point = saved_point;
-                diagf!(loc(l), c!("LEXER ERROR: C++ style comments are not available in the historical mode.\n"));
-                (*l).token = Token::ParseError;
-                return None;
+                diagf!(loc(l), code = c!("historical-comment"), c!("LEXER ERROR: C++ style comments are not available in the historical mode.\n"));
+                (*l).parse_point = comment_point;
+                bump_error_count(l)?;
             }
             skip_until(l, c!("\n"));
             continue 'comments;
@@ -529,74 +930,85 @@ pub unsafe fn get_token(l: *mut Lexer) -> Option<()> {
         return Some(())
     }
 
-    let start_of_number = (*l).parse_point;
     if skip_prefix(l, c!("0x")) {
-        if (*l).historical {
-            (*l).parse_point = start_of_number;
-            diagf!(loc(l), c!("LEXER ERROR: hex literals are not available in the historical mode.\n"));
-            (*l).token = Token::ParseError;
-            return None;
+        if (*l).historical && is_new_error(l, (*l).parse_point.current) {
+            diagf!(token_loc(l), code = c!("historical-hex"), c!("LEXER ERROR: hex literals are not available in the historical mode.\n"));
+            bump_error_count(l)?;
         }
 
         (*l).token = Token::IntLit;
         (*l).int_number = 0;
-        return parse_number(l, Radix::Hex, start_of_number);
+        return parse_number(l, Radix::Hex);
     }
 
     if skip_prefix(l, c!("0")) {
         (*l).token = Token::IntLit;
         (*l).int_number = 0;
-        return parse_number(l, Radix::Oct, start_of_number);
+        return parse_number(l, Radix::Oct);
     }
 
     if isdigit(x as c_int) != 0 {
         (*l).token = Token::IntLit;
         (*l).int_number = 0;
-        return parse_number(l, Radix::Dec, start_of_number);
+        return parse_number(l, Radix::Dec);
     }
 
     if x == '"' as c_char {
+        let start_of_literal = (*l).parse_point;
         skip_char(l);
         (*l).token = Token::String;
         (*l).string_storage.count = 0;
         parse_string_into_storage(l, '"' as c_char)?;
         if is_eof(l) {
-            diagf!(loc(l), c!("LEXER ERROR: Unfinished string literal\n"));
-            diagf!((*l).loc, c!("LEXER INFO: Literal starts here\n"));
-            (*l).token = Token::ParseError;
-            return None;
+            let eof_loc = loc(l);
+            skip_unfinished_literal(l, start_of_literal);
+            if is_new_error(l, (*l).parse_point.current) {
+                diagf!(eof_loc, code = c!("unfinished-string"), c!("LEXER ERROR: Unfinished string literal\n"));
+                diagf!((*l).loc, c!("LEXER INFO: Literal starts here\n"));
+                bump_error_count(l)?;
+            }
+            (*l).string_storage.count = 0;
+        } else {
+            skip_char(l);
         }
-        skip_char(l);
         da_append(&mut (*l).string_storage, 0);
         (*l).string = (*l).string_storage.items;
         return Some(());
     }
 
     if x == '\'' as c_char {
+        let start_of_literal = (*l).parse_point;
         skip_char(l);
         (*l).token = Token::CharLit;
         (*l).string_storage.count = 0;
+        (*l).int_number = 0;
         parse_string_into_storage(l, '\'' as c_char)?;
         if is_eof(l) {
-            diagf!(loc(l), c!("LEXER ERROR: Unfinished character literal\n"));
-            diagf!((*l).loc, c!("LEXER INFO: Literal starts here\n"));
-            (*l).token = Token::ParseError;
-            return None;
+            let eof_loc = loc(l);
+            skip_unfinished_literal(l, start_of_literal);
+            if is_new_error(l, (*l).parse_point.current) {
+                diagf!(eof_loc, code = c!("unfinished-char"), c!("LEXER ERROR: Unfinished character literal\n"));
+                diagf!((*l).loc, c!("LEXER INFO: Literal starts here\n"));
+                bump_error_count(l)?;
+            }
+            return Some(());
         }
         skip_char(l);
         if (*l).string_storage.count == 0 {
-            diagf!((*l).loc, c!("LEXER ERROR: Empty character literal\n"));
-            (*l).token = Token::ParseError;
-            return None;
+            if is_new_error(l, (*l).parse_point.current) {
+                diagf!(token_loc(l), code = c!("empty-char"), c!("LEXER ERROR: Empty character literal\n"));
+                bump_error_count(l)?;
+            }
+            return Some(());
         }
         if (*l).string_storage.count > 2 {
             // TODO: maybe we should allow more on targets with 64 bits?
-            // TODO: such error should not terminate the compilation
-            diagf!((*l).loc, c!("LEXER ERROR: Character literal contains more than two characters\n"));
-            (*l).token = Token::ParseError;
-            return None;
+            if is_new_error(l, (*l).parse_point.current) {
+                diagf!(token_loc(l), code = c!("long-char"), c!("LEXER ERROR: Character literal contains more than two characters\n"));
+                bump_error_count(l)?;
+            }
+            return Some(());
         }
-        (*l).int_number = 0;
         for i in 0..(*l).string_storage.count {
             (*l).int_number *= 0x100;
             (*l).int_number += *(*l).string_storage.items.add(i) as u64;
@@ -604,7 +1016,11 @@ pub unsafe fn get_token(l: *mut Lexer) -> Option<()> {
         return Some(());
     }
 
-    diagf!((*l).loc, c!("LEXER ERROR: Unknown token %c\n"), *(*l).parse_point.current as c_int);
-    (*l).token = Token::ParseError;
-    None
+    // Resync right after the unknown character
+    skip_char(l);
+    if is_new_error(l, (*l).parse_point.current) {
+        diagf!((*l).loc, code = c!("unknown-token"), c!("LEXER ERROR: Unknown token %c\n"), x as c_int);
+        bump_error_count(l)?;
+    }
+    lex_token(l)
 }
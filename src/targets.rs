@@ -39,9 +39,11 @@ impl Target {
         garbage_base: *const c_char,
         nostdlib: bool,
         debug: bool,
+        stage: BuildStage,
+        objects: *const [*const c_char],
     ) -> Option<()> {
         match self.api {
-            TargetAPI::V1 { build, .. } => build(gen, program, program_path, garbage_base, nostdlib, debug),
+            TargetAPI::V1 { build, .. } => build(gen, program, program_path, garbage_base, nostdlib, debug, stage, objects),
         }
     }
     pub unsafe fn run (
@@ -59,6 +61,18 @@ impl Target {
             TargetAPI::V1 { file_ext, .. } => file_ext,
         }
     }
+    /// Extension of the native object files of the target. Null if the target can't produce them.
+    pub unsafe fn object_file_ext(self) -> *const c_char {
+        match self.api {
+            TargetAPI::V1 { object_file_ext, .. } => object_file_ext,
+        }
+    }
+    /// Extension of the assembly files of the target. Null if the target can't produce them.
+    pub unsafe fn assembly_file_ext(self) -> *const c_char {
+        match self.api {
+            TargetAPI::V1 { assembly_file_ext, .. } => assembly_file_ext,
+        }
+    }
 }
 
 pub unsafe fn register_apis(targets: *mut Array<Target>, apis: *const [TargetAPI], codegen_name: *const c_char) -> Option<()> {
@@ -85,6 +99,10 @@ pub enum TargetAPI {
     V1 {
         name: *const c_char,
         file_ext: *const c_char,
+        /// Null if the codegen does not support BuildStage::Object
+        object_file_ext: *const c_char,
+        /// Null if the codegen does not support BuildStage::Assembly
+        assembly_file_ext: *const c_char,
         new: unsafe fn(
             a: *mut arena::Arena,
             args: *const [*const c_char]
@@ -96,6 +114,9 @@ pub enum TargetAPI {
             garbage_base: *const c_char,
             nostdlib: bool,
             debug: bool,
+            stage: BuildStage,
+            // Native object files to link the program with
+            objects: *const [*const c_char],
         ) -> Option<()>,
         run: unsafe fn(
             gen: *mut c_void,
@@ -113,6 +134,16 @@ impl TargetAPI {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BuildStage {
+    /// Stop at the assembly of the program that is written to `program_path`
+    Assembly,
+    /// Stop at a relocatable native object file that is written to `program_path`
+    Object,
+    /// Build the final program
+    Program,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Os {
     Linux,
@@ -0,0 +1,572 @@
+//! Serialized IR units produced by `b -c` for the targets that have no native object files.
+//!
+//! A unit is just an `ir::Program` of a single input written in a simple binary format. The link step reads
+//! the units back and merges them into the Program that is then passed to the codegen as usual.
+//!
+//! The format is a sequence of little-endian u64s and length-prefixed strings:
+//!
+//! ```text
+//! UNIT_MAGIC, UNIT_VERSION, target name,
+//! data, extrns, attributes, funcs, globals, asm_funcs
+//! ```
+//!
+//! Bump UNIT_VERSION whenever the layout of anything in here changes.
+
+use core::ffi::*;
+use core::mem::zeroed;
+use core::ptr;
+use crate::nob::*;
+use crate::ir::*;
+use crate::lexer::Loc;
+use crate::crust::libc::*;
+use crate::arena::{self, Arena};
+
+pub const UNIT_FILE_EXT: *const c_char = c!(".bo");
+const UNIT_MAGIC: u64 = 0x54494E55_42; // "BUNIT" in little-endian
+const UNIT_VERSION: u64 = 1;
+const NULL_STRING: u64 = u64::MAX;
+
+pub unsafe fn write_u64(output: *mut String_Builder, value: u64) {
+    da_append_many(output, &value.to_le_bytes() as *const [u8] as *const [c_char]);
+}
+
+pub unsafe fn write_string(output: *mut String_Builder, s: *const c_char) {
+    if s.is_null() {
+        write_u64(output, NULL_STRING);
+        return;
+    }
+    let len = strlen(s);
+    write_u64(output, len as u64);
+    da_append_many(output, ptr::slice_from_raw_parts(s, len));
+}
+
+pub unsafe fn write_loc(output: *mut String_Builder, loc: Loc) {
+    write_string(output, loc.input_path);
+    write_u64(output, loc.line_number as u64);
+    write_u64(output, loc.line_offset as u64);
+    write_u64(output, loc.span as u64);
+}
+
+unsafe fn write_arg(output: *mut String_Builder, arg: Arg, data_base: usize) {
+    match arg {
+        Arg::Bogus               => unreachable!("bogus-amogus"),
+        Arg::AutoVar(index)      => { write_u64(output, 0); write_u64(output, index as u64); }
+        Arg::Deref(index)        => { write_u64(output, 1); write_u64(output, index as u64); }
+        Arg::RefAutoVar(index)   => { write_u64(output, 2); write_u64(output, index as u64); }
+        Arg::RefExternal(name)   => { write_u64(output, 3); write_string(output, name); }
+        Arg::External(name)      => { write_u64(output, 4); write_string(output, name); }
+        Arg::Literal(value)      => { write_u64(output, 5); write_u64(output, value); }
+        Arg::DataOffset(offset)  => { write_u64(output, 6); write_u64(output, (offset - data_base) as u64); }
+    }
+}
+
+unsafe fn write_asm_stmts(output: *mut String_Builder, stmts: Array<AsmStmt>) {
+    write_u64(output, stmts.count as u64);
+    for i in 0..stmts.count {
+        let stmt = *stmts.items.add(i);
+        write_string(output, stmt.line);
+        write_loc(output, stmt.loc);
+    }
+}
+
+unsafe fn write_op(output: *mut String_Builder, op: OpWithLocation, data_base: usize) {
+    write_loc(output, op.loc);
+    write_u64(output, op.scope_events_count as u64);
+    match op.opcode {
+        Op::Bogus => unreachable!("bogus-amogus"),
+        Op::UnaryNot {result, arg} => {
+            write_u64(output, 0);
+            write_u64(output, result as u64);
+            write_arg(output, arg, data_base);
+        }
+        Op::Negate {result, arg} => {
+            write_u64(output, 1);
+            write_u64(output, result as u64);
+            write_arg(output, arg, data_base);
+        }
+        Op::Asm {stmts} => {
+            write_u64(output, 2);
+            write_asm_stmts(output, stmts);
+        }
+        Op::Binop {binop, index, lhs, rhs} => {
+            write_u64(output, 3);
+            write_u64(output, binop as u64);
+            write_u64(output, index as u64);
+            write_arg(output, lhs, data_base);
+            write_arg(output, rhs, data_base);
+        }
+        Op::Index {result, arg, offset} => {
+            write_u64(output, 4);
+            write_u64(output, result as u64);
+            write_arg(output, arg, data_base);
+            write_arg(output, offset, data_base);
+        }
+        Op::AutoAssign {index, arg} => {
+            write_u64(output, 5);
+            write_u64(output, index as u64);
+            write_arg(output, arg, data_base);
+        }
+        Op::ExternalAssign {name, arg} => {
+            write_u64(output, 6);
+            write_string(output, name);
+            write_arg(output, arg, data_base);
+        }
+        Op::Store {index, arg} => {
+            write_u64(output, 7);
+            write_u64(output, index as u64);
+            write_arg(output, arg, data_base);
+        }
+        Op::Funcall {result, fun, args} => {
+            write_u64(output, 8);
+            write_u64(output, result as u64);
+            write_arg(output, fun, data_base);
+            write_u64(output, args.count as u64);
+            for i in 0..args.count {
+                write_arg(output, *args.items.add(i), data_base);
+            }
+        }
+        Op::Label {label} => {
+            write_u64(output, 9);
+            write_u64(output, label as u64);
+        }
+        Op::JmpLabel {label} => {
+            write_u64(output, 10);
+            write_u64(output, label as u64);
+        }
+        Op::JmpIfNotLabel {label, arg} => {
+            write_u64(output, 11);
+            write_u64(output, label as u64);
+            write_arg(output, arg, data_base);
+        }
+        Op::Return {arg} => {
+            write_u64(output, 12);
+            match arg {
+                Some(arg) => {
+                    write_u64(output, 1);
+                    write_arg(output, arg, data_base);
+                }
+                None => write_u64(output, 0),
+            }
+        }
+    }
+}
+
+unsafe fn write_immediate_value(output: *mut String_Builder, value: ImmediateValue, data_base: usize) {
+    match value {
+        ImmediateValue::Name(name)         => { write_u64(output, 0); write_string(output, name); }
+        ImmediateValue::Literal(value)     => { write_u64(output, 1); write_u64(output, value); }
+        ImmediateValue::DataOffset(offset) => { write_u64(output, 2); write_u64(output, (offset - data_base) as u64); }
+    }
+}
+
+/// The data section of `program` is expected to start at `data_base` of some bigger data section, so all the
+/// offsets into it are made relative to `data_base`. Pass 0 if the program is not a part of anything bigger.
+pub unsafe fn write_unit(output: *mut String_Builder, program: *const Program, target_name: *const c_char, data_base: usize) {
+    write_u64(output, UNIT_MAGIC);
+    write_u64(output, UNIT_VERSION);
+    write_string(output, target_name);
+
+    write_u64(output, (*program).data.count as u64);
+    da_append_many(output, da_slice((*program).data) as *const [c_char]);
+
+    write_u64(output, (*program).extrns.count as u64);
+    for i in 0..(*program).extrns.count {
+        write_string(output, *(*program).extrns.items.add(i));
+    }
+
+    write_u64(output, (*program).attributes.count as u64);
+    for i in 0..(*program).attributes.count {
+        let (name, attrs) = *(*program).attributes.items.add(i);
+        write_string(output, name);
+        write_u64(output, attrs.count as u64);
+        for j in 0..attrs.count {
+            let attr = *attrs.items.add(j);
+            write_string(output, attr.kind.name());
+            match attr.value {
+                AttributeValue::None      => write_u64(output, 0),
+                AttributeValue::Int(x)    => { write_u64(output, 1); write_u64(output, x); }
+                AttributeValue::String(s) => { write_u64(output, 2); write_string(output, s); }
+            }
+            write_loc(output, attr.loc);
+        }
+    }
+
+    write_u64(output, (*program).funcs.count as u64);
+    for i in 0..(*program).funcs.count {
+        let func = *(*program).funcs.items.add(i);
+        write_string(output, func.name);
+        write_loc(output, func.name_loc);
+        write_u64(output, func.params_count as u64);
+        write_u64(output, func.auto_vars_count as u64);
+        write_u64(output, func.body.count as u64);
+        for j in 0..func.body.count {
+            write_op(output, *func.body.items.add(j), data_base);
+        }
+        write_u64(output, func.scope_events.count as u64);
+        for j in 0..func.scope_events.count {
+            match *func.scope_events.items.add(j) {
+                ScopeEvent::Declare {name, index} => { write_u64(output, 0); write_string(output, name); write_u64(output, index as u64); }
+                ScopeEvent::BlockBegin {index}    => { write_u64(output, 1); write_u64(output, index as u64); }
+                ScopeEvent::BlockEnd {index}      => { write_u64(output, 2); write_u64(output, index as u64); }
+            }
+        }
+    }
+
+    write_u64(output, (*program).globals.count as u64);
+    for i in 0..(*program).globals.count {
+        let global = *(*program).globals.items.add(i);
+        write_string(output, global.name);
+        write_loc(output, global.name_loc);
+        write_u64(output, global.is_vec as u64);
+        write_u64(output, global.minimum_size as u64);
+        write_u64(output, global.values.count as u64);
+        for j in 0..global.values.count {
+            write_immediate_value(output, *global.values.items.add(j), data_base);
+        }
+    }
+
+    write_u64(output, (*program).asm_funcs.count as u64);
+    for i in 0..(*program).asm_funcs.count {
+        let asm_func = *(*program).asm_funcs.items.add(i);
+        write_string(output, asm_func.name);
+        write_loc(output, asm_func.name_loc);
+        write_asm_stmts(output, asm_func.body);
+    }
+}
+
+pub struct Unit_Reader {
+    pub path: *const c_char,
+    pub data: *const u8,
+    pub count: usize,
+    pub pos: usize,
+    pub arena: *mut Arena,
+    /// Do not report the problems with the data, the caller handles them on its own
+    pub quiet: bool,
+}
+
+/// Reports a problem with the data of the reader unless it is quiet
+macro_rules! reader_error {
+    ($r:expr, $($args:tt)*) => {
+        if !(*$r).quiet {
+            log(Log_Level::ERROR, $($args)*);
+        }
+    }
+}
+
+pub unsafe fn read_u64(r: *mut Unit_Reader) -> Option<u64> {
+    if (*r).pos + 8 > (*r).count {
+        reader_error!(r, c!("%s: unexpected end of the unit"), (*r).path);
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    ptr::copy_nonoverlapping((*r).data.add((*r).pos), bytes.as_mut_ptr(), 8);
+    (*r).pos += 8;
+    Some(u64::from_le_bytes(bytes))
+}
+
+pub unsafe fn read_usize(r: *mut Unit_Reader) -> Option<usize> {
+    read_u64(r).map(|x| x as usize)
+}
+
+pub unsafe fn read_string(r: *mut Unit_Reader) -> Option<*const c_char> {
+    let len = read_u64(r)?;
+    if len == NULL_STRING {
+        return Some(ptr::null());
+    }
+    let len = len as usize;
+    if (*r).pos + len > (*r).count {
+        reader_error!(r, c!("%s: unexpected end of the unit"), (*r).path);
+        return None;
+    }
+    let s: *const c_char = arena::sprintf((*r).arena, c!("%.*s"), len as c_int, (*r).data.add((*r).pos));
+    (*r).pos += len;
+    Some(s)
+}
+
+pub unsafe fn read_loc(r: *mut Unit_Reader) -> Option<Loc> {
+    let mut loc: Loc = zeroed();
+    loc.input_path  = read_string(r)?;
+    loc.line_number = read_u64(r)? as c_int;
+    loc.line_offset = read_u64(r)? as c_int;
+    loc.span        = read_u64(r)? as c_int;
+    Some(loc)
+}
+
+unsafe fn invalid_unit(r: *mut Unit_Reader, what: *const c_char, tag: u64) -> Option<()> {
+    reader_error!(r, c!("%s: invalid %s tag %llu"), (*r).path, what, tag);
+    None
+}
+
+unsafe fn read_arg(r: *mut Unit_Reader) -> Option<Arg> {
+    let tag = read_u64(r)?;
+    match tag {
+        0 => Some(Arg::AutoVar(read_usize(r)?)),
+        1 => Some(Arg::Deref(read_usize(r)?)),
+        2 => Some(Arg::RefAutoVar(read_usize(r)?)),
+        3 => Some(Arg::RefExternal(read_string(r)?)),
+        4 => Some(Arg::External(read_string(r)?)),
+        5 => Some(Arg::Literal(read_u64(r)?)),
+        6 => Some(Arg::DataOffset(read_usize(r)?)),
+        _ => invalid_unit(r, c!("argument"), tag).map(|()| Arg::Bogus),
+    }
+}
+
+unsafe fn read_asm_stmts(r: *mut Unit_Reader) -> Option<Array<AsmStmt>> {
+    let mut stmts: Array<AsmStmt> = zeroed();
+    let count = read_usize(r)?;
+    for _ in 0..count {
+        let line = read_string(r)?;
+        let loc = read_loc(r)?;
+        da_append(&mut stmts, AsmStmt {line, loc});
+    }
+    Some(stmts)
+}
+
+const BINOPS: *const [Binop] = &[
+    Binop::Plus, Binop::Minus, Binop::Mult, Binop::Div, Binop::Mod,
+    Binop::Equal, Binop::NotEqual, Binop::Less, Binop::LessEqual, Binop::Greater, Binop::GreaterEqual,
+    Binop::BitOr, Binop::BitAnd, Binop::BitShl, Binop::BitShr,
+];
+
+unsafe fn read_op(r: *mut Unit_Reader) -> Option<OpWithLocation> {
+    let loc = read_loc(r)?;
+    let scope_events_count = read_usize(r)?;
+    let tag = read_u64(r)?;
+    let opcode = match tag {
+        0 => Op::UnaryNot {result: read_usize(r)?, arg: read_arg(r)?},
+        1 => Op::Negate {result: read_usize(r)?, arg: read_arg(r)?},
+        2 => Op::Asm {stmts: read_asm_stmts(r)?},
+        3 => {
+            let binop = read_usize(r)?;
+            if binop >= BINOPS.len() {
+                return invalid_unit(r, c!("binop"), binop as u64).map(|()| zeroed());
+            }
+            Op::Binop {binop: (*BINOPS)[binop], index: read_usize(r)?, lhs: read_arg(r)?, rhs: read_arg(r)?}
+        }
+        4 => Op::Index {result: read_usize(r)?, arg: read_arg(r)?, offset: read_arg(r)?},
+        5 => Op::AutoAssign {index: read_usize(r)?, arg: read_arg(r)?},
+        6 => Op::ExternalAssign {name: read_string(r)?, arg: read_arg(r)?},
+        7 => Op::Store {index: read_usize(r)?, arg: read_arg(r)?},
+        8 => {
+            let result = read_usize(r)?;
+            let fun = read_arg(r)?;
+            let mut args: Array<Arg> = zeroed();
+            let count = read_usize(r)?;
+            for _ in 0..count {
+                da_append(&mut args, read_arg(r)?);
+            }
+            Op::Funcall {result, fun, args}
+        }
+        9  => Op::Label {label: read_usize(r)?},
+        10 => Op::JmpLabel {label: read_usize(r)?},
+        11 => Op::JmpIfNotLabel {label: read_usize(r)?, arg: read_arg(r)?},
+        12 => Op::Return {arg: if read_u64(r)? != 0 { Some(read_arg(r)?) } else { None }},
+        _ => return invalid_unit(r, c!("op"), tag).map(|()| zeroed()),
+    };
+    Some(OpWithLocation {opcode, loc, scope_events_count})
+}
+
+/// Reads the unit into `program` that is expected to be empty.
+/// The strings of the unit are allocated in the arena.
+pub unsafe fn read_unit(a: *mut Arena, path: *const c_char, data: *const [u8], target_name: *const c_char, program: *mut Program) -> Option<()> {
+    let mut reader = Unit_Reader {
+        path,
+        data: data as *const u8,
+        count: data.len(),
+        pos: 0,
+        arena: a,
+        quiet: false,
+    };
+    read_unit_from(&mut reader, target_name, program)
+}
+
+/// Same as read_unit() but starts at the current position of the reader. The unit must span till the end of its data.
+pub unsafe fn read_unit_from(r: *mut Unit_Reader, target_name: *const c_char, program: *mut Program) -> Option<()> {
+    let path = (*r).path;
+    if read_u64(r)? != UNIT_MAGIC {
+        reader_error!(r, c!("%s: not a B unit"), path);
+        return None;
+    }
+    let version = read_u64(r)?;
+    if version != UNIT_VERSION {
+        reader_error!(r, c!("%s: unsupported version of the unit %llu, expected %llu. Please recompile it."), path, version, UNIT_VERSION);
+        return None;
+    }
+    let unit_target_name = read_string(r)?;
+    if strcmp(unit_target_name, target_name) != 0 {
+        reader_error!(r, c!("%s: the unit was compiled for target `%s`, but the current target is `%s`"), path, unit_target_name, target_name);
+        return None;
+    }
+
+    let data_count = read_usize(r)?;
+    if (*r).pos + data_count > (*r).count {
+        reader_error!(r, c!("%s: unexpected end of the unit"), path);
+        return None;
+    }
+    da_append_many(&mut (*program).data, ptr::slice_from_raw_parts((*r).data.add((*r).pos), data_count));
+    (*r).pos += data_count;
+
+    let extrns_count = read_usize(r)?;
+    for _ in 0..extrns_count {
+        da_append(&mut (*program).extrns, read_string(r)?);
+    }
+
+    let attributes_count = read_usize(r)?;
+    for _ in 0..attributes_count {
+        let name = read_string(r)?;
+        let mut attrs: Array<Attribute> = zeroed();
+        let count = read_usize(r)?;
+        for _ in 0..count {
+            let kind_name = read_string(r)?;
+            let Some(kind) = AttributeKind::from_name(kind_name) else {
+                reader_error!(r, c!("%s: unknown attribute `%s`"), path, kind_name);
+                return None;
+            };
+            let tag = read_u64(r)?;
+            let value = match tag {
+                0 => AttributeValue::None,
+                1 => AttributeValue::Int(read_u64(r)?),
+                2 => AttributeValue::String(read_string(r)?),
+                _ => return invalid_unit(r, c!("attribute value"), tag),
+            };
+            let loc = read_loc(r)?;
+            da_append(&mut attrs, Attribute {kind, value, loc});
+        }
+        da_append(&mut (*program).attributes, (name, attrs));
+    }
+
+    let funcs_count = read_usize(r)?;
+    for _ in 0..funcs_count {
+        let mut func: Func = zeroed();
+        func.name            = read_string(r)?;
+        func.name_loc        = read_loc(r)?;
+        func.params_count    = read_usize(r)?;
+        func.auto_vars_count = read_usize(r)?;
+        let body_count = read_usize(r)?;
+        for _ in 0..body_count {
+            da_append(&mut func.body, read_op(r)?);
+        }
+        let scope_events_count = read_usize(r)?;
+        for _ in 0..scope_events_count {
+            let tag = read_u64(r)?;
+            let event = match tag {
+                0 => ScopeEvent::Declare {name: read_string(r)?, index: read_usize(r)?},
+                1 => ScopeEvent::BlockBegin {index: read_usize(r)?},
+                2 => ScopeEvent::BlockEnd {index: read_usize(r)?},
+                _ => return invalid_unit(r, c!("scope event"), tag),
+            };
+            da_append(&mut func.scope_events, event);
+        }
+        da_append(&mut (*program).funcs, func);
+    }
+
+    let globals_count = read_usize(r)?;
+    for _ in 0..globals_count {
+        let mut global: Global = zeroed();
+        global.name         = read_string(r)?;
+        global.name_loc     = read_loc(r)?;
+        global.is_vec       = read_u64(r)? != 0;
+        global.minimum_size = read_usize(r)?;
+        let values_count = read_usize(r)?;
+        for _ in 0..values_count {
+            let tag = read_u64(r)?;
+            let value = match tag {
+                0 => ImmediateValue::Name(read_string(r)?),
+                1 => ImmediateValue::Literal(read_u64(r)?),
+                2 => ImmediateValue::DataOffset(read_usize(r)?),
+                _ => return invalid_unit(r, c!("immediate value"), tag),
+            };
+            da_append(&mut global.values, value);
+        }
+        da_append(&mut (*program).globals, global);
+    }
+
+    let asm_funcs_count = read_usize(r)?;
+    for _ in 0..asm_funcs_count {
+        let name = read_string(r)?;
+        let name_loc = read_loc(r)?;
+        let body = read_asm_stmts(r)?;
+        da_append(&mut (*program).asm_funcs, AsmFunc {name, name_loc, body});
+    }
+
+    if (*r).pos != (*r).count {
+        reader_error!(r, c!("%s: unexpected garbage at the end of the unit"), path);
+        return None;
+    }
+    Some(())
+}
+
+unsafe fn relocate_arg(arg: *mut Arg, data_base: usize) {
+    if let Arg::DataOffset(offset) = *arg {
+        *arg = Arg::DataOffset(offset + data_base);
+    }
+}
+
+/// Appends everything from `unit` to `program`. The data section of the unit is appended to
+/// the data section of the program, so all the offsets into it are relocated.
+pub unsafe fn link_unit(program: *mut Program, unit: *mut Program) {
+    let data_base = (*program).data.count;
+    da_append_many(&mut (*program).data, da_slice((*unit).data));
+
+    for i in 0..(*unit).extrns.count {
+        let name = *(*unit).extrns.items.add(i);
+        if !(0..(*program).extrns.count).any(|j| strcmp(*(*program).extrns.items.add(j), name) == 0) {
+            da_append(&mut (*program).extrns, name);
+        }
+    }
+
+    for i in 0..(*unit).attributes.count {
+        let (name, attrs) = *(*unit).attributes.items.add(i);
+        for j in 0..attrs.count {
+            let attr = *attrs.items.add(j);
+            if find_attribute(da_slice((*program).attributes), name, attr.kind).is_some() { continue; }
+            if let Some(existing) = crate::crust::assoc_lookup_cstr_mut(da_slice((*program).attributes), name) {
+                da_append(existing, attr);
+            } else {
+                let mut new_attrs: Array<Attribute> = zeroed();
+                da_append(&mut new_attrs, attr);
+                da_append(&mut (*program).attributes, (name, new_attrs));
+            }
+        }
+    }
+
+    for i in 0..(*unit).funcs.count {
+        let func = *(*unit).funcs.items.add(i);
+        for j in 0..func.body.count {
+            let op = &mut (*func.body.items.add(j)).opcode;
+            match op {
+                Op::Bogus | Op::Asm {..} | Op::Label {..} | Op::JmpLabel {..} | Op::Return {arg: None} => {}
+                Op::UnaryNot {arg, ..} | Op::Negate {arg, ..} | Op::AutoAssign {arg, ..} | Op::ExternalAssign {arg, ..}
+                | Op::Store {arg, ..} | Op::JmpIfNotLabel {arg, ..} | Op::Return {arg: Some(arg)} => relocate_arg(arg, data_base),
+                Op::Binop {lhs, rhs, ..} => {
+                    relocate_arg(lhs, data_base);
+                    relocate_arg(rhs, data_base);
+                }
+                Op::Index {arg, offset, ..} => {
+                    relocate_arg(arg, data_base);
+                    relocate_arg(offset, data_base);
+                }
+                Op::Funcall {fun, args, ..} => {
+                    relocate_arg(fun, data_base);
+                    for k in 0..args.count {
+                        relocate_arg(args.items.add(k), data_base);
+                    }
+                }
+            }
+        }
+        da_append(&mut (*program).funcs, func);
+    }
+
+    for i in 0..(*unit).globals.count {
+        let global = *(*unit).globals.items.add(i);
+        for j in 0..global.values.count {
+            let value = global.values.items.add(j);
+            if let ImmediateValue::DataOffset(offset) = *value {
+                *value = ImmediateValue::DataOffset(offset + data_base);
+            }
+        }
+        da_append(&mut (*program).globals, global);
+    }
+
+    da_append_many(&mut (*program).asm_funcs, da_slice((*unit).asm_funcs));
+}
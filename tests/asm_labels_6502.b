@@ -0,0 +1,84 @@
+/* This tests labels, directives and expressions of the 6502 inline assembly */
+
+counter 0;
+
+/* sum of the table with a local loop and a forward branch */
+sum __asm__(
+    "        LDX #0",
+    "        LDA #0",
+    "@loop:  CPX #4",
+    "        BEQ @done",
+    "        CLC",
+    "        ADC @table,X",
+    "        INX",
+    "        JMP @loop",
+    "@done:  LDY #0",
+    "        RTS",
+    "@table: .byte 1, 2, $03, %100 ; 10 in total"
+);
+
+/* increments the counter through a pointer in the zero page until it is 3 */
+bump __asm__(
+    "        LDA #<counter",
+    "        STA $10",
+    "        LDA #>counter",
+    "        STA $11",
+    "        LDX #0",
+    "@loop:  LDA ($10,X)", /* the same local label in another block */
+    "        CLC",
+    "        ADC #1",
+    "        STA ($10,X)",
+    "        LDA counter",
+    "        CMP #3",
+    "        BNE @loop",
+    "        LDY counter+1",
+    "        RTS"
+);
+
+/* returns the address of `sum` stored in the code */
+sum_ptr __asm__(
+    "        LDA @ptr",
+    "        LDY @ptr+1",
+    "        RTS",
+    "@ptr:   .word sum"
+);
+
+/* returns the second character of the string through a pointer in the zero page */
+second __asm__(
+    "        STA $10",
+    "        STY $11",
+    "        LDY #1",
+    "        LDA ($10),Y",
+    "        LDY #0",
+    "        RTS"
+);
+
+/* returns the address right after the first byte of the counter */
+after_counter __asm__(
+    "        LDA #<(counter+1)",
+    "        LDY #>((counter-1)+2)",
+    "        RTS"
+);
+
+main() {
+    extrn printf;
+    printf("%d\n", sum());
+    printf("%d %d\n", bump(), counter);
+    printf("%d\n", sum_ptr() == &sum);
+    printf("%d\n", (sum_ptr())());
+    printf("%c\n", second("hi"));
+    printf("%d\n", after_counter() - &counter);
+
+    __asm__(
+        "        LDA #'A'-5",
+        "        LDX #5",
+        "@loop:  CLC",
+        "        ADC #1",
+        "        DEX",
+        "        BNE @loop",
+        "        LDY #0",
+        "        JSR putchar",
+        "        LDA #10",
+        "        JSR putchar"
+    );
+}
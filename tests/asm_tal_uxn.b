@@ -0,0 +1,35 @@
+/* The devices of Uxn laid out in the zero page, the function itself is empty */
+devices __asm__(
+    "|10 @Console &vector $2 &read $1 &pad $4 &type $1 &write $1 &error $1"
+);
+
+/* ( str* -- ) prints a NUL-terminated string */
+puts_tal __asm__(
+    "&loop LDAk DUP ?&putc",
+    "POP POP2 JMP2r",
+    "&putc .Console/write DEO INC2 !&loop"
+);
+
+counter;
+
+bump() counter++;
+
+main() {
+    extrn printf;
+    __asm__(
+        ";&hello puts_tal !&end",
+        "&hello \"Hello, 20 \"World! 0a 00",
+        "&end"
+    );
+    __asm__(
+        "#05",
+        "&star #2a .Console/write DEO",
+        "#01 SUB DUP ,&star JCN",
+        "POP #0a .Console/write DEO"
+    );
+    __asm__(
+        "#0005 ;counter STA2 ( B globals and functions are labels too )",
+        "bump bump"
+    );
+    printf("%d\n", counter);
+}
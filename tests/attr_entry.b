@@ -0,0 +1,14 @@
+__attr__(start, entry);
+__attr__(fatal, noreturn);
+
+fatal(message) {
+    extrn printf, exit;
+    printf("%s\n", message);
+    exit(0);
+}
+
+start() {
+    extrn printf;
+    printf("Started at start\n");
+    fatal("Goodbye, world!");
+}
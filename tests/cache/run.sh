@@ -0,0 +1,29 @@
+#!/bin/sh
+# Regression tests of the cache of the compiled files (see src/cache.rs) that need several runs of the compiler,
+# so they don't fit into btest. Run from the root of the repo: `sh tests/cache/run.sh`.
+set -e
+
+B=${B:-./build/b}
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+cp tests/cache/*.b "$dir"
+
+fail() {
+    echo "FAIL: $1"
+    cat "$dir/stderr"
+    exit 1
+}
+
+# uses_x.b gets cached while `x` is declared by declares_x.b, but the cache entry must not hide that `x` is missing
+$B -q -t uxn -o "$dir/both" "$dir/declares_x.b" "$dir/uses_x.b" 2> "$dir/stderr" || fail "could not compile declares_x.b uses_x.b"
+if $B -q -t uxn -o "$dir/alone" "$dir/uses_x.b" 2> "$dir/stderr"; then
+    fail "uses_x.b compiled without declares_x.b"
+fi
+grep -q 'could not find name `x`' "$dir/stderr" || fail "expected the missing name to be reported"
+
+# The codegen parameters don't affect the IR, so they must not invalidate the cache entries
+$B -t uxn -o "$dir/plain" "$dir/declares_x.b" "$dir/uses_x.b" 2> "$dir/stderr" || fail "could not compile declares_x.b uses_x.b"
+$B -t uxn -C emit=tal -o "$dir/tal" "$dir/declares_x.b" "$dir/uses_x.b" 2> "$dir/stderr" || fail "could not compile with -C emit=tal"
+grep -q 'uses_x.b: reusing the cached compilation' "$dir/stderr" || fail "expected the cache to be reused with another -C"
+
+echo "OK"
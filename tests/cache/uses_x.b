@@ -0,0 +1,4 @@
+main() {
+    extrn printf;
+    printf("%d\n", x);
+}
@@ -19,6 +19,8 @@ main() {
     div(-101, 100);
     div( 201, 100);
     div(-201, 100);
+    div( 100,  -7);
+    div(-100,  -7);
     printf("\n");
     printf("Remainder:\n");
     mod(  1, 100);
@@ -27,4 +29,9 @@ main() {
     mod(101, 100);
     mod(201, 100);
     mod( -1, 100);
+    mod( 100, -7);
+    mod(-100, -7);
+    printf("\n");
+    printf("Unsigned:\n");
+    printf("%u %u\n", 40000, 65535);
 }
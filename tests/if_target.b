@@ -0,0 +1,24 @@
+__if_target__("gas-*") {
+    family() return ("gas");
+} else __if_target__("uxn") {
+    family() return ("uxn");
+} else {
+    family() return ("other");
+}
+
+__if_target__("does-not-exist") {
+    /* Not selected branches are only lexed, so they may contain anything */
+    this is ( not B ;
+}
+
+main() {
+    extrn printf;
+    printf("%s\n", family());
+    __if_target__("*-linux") {
+        printf("linux\n");
+    } else __if_target__("*-darwin") {
+        printf("darwin\n");
+    } else {
+        printf("somewhere else\n");
+    }
+}
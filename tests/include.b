@@ -0,0 +1,8 @@
+__include__("include/greet.b");
+__include__("include/greet.b");
+__include__("include/greet_twice.b");
+
+main() {
+    greet("World");
+    greet_twice("again");
+}
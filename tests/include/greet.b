@@ -0,0 +1,5 @@
+/* Included by ../include.b. Redefines `greet` unless it is compiled only once */
+greet(name) {
+    extrn printf;
+    printf("Hello, %s!\n", name);
+}
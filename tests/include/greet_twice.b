@@ -0,0 +1,7 @@
+/* Included by ../include.b. The path is resolved relative to this file */
+__include__("greet.b");
+
+greet_twice(name) {
+    greet(name);
+    greet(name);
+}
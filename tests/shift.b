@@ -0,0 +1,29 @@
+main() {
+    extrn printf;
+    auto x, n;
+
+    x = 291;
+    printf("%d %d\n", x << 0, x << 1);
+    printf("%d %d\n", x << 5, x >> 3);
+    x = 35;
+    printf("%d %d\n", x << 8, x << 9);
+    x = 4660;
+    printf("%d %d\n", x >> 8, x >> 12);
+
+    n = 0;
+    while (n < 7) {
+        printf("%d %d\n", 291 << n, 28672 >> n);
+        n++;
+    }
+
+    /* the counts of 16 or more shift out every bit of a 16 bit word */
+    __if_target__("uxn") {
+        /* TODO: SFT2 only takes 4 bits of the count, so uxn shifts by the count modulo 16 */
+    } else {
+        n = 16;
+        while (n < 20) {
+            printf("%d %d\n", (291 << n) & 0xFFFF, 28672 >> n);
+            n++;
+        }
+    }
+}
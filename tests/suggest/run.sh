@@ -0,0 +1,23 @@
+#!/bin/sh
+# Checks the names suggested for the undefined names (see suggest_var_near() in src/b.rs). They are diagnostics
+# of a failing compilation, so they don't fit into btest. Run from the root of the repo: `sh tests/suggest/run.sh`.
+set -e
+
+B=${B:-./build/b}
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+
+fail() {
+    echo "FAIL: $1"
+    cat "$dir/stderr"
+    exit 1
+}
+
+# Swapping two adjacent characters is the most common typo
+if $B -nocache -q -t uxn -o "$dir/transposed" tests/suggest/transposed.b 2> "$dir/stderr"; then
+    fail "transposed.b compiled with undefined names"
+fi
+grep -q 'did you mean `value`' "$dir/stderr" || fail "expected \`value\` to be suggested for \`valeu\`"
+grep -q 'did you mean `xyz`' "$dir/stderr" || fail "expected \`xyz\` to be suggested for \`xzy\`"
+
+echo "OK"
@@ -0,0 +1,8 @@
+xyz 3;
+
+main() {
+    extrn printf;
+    auto value;
+    value = 1;
+    printf("%d %d\n", valeu, xzy);
+}
@@ -0,0 +1,38 @@
+#!/bin/sh
+# Checks the Uxntal source that the uxn target writes with `-S` and `-C emit=tal` (see generate_tal() in
+# src/codegen/uxn/mod.rs). When uxnasm is in $PATH the source is assembled back and compared with the ROM.
+# Run from the root of the repo: `sh tests/tal/run.sh`.
+set -e
+
+B=${B:-./build/b}
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+
+fail() {
+    echo "FAIL: $1"
+    cat "$dir/stderr"
+    exit 1
+}
+
+if ! command -v uxnasm > /dev/null; then
+    echo "NOTE: uxnasm is not found, skipping the assembling of the Uxntal source"
+fi
+
+for name in hello asm_tal_uxn globals switch divmod; do
+    $B -q -t uxn -C emit=tal -o "$dir/$name" "tests/$name.b" 2> "$dir/stderr" || fail "could not compile $name.b with -C emit=tal"
+    $B -q -t uxn -S -o "$dir/$name.tal" "tests/$name.b" 2> "$dir/stderr" || fail "could not compile $name.b with -S"
+    cmp -s "$dir/$name.rom.tal" "$dir/$name.tal" || fail "-S and -C emit=tal wrote different sources for $name.b"
+
+    # Every rune reference must point to a label that the source defines
+    tr -s ' \n' '\n\n' < "$dir/$name.tal" | sed -n 's/^@//p' | sort -u > "$dir/defined"
+    tr -s ' \n' '\n\n' < "$dir/$name.tal" | sed -n 's/^[;,.=!?-]//p' | sort -u > "$dir/referenced"
+    missing=$(comm -13 "$dir/defined" "$dir/referenced")
+    [ -z "$missing" ] || fail "$name.tal references undefined labels: $missing"
+
+    if command -v uxnasm > /dev/null; then
+        uxnasm "$dir/$name.tal" "$dir/$name.uxnasm.rom" > "$dir/stderr" 2>&1 || fail "uxnasm could not assemble $name.tal"
+        cmp -s "$dir/$name.rom" "$dir/$name.uxnasm.rom" || fail "uxnasm assembled $name.tal into a different ROM"
+    fi
+done
+
+echo "OK"
@@ -1,4 +1,10 @@
 #include <stdio.h>
+#include <stdlib.h>
+#ifdef _WIN32
+#include <io.h>
+#else
+#include <unistd.h>
+#endif
 
 // Linux defines `stdin`, `stdout` and `stderr` as both an external symbol and a macro.
 // however Windows defines them as macros only, and these macros expand to non-const
@@ -9,3 +15,19 @@
 FILE *get_stdin() { return stdin; }
 FILE *get_stdout() { return stdout; }
 FILE *get_stderr() { return stderr; }
+
+// Same story with `fileno` and `isatty`. On Windows they are deprecated aliases of `_fileno` and `_isatty`.
+#ifdef _WIN32
+int get_fileno(FILE *stream) { return _fileno(stream); }
+int get_isatty(int fd) { return _isatty(fd); }
+#else
+int get_fileno(FILE *stream) { return fileno(stream); }
+int get_isatty(int fd) { return isatty(fd); }
+#endif
+
+// Windows has no `realpath`, but `_fullpath` does pretty much the same thing.
+#ifdef _WIN32
+char *get_realpath(const char *path) { return _fullpath(NULL, path, 0); }
+#else
+char *get_realpath(const char *path) { return realpath(path, NULL); }
+#endif